@@ -0,0 +1,66 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Resets attributes, shows the cursor and turns off the modes a guest
+/// may have left on: mouse reporting, focus events, bracketed paste and
+/// the alternate screen.
+pub const RESET_TERMINAL: &[u8] = b"\x1b[0m\x1b[?25h\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b[?1004l\x1b[?2004l\x1b[?1049l";
+
+/// Where a `Stripper` is in the stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Text,
+    /// After ESC.
+    Escape,
+    /// Inside `ESC [`, up to the final byte.
+    Csi,
+    /// Inside `ESC ]`, up to BEL or ST.
+    Osc,
+    /// After an ESC inside an OSC, which may start its ST.
+    OscEscape,
+}
+
+/// Removes CSI (`ESC [ ... final`), OSC (`ESC ] ... BEL/ST`) and two-byte
+/// escape sequences, leaving only printable text and line control. A
+/// sequence split across calls is still removed whole.
+#[derive(Debug, Default)]
+pub struct Stripper {
+    state: State,
+}
+
+impl Stripper {
+    pub fn strip(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len());
+        for &b in buf {
+            self.state = match (self.state, b) {
+                (State::Text, ESC) => State::Escape,
+                (State::Text, _) => {
+                    out.push(b);
+                    State::Text
+                }
+                (State::Escape, b'[') => State::Csi,
+                (State::Escape, b']') => State::Osc,
+                (State::Escape, _) => State::Text,
+                (State::Csi, 0x40..=0x7e) => State::Text,
+                (State::Csi, _) => State::Csi,
+                (State::Osc, BEL) => State::Text,
+                (State::Osc, ESC) => State::OscEscape,
+                (State::Osc, _) => State::Osc,
+                (State::OscEscape, b'\\') => State::Text,
+                (State::OscEscape, BEL) => State::Text,
+                (State::OscEscape, ESC) => State::OscEscape,
+                (State::OscEscape, _) => State::Osc,
+            };
+        }
+        out
+    }
+}
+
+/// Strips a complete buffer, see `Stripper`.
+pub fn strip(buf: &[u8]) -> Vec<u8> {
+    Stripper::default().strip(buf)
+}
@@ -0,0 +1,104 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+const ESC: u8 = 0x1b;
+
+/// Longest CSI held back while waiting for its final byte.
+const MAX_PENDING: usize = 16;
+
+/// Terminal queries a guest may block on until it gets an answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// Primary device attributes, `CSI c`.
+    Attributes,
+    /// Secondary device attributes, `CSI > c`.
+    SecondaryAttributes,
+    /// Device status report, `CSI 5 n`.
+    Status,
+    /// Cursor position report, `CSI 6 n`.
+    CursorPosition,
+}
+
+impl Query {
+    fn parse(seq: &[u8]) -> Option<Query> {
+        match seq {
+            b"c" | b"0c" => Some(Query::Attributes),
+            b">c" | b">0c" => Some(Query::SecondaryAttributes),
+            b"5n" => Some(Query::Status),
+            b"6n" => Some(Query::CursorPosition),
+            _ => None,
+        }
+    }
+
+    /// The answer of a VT100 with advanced video; the cursor position is
+    /// 1-based (row, column) and reported as home when unknown.
+    pub fn reply(&self, cursor: Option<(u16, u16)>) -> Vec<u8> {
+        match self {
+            Query::Attributes => b"\x1b[?1;2c".to_vec(),
+            Query::SecondaryAttributes => b"\x1b[>0;10;1c".to_vec(),
+            Query::Status => b"\x1b[0n".to_vec(),
+            Query::CursorPosition => {
+                let (row, col) = cursor.unwrap_or((1, 1));
+                format!("\x1b[{};{}R", row, col).into_bytes()
+            }
+        }
+    }
+}
+
+/// Takes terminal queries out of guest output so they can be answered
+/// locally. Queries split across reads are held back until complete.
+#[derive(Default)]
+pub struct Answerback {
+    pending: Vec<u8>,
+}
+
+impl Answerback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Splits `buf` into output chunks, each followed by the query that
+    /// came after it, if any.
+    pub fn apply(&mut self, buf: &[u8]) -> Vec<(Vec<u8>, Option<Query>)> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(buf);
+
+        let mut pieces = Vec::new();
+        let mut chunk = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != ESC {
+                chunk.push(input[i]);
+                i += 1;
+                continue;
+            }
+            let rest = &input[i..];
+            if rest.len() == 1 || (rest[1] == b'[' && rest.len() < MAX_PENDING) {
+                let params = rest.get(2..).unwrap_or_default();
+                if params.iter().all(|b| (0x30..=0x3f).contains(b)) {
+                    self.pending = rest.to_vec();
+                    break;
+                }
+            }
+            let query = match rest.get(1) {
+                Some(b'[') => rest[2..]
+                    .iter()
+                    .position(|b| (0x40..=0x7e).contains(b))
+                    .and_then(|end| Some((end + 3, Query::parse(&rest[2..end + 3])?))),
+                _ => None,
+            };
+            match query {
+                Some((len, query)) => {
+                    pieces.push((std::mem::take(&mut chunk), Some(query)));
+                    i += len;
+                }
+                None => {
+                    chunk.push(ESC);
+                    i += 1;
+                }
+            }
+        }
+        pieces.push((chunk, None));
+        pieces
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::error;
+
+static AUDIT_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Opens the audit log for appending. Until this is called `record` does
+/// nothing.
+pub fn open(path: &Path) -> Result<(), std::io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = AUDIT_LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+/// The local account winpipe runs as.
+fn user() -> String {
+    match (std::env::var("USERDOMAIN"), std::env::var("USERNAME")) {
+        (Ok(domain), Ok(name)) => format!("{}\\{}", domain, name),
+        (_, Ok(name)) => name,
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Appends one line describing a connection event: `event` happened on
+/// `path` for `client` (the console, a relay or a network peer) because
+/// of `reason`.
+pub fn record(event: &str, path: &str, client: &str, reason: &str) {
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let line = format!(
+        "{} event={} user={:?} pid={} path={:?} client={:?} reason={:?}\n",
+        timestamp,
+        event,
+        user(),
+        std::process::id(),
+        path,
+        client,
+        reason
+    );
+
+    let mut file = log.lock().unwrap();
+    if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+        error!("Failed to write audit log: {:?}", e);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::windows::process::CommandExt;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+use std::time::Instant;
+
+use log::info;
+use log::warn;
+
+use windows::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP;
+use windows::Win32::System::Threading::DETACHED_PROCESS;
+
+use crate::named_pipe;
+use crate::named_pipe::NamedPipe;
+use crate::summary;
+
+/// Longest command line read from the control pipe.
+const MAX_COMMAND: usize = 256;
+
+/// Name of the pipe the background winpipe with `pid` is controlled through.
+pub fn control_pipe(pid: u32) -> String {
+    format!(r"\\.\pipe\winpipe-control-{}", pid)
+}
+
+/// Starts this command line again, minus `--background`, as a process
+/// without a console so it outlives the shell. Returns the new PID.
+pub fn detach() -> Result<u32, std::io::Error> {
+    let args = std::env::args_os().skip(1).filter(|a| a != "--background");
+    let child = Command::new(std::env::current_exe()?)
+        .args(args)
+        .arg("--detached")
+        .creation_flags(DETACHED_PROCESS.0 | CREATE_NEW_PROCESS_GROUP.0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(child.id())
+}
+
+/// Serves this process's control pipe on its own thread. A client sends
+/// `status` or `stop`, one command per line; `relaying` names what the
+/// background bridge relays, for the status reply.
+pub fn serve_control(relaying: String) {
+    let name = control_pipe(std::process::id());
+    let server = match named_pipe::Server::create(&name, &named_pipe::ServerConfig::default()) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to create control pipe {}: {:?}", name, e);
+            return;
+        }
+    };
+    let started = Instant::now();
+    thread::spawn(move || loop {
+        let pipe = match server.accept(&named_pipe::PipeConfig::default()) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                warn!("Control pipe stopped accepting: {:?}", e);
+                return;
+            }
+        };
+        let mut pending: Vec<u8> = Vec::new();
+        while let Some(command) = read_command(&pipe, &mut pending) {
+            let reply = match command.as_str() {
+                "" => continue,
+                "status" => format!(
+                    "winpipe pid {} relaying {}, up {}s\r\n",
+                    std::process::id(),
+                    relaying,
+                    started.elapsed().as_secs()
+                ),
+                "stop" => {
+                    info!("Stopped from the control pipe");
+                    let _ = pipe.write_all(b"stopping\r\n");
+                    summary::finish("stopped from the control pipe", 0);
+                    std::process::exit(0);
+                }
+                other => format!("unknown command {:?}, try status or stop\r\n", other),
+            };
+            if pipe.write_all(reply.as_bytes()).is_err() {
+                break;
+            }
+        }
+        server.disconnect();
+    });
+}
+
+/// Reads one line from a control client, `None` once it has gone away.
+/// What followed the line is kept in `pending` for the next call.
+fn read_command(pipe: &NamedPipe, pending: &mut Vec<u8>) -> Option<String> {
+    loop {
+        if let Some(end) = pending.iter().position(|b| *b == b'\r' || *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            return Some(line.trim().to_lowercase());
+        }
+        if pending.len() > MAX_COMMAND {
+            let line = String::from_utf8_lossy(pending).to_string();
+            pending.clear();
+            return Some(line);
+        }
+        let mut buffer: Vec<u8> = Vec::new();
+        if pipe.read_wait(&mut buffer, None).ok()? == 0 {
+            return None;
+        }
+        pending.extend_from_slice(&buffer);
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::ValueEnum;
+use log::warn;
+
+use crate::console::Console;
+use crate::console::StdioKind;
+
+/// When to show connection banners in the session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Banner {
+    /// only when attached to a console or pty
+    #[default]
+    Auto,
+    /// also with --headless
+    Always,
+    /// never
+    Never,
+}
+
+/// Writes `msg` as a banner: bracketed and dimmed on a terminal, plain
+/// text otherwise. Banners go to the console only, never to captures.
+pub fn show(con: &Console, mode: Banner, msg: &str) {
+    let text = match (mode, con.kind()) {
+        (Banner::Never, _) | (Banner::Auto, StdioKind::Headless) => return,
+        (_, StdioKind::Headless) => format!("[winpipe] {}\r\n", msg),
+        _ => format!("\r\n\x1b[2m[winpipe] {}\x1b[22m\r\n", msg),
+    };
+    if let Err(e) = con.write(text.as_bytes()) {
+        warn!("Failed to show banner: {:?}", e);
+    }
+}
@@ -0,0 +1,684 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::error;
+use log::info;
+use log::warn;
+
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_OPERATION_ABORTED;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+use windows::Win32::Foundation::ERROR_TIMEOUT;
+use windows::Win32::Foundation::STATUS_INTERRUPTED;
+
+use crate::answerback;
+use crate::capture;
+use crate::console;
+use crate::console::Console;
+use crate::crlf;
+use crate::event::Event;
+use crate::filter;
+use crate::history::History;
+use crate::keepalive;
+use crate::keys;
+use crate::menu;
+use crate::paste;
+use crate::pipeline;
+use crate::plugin;
+use crate::progress;
+use crate::receive;
+use crate::record;
+use crate::screen;
+use crate::scripting;
+use crate::send;
+use crate::transport::Transport;
+use crate::win32_input;
+
+/// How a [`Bridge`] relays; the winpipe command line fills this in from
+/// its flags, anything left at its default is off.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Only relay pipe output, don't read the console.
+    pub read_only: bool,
+    /// Only relay console input, don't read the pipe.
+    pub write_only: bool,
+    /// Capture files: `redir` gets pipe output, `redir_in` the input sent.
+    pub redir: Option<PathBuf>,
+    pub redir_in: Option<PathBuf>,
+    /// Prefix every line of pipe output in the capture files with the time.
+    pub timestamps: bool,
+    /// asciinema recording of the pipe output, across connections.
+    pub record: Option<PathBuf>,
+    /// Streamed into the pipe before console input is read.
+    pub input_file: Option<PathBuf>,
+    /// Pacing of `input_file` and files pasted from the escape menu.
+    pub pacing: send::Pacing,
+    /// Stop after `input_file` instead of reading the console.
+    pub input_only: bool,
+    /// Show the progress of `input_file` in the console title.
+    pub progress: bool,
+    /// Console title to put back after the progress; the default title
+    /// is restored when `None`.
+    pub title: Option<String>,
+    pub backspace: Option<keys::Backspace>,
+    pub delete: Option<keys::Delete>,
+    pub enter: Option<keys::Enter>,
+    pub paste_transform: Vec<paste::PasteTransform>,
+    pub paste_tab_width: usize,
+    /// Ask before sending a paste larger than this.
+    pub paste_guard: Option<usize>,
+    pub input_filter: Vec<pipeline::FilterSpec>,
+    pub output_filter: Vec<pipeline::FilterSpec>,
+    pub filter_control: Vec<filter::ControlClass>,
+    pub osc52: filter::Osc52,
+    /// Key that opens the escape menu; `None` passes every key through.
+    pub escape: Option<u8>,
+    /// Line-ending translation and local echo, switched from the escape
+    /// menu during the session.
+    pub line_modes: Arc<crlf::LineModes>,
+    /// Ctrl+C typed on the console quits instead of going to the pipe.
+    pub ctrl_c_exits: bool,
+    /// Answer terminal queries from the guest on the console's behalf.
+    pub answerback: bool,
+    /// Bytes written after this long without input.
+    pub keepalive: Option<(Vec<u8>, Duration)>,
+}
+
+/// How the relay threads of a session stop.
+struct Stop {
+    /// The input ended; the pipe output is drained before stopping.
+    input_closed: AtomicBool,
+    /// Left from the escape menu; the output isn't drained.
+    detached: AtomicBool,
+    /// Quit from the escape menu, Ctrl+C or an interrupt.
+    quit: AtomicBool,
+    /// Set to wake both threads out of their waits.
+    wake: Event,
+}
+
+/// Relays between a console and one pipe connection at a time. The
+/// scrollback and a pending receive-file capture carry over from one
+/// connection to the next.
+pub struct Bridge {
+    con: Arc<Console>,
+    options: Arc<Options>,
+    history: Arc<Mutex<History>>,
+    receive: Arc<receive::Slot>,
+    cast: Arc<Mutex<Option<record::Cast>>>,
+    stop: Arc<Stop>,
+    /// The connection `run` is relaying, for [`Bridge::interrupt`].
+    pipe: Mutex<Option<Arc<dyn Transport>>>,
+}
+
+impl Bridge {
+    pub fn new(
+        con: Arc<Console>,
+        options: Options,
+        scrollback: usize,
+    ) -> windows::core::Result<Self> {
+        Ok(Self {
+            con,
+            options: Arc::new(options),
+            history: Arc::new(Mutex::new(History::new(scrollback))),
+            receive: Arc::new(Mutex::new(None)),
+            cast: Arc::new(Mutex::new(None)),
+            stop: Arc::new(Stop {
+                input_closed: AtomicBool::new(false),
+                detached: AtomicBool::new(false),
+                quit: AtomicBool::new(false),
+                wake: Event::new()?,
+            }),
+            pipe: Mutex::new(None),
+        })
+    }
+
+    /// Whether the last [`Bridge::run`] ended because the input ended,
+    /// rather than the pipe.
+    pub fn input_closed(&self) -> bool {
+        self.stop.input_closed.load(Ordering::SeqCst)
+    }
+
+    /// Whether the last [`Bridge::run`] ended with quit in the escape menu
+    /// or [`Bridge::interrupt`].
+    pub fn quit(&self) -> bool {
+        self.stop.quit.load(Ordering::SeqCst)
+    }
+
+    /// Ends the running session from another thread, as quit in the escape
+    /// menu does, cancelling the pending pipe I/O and console reads so both
+    /// threads can be joined.
+    pub fn interrupt(&self) {
+        self.stop.quit.store(true, Ordering::SeqCst);
+        self.stop.detached.store(true, Ordering::SeqCst);
+        self.stop.wake.set();
+        if let Some(pipe) = &*self.pipe.lock().unwrap() {
+            // A write blocked on a full pipe holds up the input thread.
+            pipe.cancel_io();
+        }
+        let _ = self.con.cancel_read();
+    }
+
+    /// Relays between the console and `pipe` until the pipe disconnects,
+    /// the input ends or the escape menu leaves the session.
+    pub fn run(&self, pipe: Arc<dyn Transport>) -> Result<(), std::io::Error> {
+        self.stop.input_closed.store(false, Ordering::SeqCst);
+        self.stop.detached.store(false, Ordering::SeqCst);
+        self.stop.wake.reset();
+        if let Some(path) = &self.options.record {
+            let mut cast = self.cast.lock().unwrap();
+            if cast.is_none() {
+                *cast = Some(record::Cast::create(path, self.con.size().ok())?);
+            }
+        }
+        *self.pipe.lock().unwrap() = Some(Arc::clone(&pipe));
+        let pipe_pts = Arc::clone(&pipe);
+        // Shared by both threads so local echo and pipe output stay in order.
+        let screen = Arc::new(screen::Screen::new(Arc::clone(&self.con)));
+        let last_write = Arc::new(Mutex::new(Instant::now()));
+        let stop_keepalive = Arc::new(AtomicBool::new(false));
+
+        let th_keepalive = match &self.options.keepalive {
+            Some((bytes, interval)) if !self.options.read_only => Some(keepalive::spawn(
+                Arc::clone(&pipe),
+                bytes.clone(),
+                *interval,
+                Arc::clone(&last_write),
+                Arc::clone(&stop_keepalive),
+            )),
+            _ => None,
+        };
+
+        let th_stdin_to_pipe = if self.options.read_only {
+            None
+        } else {
+            let arc_screen_r = Arc::clone(&screen);
+            let arc_options_r = Arc::clone(&self.options);
+            let arc_history_r = Arc::clone(&self.history);
+            let arc_receive_r = Arc::clone(&self.receive);
+            let arc_stop_r = Arc::clone(&self.stop);
+            let arc_last_write_r = Arc::clone(&last_write);
+            Some(std::thread::spawn(move || {
+                let ret = stdin_to_pipe(
+                    pipe,
+                    arc_screen_r,
+                    arc_options_r,
+                    arc_history_r,
+                    arc_receive_r,
+                    arc_stop_r,
+                    arc_last_write_r,
+                );
+                if let Err(e) = &ret {
+                    error!("Error in stdin_to_pipe: {:?}", e);
+                }
+                ret
+            }))
+        };
+
+        let th_pipe_to_stdout = if self.options.write_only {
+            None
+        } else {
+            let arc_screen_w = Arc::clone(&screen);
+            let arc_options_w = Arc::clone(&self.options);
+            let arc_history_w = Arc::clone(&self.history);
+            let arc_receive_w = Arc::clone(&self.receive);
+            let arc_cast_w = Arc::clone(&self.cast);
+            let arc_stop_w = Arc::clone(&self.stop);
+            Some(std::thread::spawn(move || {
+                let ret = pipe_to_stdout(
+                    pipe_pts,
+                    arc_screen_w,
+                    arc_options_w,
+                    arc_history_w,
+                    arc_receive_w,
+                    arc_cast_w,
+                    arc_stop_w,
+                );
+                if let Err(e) = &ret {
+                    error!("Error in pipe_to_stdout: {:?}", e);
+                }
+                ret.map_err(std::io::Error::from)
+            }))
+        };
+
+        let ret_out = th_pipe_to_stdout.map_or(Ok(()), |th| th.join().unwrap());
+        let ret_in = th_stdin_to_pipe.map_or(Ok(()), |th| th.join().unwrap());
+        *self.pipe.lock().unwrap() = None;
+        stop_keepalive.store(true, Ordering::SeqCst);
+        if let Some(th) = th_keepalive {
+            let _ = th.join();
+        }
+        ret_out.and(ret_in)
+    }
+}
+
+/// Streams `path` into the pipe with the configured pacing, showing the
+/// bytes, rate and ETA in the console title if `show_progress`. Typing
+/// `abort_key` stops the send.
+fn send_file(
+    pipe: &dyn Transport,
+    con: &Console,
+    options: &Options,
+    path: &Path,
+    last_write: &keepalive::LastWrite,
+    show_progress: bool,
+    abort_key: Option<u8>,
+) -> Result<usize, std::io::Error> {
+    let mut progress: Option<progress::Progress> = None;
+    let ret = send::send_file(pipe, path, options.pacing, |sent, total| {
+        *last_write.lock().unwrap() = Instant::now();
+        if show_progress {
+            let p =
+                progress.get_or_insert_with(|| progress::Progress::new("winpipe: sending", total));
+            if let Some(text) = p.update(sent) {
+                let _ = con.set_title(&text);
+            }
+        }
+        !abort_key.is_some_and(|key| con.key_typed(key).unwrap_or(false))
+    });
+    if show_progress {
+        let _ = match &options.title {
+            Some(title) => con.set_title(title),
+            None => con.reset_title(),
+        };
+    }
+    ret
+}
+
+/// Asks on the console whether a large paste of `len` bytes should be sent.
+fn confirm_paste(con: &Console, len: usize) -> windows::core::Result<bool> {
+    let prompt = format!("\r\n[winpipe] send {} pasted bytes? [y/N] ", len);
+    con.write(prompt.as_bytes())?;
+
+    let mut key: Vec<u8> = vec![0u8; 16];
+    let n = con.read(&mut key)?;
+    let yes = n > 0 && (key[0] == b'y' || key[0] == b'Y');
+    con.write(if yes { b"y\r\n" } else { b"n\r\n" })?;
+    Ok(yes)
+}
+
+/// Where typed input goes on its way to the pipe, kept across reads.
+struct Input {
+    filters: pipeline::Pipeline,
+    captures: capture::Capture,
+    echo_last_cr: bool,
+}
+
+impl Input {
+    /// Sends typed input the way every key goes out: remapped, past the
+    /// paste guard and the input filters, then captured and echoed.
+    fn send(
+        &mut self,
+        mut buf: Vec<u8>,
+        pipe: &dyn Transport,
+        screen: &screen::Screen,
+        options: &Options,
+        enter: Option<keys::Enter>,
+        last_write: &keepalive::LastWrite,
+    ) -> Result<(), std::io::Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let remap = options.backspace.is_some() || options.delete.is_some() || enter.is_some();
+        if remap && !paste::is_paste(&buf) {
+            buf = keys::map(&buf, options.backspace, options.delete, enter);
+        }
+
+        if let Some(limit) = options.paste_guard {
+            if buf.len() > limit && !confirm_paste(screen.console(), buf.len())? {
+                warn!("Discarded {} pasted bytes", buf.len());
+                return Ok(());
+            }
+        }
+
+        buf = self.filters.run(&buf);
+
+        match pipe.write_all(&buf) {
+            // The watchdog cancelled a stuck write; drop it and carry on.
+            Err(e) if e.code() == ERROR_TIMEOUT.into() => {
+                warn!("Dropped {} bytes of input", buf.len());
+                return Ok(());
+            }
+            ret => ret?,
+        }
+        *last_write.lock().unwrap() = Instant::now();
+        self.captures.write(&buf)?;
+        if options.line_modes.local_echo() {
+            screen.write(&crlf::to_crlf(&buf, true, &mut self.echo_last_cr))?;
+        }
+        Ok(())
+    }
+}
+
+fn stdin_to_pipe(
+    pipe: Arc<dyn Transport>,
+    screen: Arc<screen::Screen>,
+    options: Arc<Options>,
+    history: Arc<Mutex<History>>,
+    receive: Arc<receive::Slot>,
+    stop: Arc<Stop>,
+    last_write: Arc<keepalive::LastWrite>,
+) -> Result<(), std::io::Error> {
+    let con = screen.console();
+    let captures = capture::open(&[&options.redir_in])?;
+
+    if let Some(path) = &options.input_file {
+        send_file(
+            &*pipe,
+            &con,
+            &options,
+            path,
+            &last_write,
+            options.progress,
+            None,
+        )?;
+        if options.input_only {
+            return Ok(());
+        }
+    }
+
+    let mut win32_input = win32_input::Decoder::new();
+    let mut enter = options.enter;
+    // What followed the escape key in the read that held it.
+    let mut tail: Vec<u8> = Vec::new();
+    let mut filters = pipeline::Pipeline::new(pipeline::Direction::ToPipe);
+    filters.push(Box::new(pipeline::PasteTransform {
+        transforms: options.paste_transform.clone(),
+        tab_width: options.paste_tab_width,
+    }));
+    filters.push(Box::new(pipeline::Crlf::new(Arc::clone(
+        &options.line_modes,
+    ))));
+    filters.push_specs(&options.input_filter);
+    plugin::attach(&mut filters);
+    scripting::attach(&mut filters);
+    let mut input = Input {
+        filters,
+        captures,
+        echo_last_cr: false,
+    };
+
+    loop {
+        // Flushed here too so input handlers' sends don't wait for output.
+        let sends = scripting::take_sends();
+        if !sends.is_empty() {
+            pipe.write_all(&sends)?;
+        }
+
+        // Input after an escape menu is handled before anything new is read.
+        let mut buf = if !tail.is_empty() {
+            std::mem::take(&mut tail)
+        } else {
+            let events: Vec<&Event> = std::iter::once(&stop.wake)
+                .chain(scripting::sent_event())
+                .collect();
+            match con.wait_input_or(&events)? {
+                Some(0) => {
+                    info!("Session ended, stopped reading input");
+                    break Ok(());
+                }
+                // The script queued a send; the top of the loop writes it.
+                Some(_) => continue,
+                None => {}
+            }
+            let mut buf: Vec<u8> = vec![0u8; 1024];
+            let n = match con.read_available(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.code() == STATUS_INTERRUPTED.into() => {
+                    info!("interrupted!");
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(e) if e.code() == ERROR_OPERATION_ABORTED.into() => {
+                    warn!("Operation aborted!");
+                    break Ok(());
+                }
+                Err(e) if con.is_raw() && e.code() == ERROR_BROKEN_PIPE.into() => {
+                    info!("stdin closed");
+                    stop.input_closed.store(true, Ordering::SeqCst);
+                    stop.wake.set();
+                    break Ok(());
+                }
+                Err(e) => {
+                    error!("Failed to read from stdin: {:?}", e);
+                    break Err(e.into());
+                }
+            };
+            if n == 0 && con.is_raw() {
+                info!("stdin reached EOF");
+                stop.input_closed.store(true, Ordering::SeqCst);
+                stop.wake.set();
+                break Ok(());
+            }
+            buf.truncate(n as usize);
+
+            // A hosting terminal in win32-input-mode sends key events instead of
+            // VT input; headless input is data and left alone.
+            if con.kind() != console::StdioKind::Headless {
+                buf = win32_input.decode(&buf);
+                if buf.is_empty() {
+                    continue;
+                }
+            }
+            buf
+        };
+
+        if options.ctrl_c_exits && con.kind() != console::StdioKind::Headless {
+            if let Some(pos) = buf.iter().position(|b| *b == 0x03) {
+                buf.truncate(pos);
+                input.send(buf, &*pipe, &screen, &options, enter, &last_write)?;
+                info!("Ctrl+C, quitting");
+                stop.quit.store(true, Ordering::SeqCst);
+                stop.detached.store(true, Ordering::SeqCst);
+                stop.wake.set();
+                break Ok(());
+            }
+        }
+
+        let escape = match options.escape {
+            Some(key) if con.kind() != console::StdioKind::Headless => {
+                buf.iter().position(|b| *b == key).map(|pos| (pos, key))
+            }
+            _ => None,
+        };
+        if let Some((pos, key)) = escape {
+            let rest = buf.split_off(pos + 1);
+            buf.truncate(pos);
+            input.send(buf, &*pipe, &screen, &options, enter, &last_write)?;
+            match menu::run(
+                &con,
+                key,
+                &history,
+                &receive,
+                &mut enter,
+                &options.line_modes,
+            )? {
+                menu::Action::Resume => {
+                    tail = rest;
+                    continue;
+                }
+                menu::Action::Detach => {
+                    stop.detached.store(true, Ordering::SeqCst);
+                    stop.wake.set();
+                    break Ok(());
+                }
+                menu::Action::Quit => {
+                    stop.quit.store(true, Ordering::SeqCst);
+                    stop.detached.store(true, Ordering::SeqCst);
+                    stop.wake.set();
+                    break Ok(());
+                }
+                menu::Action::SendFile(path) => {
+                    let sent =
+                        send_file(&*pipe, &con, &options, &path, &last_write, true, Some(key));
+                    let msg = match sent {
+                        Ok(sent) => format!("sent {} bytes from {}", sent, path.display()),
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                            format!("{} {}", path.display(), e)
+                        }
+                        Err(e) => {
+                            warn!("Failed to send {:?}: {:?}", path, e);
+                            format!("failed to send {}: {}", path.display(), e)
+                        }
+                    };
+                    con.write(format!("[winpipe] {}\r\n", msg).as_bytes())?;
+                    tail = rest;
+                    continue;
+                }
+                // Typed as hex so it goes out as is, past keys and filters.
+                menu::Action::Send(bytes) => {
+                    pipe.write_all(&bytes)?;
+                    *last_write.lock().unwrap() = Instant::now();
+                    input.captures.write(&bytes)?;
+                    tail = rest;
+                    continue;
+                }
+            }
+        }
+
+        input.send(buf, &*pipe, &screen, &options, enter, &last_write)?;
+    }
+}
+
+fn pipe_to_stdout(
+    pipe: Arc<dyn Transport>,
+    screen: Arc<screen::Screen>,
+    options: Arc<Options>,
+    history: Arc<Mutex<History>>,
+    receive: Arc<receive::Slot>,
+    cast: Arc<Mutex<Option<record::Cast>>>,
+    stop: Arc<Stop>,
+) -> windows::core::Result<()> {
+    let con = screen.console();
+    let mut captures = capture::open(&[&options.redir])?;
+    let mut timestamps = record::Timestamps::default();
+    let mut filters =
+        pipeline::Pipeline::from_specs(pipeline::Direction::FromPipe, &options.output_filter);
+    plugin::attach(&mut filters);
+    scripting::attach(&mut filters);
+    // Stages only the console sees, after the captures have their copy.
+    let queries = Arc::new(Mutex::new(Vec::new()));
+    let mut shown_filters = pipeline::Pipeline::new(pipeline::Direction::FromPipe);
+    shown_filters.push(Box::new(pipeline::Crlf::new(Arc::clone(
+        &options.line_modes,
+    ))));
+    shown_filters.push(Box::new(filter::ControlFilter::new(
+        &options.filter_control,
+        options.osc52,
+    )));
+    if options.answerback {
+        shown_filters.push(Box::new(pipeline::Answerback::new(Arc::clone(&queries))));
+    }
+
+    loop {
+        // Checked before every read too: a guest that never stops talking
+        // would otherwise keep the read from ever coming back empty.
+        if stop.detached.load(Ordering::SeqCst) {
+            info!("Detached from the pipe");
+            break Ok(());
+        }
+
+        let sends = scripting::take_sends();
+        if !sends.is_empty() {
+            pipe.write_all(&sends)?;
+        }
+
+        // Once the input has ended, only what is already buffered is read.
+        let input_closed = stop.input_closed.load(Ordering::SeqCst);
+        let mut buffer: Vec<u8> = Vec::new();
+        let ret = if input_closed {
+            pipe.read(&mut buffer)
+        } else {
+            pipe.read_wait(&mut buffer, Some(&stop.wake))
+        };
+        match ret {
+            Ok(0) if input_closed => {
+                info!("Pipe output drained");
+                break Ok(());
+            }
+            // Woken by the input ending or a detach; the top of the loop
+            // tells which.
+            Ok(0) => continue,
+            // What arrived after leaving isn't relayed.
+            Ok(_) if stop.detached.load(Ordering::SeqCst) => {
+                info!("Detached from the pipe");
+                break Ok(());
+            }
+            Ok(_) => {}
+            Err(e)
+                if e.code() == ERROR_PIPE_NOT_CONNECTED.into()
+                    || e.code() == ERROR_BROKEN_PIPE.into() =>
+            {
+                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
+                stop.wake.set();
+                // A console read blocked on key-up or focus events.
+                con.cancel_read()?;
+                break Ok(());
+            }
+            Err(e) if e.code() == ERROR_TIMEOUT.into() => continue,
+            Err(e) => {
+                error!("Failed to read from pipe: {:?}", e);
+                stop.wake.set();
+                break Err(e);
+            }
+        }
+
+        // Everything downstream, captures included, sees filtered output.
+        if !filters.is_empty() {
+            buffer = filters.run(&buffer);
+        }
+
+        if options.timestamps {
+            captures.write(&timestamps.apply(&buffer))?;
+        } else {
+            captures.write(buffer.as_slice())?;
+        }
+        if let Some(cast) = cast.lock().unwrap().as_mut() {
+            cast.output(&buffer)?;
+        }
+
+        // Output claimed by a receive-file capture isn't shown.
+        let mut shown = buffer.as_slice();
+        let mut slot = receive.lock().unwrap();
+        if let Some(r) = slot.as_mut() {
+            let (taken, done) = r.feed(shown)?;
+            shown = &shown[taken..];
+            if done {
+                info!("Received {} bytes into {:?}", r.received(), r.path());
+                let msg = format!(
+                    "\r\n[winpipe] received {} bytes into {}\r\n",
+                    r.received(),
+                    r.path().display()
+                );
+                screen.write(msg.as_bytes())?;
+                *slot = None;
+            }
+        }
+        drop(slot);
+
+        let shown = shown_filters.run(shown);
+        screen.write(&shown)?;
+        history.lock().unwrap().push(&shown);
+        // Answered once the output before them is on the console, so the
+        // cursor position reported is where the guest expects it.
+        let pending: Vec<answerback::Query> = std::mem::take(&mut queries.lock().unwrap());
+        if !pending.is_empty() {
+            screen.flush()?;
+        }
+        for query in pending {
+            info!("Answering {:?}", query);
+            if let Err(e) = pipe.write_all(&query.reply(con.cursor().ok())) {
+                warn!("Failed to answer {:?}: {:?}", query, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::error;
+use log::info;
+use log::warn;
+
+use crate::script;
+
+/// Most bytes queued for the writer thread. Past this the session keeps
+/// going and the capture loses data rather than stalling the console.
+const RING_SIZE: usize = 16 * 1024 * 1024;
+
+/// Longest `flush_all` waits for the writer threads, as the console close
+/// handler only has a few seconds.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// When `--record-split` moves the captures on to new files.
+#[derive(Clone, Debug)]
+pub enum Split {
+    /// once a file has grown past this many bytes
+    Size(u64),
+    /// once a file has been written for this long
+    Duration(Duration),
+    /// after output containing this marker
+    Marker(Vec<u8>),
+}
+
+impl FromStr for Split {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("size", n)) => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Ok(Split::Size(n)),
+                _ => Err(format!("invalid size {:?}", n)),
+            },
+            Some(("duration", secs)) => match secs.parse::<f64>() {
+                Ok(secs) if secs > 0.0 => Ok(Split::Duration(Duration::from_secs_f64(secs))),
+                _ => Err(format!("invalid duration {:?}", secs)),
+            },
+            Some(("marker", marker)) if !marker.is_empty() => {
+                Ok(Split::Marker(script::unescape(marker)?))
+            }
+            _ => Err(format!(
+                "expected size:BYTES, duration:SECS or marker:STR, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+static SPLIT: OnceLock<Split> = OnceLock::new();
+/// The file set captures currently go to; 0 is the names as given.
+static SEGMENT: AtomicUsize = AtomicUsize::new(0);
+static SEGMENT_START: Mutex<Option<Instant>> = Mutex::new(None);
+/// Captures are paused from the escape menu; what passes meanwhile is lost.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Every capture with a writer thread, for `flush_all`.
+static OPEN: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+
+pub fn set_split(split: Split) {
+    let _ = SPLIT.set(split);
+}
+
+/// Moves every capture on to new files, e.g. from the escape menu.
+pub fn split() -> usize {
+    split_from(SEGMENT.load(Ordering::SeqCst))
+}
+
+/// Pauses or resumes every capture; returns whether they are now paused.
+pub fn toggle_paused() -> bool {
+    let paused = !PAUSED.fetch_xor(true, Ordering::SeqCst);
+    info!("Captures {}", if paused { "paused" } else { "resumed" });
+    paused
+}
+
+/// Moves on from `segment` unless another capture already has, so files
+/// reaching a limit together start a single new segment.
+fn split_from(segment: usize) -> usize {
+    let next = segment + 1;
+    match SEGMENT.compare_exchange(segment, next, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {
+            *SEGMENT_START.lock().unwrap() = Some(Instant::now());
+            info!("Captures continue in segment {}", next);
+            next
+        }
+        Err(current) => current,
+    }
+}
+
+/// `path` for segment `n`: `out.log`, then `out-001.log`, `out-002.log`...
+fn segment_path(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{:03}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{:03}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+#[derive(Default)]
+struct Ring {
+    data: VecDeque<u8>,
+    closed: bool,
+    dropped: u64,
+    error: Option<io::Error>,
+    /// Flushes asked for and flushes done; the writer syncs the files
+    /// once it has drained the ring.
+    flush: u64,
+    synced: u64,
+}
+
+#[derive(Default)]
+struct Shared {
+    ring: Mutex<Ring>,
+    ready: Condvar,
+    synced: Condvar,
+}
+
+impl Shared {
+    /// Waits until what is queued is on disk, or until `deadline`.
+    fn flush(&self, deadline: Instant) {
+        let mut ring = self.ring.lock().unwrap();
+        if ring.closed {
+            return;
+        }
+        ring.flush += 1;
+        let want = ring.flush;
+        self.ready.notify_one();
+        while ring.synced < want && !ring.closed {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                warn!("Capture flush timed out");
+                return;
+            }
+            ring = self.synced.wait_timeout(ring, left).unwrap().0;
+        }
+    }
+}
+
+/// Writes out and syncs everything queued for every open capture, for exit
+/// paths that don't get to drop them.
+pub fn flush_all() {
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    let open: Vec<Arc<Shared>> = OPEN
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for shared in open {
+        shared.flush(deadline);
+    }
+}
+
+/// Capture files written by a dedicated thread, so a slow disk or a virus
+/// scanner holding the file doesn't block the session. Dropping it waits
+/// for everything queued to be written. Files follow `--record-split`
+/// segments as they start.
+pub struct Capture {
+    shared: Arc<Shared>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+/// The capture files of one `Capture`, written from its thread.
+struct Files {
+    paths: Vec<PathBuf>,
+    files: Vec<File>,
+    segment: usize,
+    /// Size of the current files, as far as this capture knows.
+    written: u64,
+    /// The last bytes written, short of a whole `Split::Marker`, so a
+    /// marker split across writes is still found.
+    tail: Vec<u8>,
+}
+
+impl Files {
+    fn open(paths: Vec<PathBuf>) -> io::Result<Self> {
+        let segment = SEGMENT.load(Ordering::SeqCst);
+        let files = paths
+            .iter()
+            .map(|path| open_file(&segment_path(path, segment)))
+            .collect::<io::Result<Vec<File>>>()?;
+        // Earlier sessions may have written to these files already.
+        let written = match files.first() {
+            Some(file) => file.metadata()?.len(),
+            None => 0,
+        };
+        Ok(Self {
+            paths,
+            files,
+            segment,
+            written,
+            tail: Vec::new(),
+        })
+    }
+
+    /// Reopens the files if the captures have moved on to a new segment.
+    fn follow(&mut self) -> io::Result<()> {
+        let segment = SEGMENT.load(Ordering::SeqCst);
+        if segment != self.segment {
+            *self = Self::open(std::mem::take(&mut self.paths))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        match SPLIT.get() {
+            Some(Split::Size(limit))
+                if self.written > 0 && self.written + buf.len() as u64 > *limit =>
+            {
+                split_from(self.segment);
+            }
+            Some(Split::Duration(limit)) => {
+                let started = *SEGMENT_START
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(Instant::now);
+                if started.elapsed() >= *limit {
+                    split_from(self.segment);
+                }
+            }
+            _ => {}
+        }
+        self.follow()?;
+
+        // Output up to and including a marker closes the current files.
+        if let Some(Split::Marker(marker)) = SPLIT.get() {
+            loop {
+                let mut window = self.tail.clone();
+                window.extend_from_slice(buf);
+                let Some(pos) = window
+                    .windows(marker.len())
+                    .position(|w| w == marker.as_slice())
+                else {
+                    let keep = (marker.len() - 1).min(window.len());
+                    self.tail = window.split_off(window.len() - keep);
+                    break;
+                };
+                // The tail is shorter than the marker, so it ends in `buf`.
+                let (head, rest) = buf.split_at(pos + marker.len() - self.tail.len());
+                self.write_all(head)?;
+                split_from(self.segment);
+                self.follow()?;
+                self.tail.clear();
+                buf = rest;
+            }
+        }
+        self.write_all(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        for file in self.files.iter_mut() {
+            file.write_all(buf)?;
+        }
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        for file in self.files.iter() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// Opens every capture file that is set, for appending, in the current
+/// segment.
+pub fn open(paths: &[&Option<PathBuf>]) -> io::Result<Capture> {
+    let paths: Vec<PathBuf> = paths.iter().filter_map(|path| (*path).clone()).collect();
+    let shared = Arc::new(Shared::default());
+    let writer = if paths.is_empty() {
+        None
+    } else {
+        let files = Files::open(paths)?;
+        let mut open = OPEN.lock().unwrap();
+        open.retain(|shared| shared.strong_count() > 0);
+        open.push(Arc::downgrade(&shared));
+        let shared = Arc::clone(&shared);
+        Some(thread::spawn(move || write_loop(&shared, files)))
+    };
+    Ok(Capture { shared, writer })
+}
+
+fn write_loop(shared: &Shared, mut files: Files) {
+    loop {
+        let (chunk, flush): (Vec<u8>, u64) = {
+            let mut ring = shared.ring.lock().unwrap();
+            while ring.data.is_empty() && !ring.closed && ring.flush == ring.synced {
+                ring = shared.ready.wait(ring).unwrap();
+            }
+            if ring.data.is_empty() && ring.closed {
+                return;
+            }
+            (ring.data.drain(..).collect(), ring.flush)
+        };
+        let mut ret = files.write(&chunk);
+        if ret.is_ok() && flush != shared.ring.lock().unwrap().synced {
+            ret = files.sync();
+        }
+        let mut ring = shared.ring.lock().unwrap();
+        if let Err(e) = ret {
+            error!("Failed to write capture: {:?}", e);
+            ring.error = Some(e);
+            ring.closed = true;
+            ring.data.clear();
+            shared.synced.notify_all();
+            return;
+        }
+        if flush != ring.synced {
+            ring.synced = flush;
+            shared.synced.notify_all();
+        }
+    }
+}
+
+impl Capture {
+    /// Queues `buf` for the capture files. Fails once a write has failed.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.writer.is_none() || buf.is_empty() || PAUSED.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut ring = self.shared.ring.lock().unwrap();
+        // The writer has stopped, keep failing rather than queue into nothing.
+        if let Some(e) = &ring.error {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        if ring.data.len() + buf.len() > RING_SIZE {
+            if ring.dropped == 0 {
+                warn!("Capture can't keep up, dropping output");
+            }
+            ring.dropped += buf.len() as u64;
+            return Ok(());
+        }
+        ring.data.extend(buf);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let dropped = {
+            let mut ring = self.shared.ring.lock().unwrap();
+            ring.closed = true;
+            ring.dropped
+        };
+        self.shared.ready.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        if dropped > 0 {
+            warn!("Capture dropped {} bytes", dropped);
+        }
+    }
+}
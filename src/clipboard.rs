@@ -0,0 +1,46 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ptr::copy_nonoverlapping;
+
+use windows::Win32::Foundation::GlobalFree;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::DataExchange::CloseClipboard;
+use windows::Win32::System::DataExchange::EmptyClipboard;
+use windows::Win32::System::DataExchange::OpenClipboard;
+use windows::Win32::System::DataExchange::SetClipboardData;
+use windows::Win32::System::Memory::GlobalAlloc;
+use windows::Win32::System::Memory::GlobalLock;
+use windows::Win32::System::Memory::GlobalUnlock;
+use windows::Win32::System::Memory::GMEM_MOVEABLE;
+
+const CF_UNICODETEXT: u32 = 13;
+
+/// Replaces the clipboard content with `text`.
+pub fn set_text(text: &str) -> windows::core::Result<()> {
+    let wide: Vec<u16> = text.encode_utf16().chain(std::iter::once(0)).collect();
+
+    unsafe {
+        OpenClipboard(None)?;
+        let ret = (|| -> windows::core::Result<()> {
+            EmptyClipboard()?;
+            let mem = GlobalAlloc(GMEM_MOVEABLE, wide.len() * 2)?;
+            let ptr = GlobalLock(mem) as *mut u16;
+            if ptr.is_null() {
+                let _ = GlobalFree(mem);
+                return Err(windows::core::Error::from_win32());
+            }
+            copy_nonoverlapping(wide.as_ptr(), ptr, wide.len());
+            let _ = GlobalUnlock(mem);
+
+            // The clipboard owns the memory once SetClipboardData succeeds.
+            if let Err(e) = SetClipboardData(CF_UNICODETEXT, HANDLE(mem.0)) {
+                let _ = GlobalFree(mem);
+                return Err(e);
+            }
+            Ok(())
+        })();
+        let _ = CloseClipboard();
+        ret
+    }
+}
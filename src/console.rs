@@ -1,18 +1,38 @@
 // Copyright 2024 Qi, Yadong.
 // SPDX-License-Identifier: Apache-2.0
+use std::ffi::c_void;
+use std::fs::OpenOptions;
+use std::mem::size_of;
+use std::os::windows::io::AsRawHandle;
 use std::sync::Arc;
+use std::sync::Mutex;
 
+use log::info;
+
+use windows::core::PCWSTR;
 use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::MAX_PATH;
+use windows::Win32::Storage::FileSystem::FileNameInfo;
+use windows::Win32::Storage::FileSystem::GetFileInformationByHandleEx;
+use windows::Win32::Storage::FileSystem::GetFileType;
 use windows::Win32::Storage::FileSystem::ReadFile;
 use windows::Win32::Storage::FileSystem::WriteFile;
+use windows::Win32::Storage::FileSystem::FILE_TYPE_PIPE;
 use windows::Win32::System::Console::GetConsoleCP;
 use windows::Win32::System::Console::GetConsoleMode;
 use windows::Win32::System::Console::GetConsoleOutputCP;
+use windows::Win32::System::Console::GetConsoleScreenBufferInfo;
+use windows::Win32::System::Console::GetConsoleTitleW;
+use windows::Win32::System::Console::GetNumberOfConsoleInputEvents;
 use windows::Win32::System::Console::GetStdHandle;
+use windows::Win32::System::Console::ReadConsoleInputW;
+use windows::Win32::System::Console::ReadConsoleW;
 use windows::Win32::System::Console::SetConsoleCP;
 use windows::Win32::System::Console::SetConsoleMode;
 use windows::Win32::System::Console::SetConsoleOutputCP;
+use windows::Win32::System::Console::SetConsoleTitleW;
 use windows::Win32::System::Console::CONSOLE_MODE;
+use windows::Win32::System::Console::CONSOLE_SCREEN_BUFFER_INFO;
 use windows::Win32::System::Console::DISABLE_NEWLINE_AUTO_RETURN;
 use windows::Win32::System::Console::ENABLE_ECHO_INPUT;
 use windows::Win32::System::Console::ENABLE_INSERT_MODE;
@@ -22,17 +42,152 @@ use windows::Win32::System::Console::ENABLE_PROCESSED_OUTPUT;
 use windows::Win32::System::Console::ENABLE_VIRTUAL_TERMINAL_INPUT;
 use windows::Win32::System::Console::ENABLE_VIRTUAL_TERMINAL_PROCESSING;
 use windows::Win32::System::Console::ENABLE_WINDOW_INPUT;
+use windows::Win32::System::Console::INPUT_RECORD;
+use windows::Win32::System::Console::KEY_EVENT;
 use windows::Win32::System::Console::STD_INPUT_HANDLE;
 use windows::Win32::System::Console::STD_OUTPUT_HANDLE;
+use windows::Win32::System::Pipes::PeekNamedPipe;
 use windows::Win32::System::IO::CancelIoEx;
 
+use crate::event;
+use crate::event::Event;
+
 const UNICODE_UTF8_CP_ID: u32 = 65001;
 
+#[repr(C)]
+struct FileNameBuf {
+    len: u32,
+    name: [u16; MAX_PATH as usize],
+}
+
+/// Whether `handle` is a console rather than a file or pipe.
+fn is_console(handle: HANDLE) -> bool {
+    let mut mode = CONSOLE_MODE(0);
+    unsafe { GetConsoleMode(handle, &mut mode).is_ok() }
+}
+
+/// Whether `handle` is one end of an MSYS/Cygwin pty, which is a named pipe
+/// called like `\msys-1888ae32e00d56aa-pty0-from-master`.
+fn is_msys_pty(handle: HANDLE) -> bool {
+    if unsafe { GetFileType(handle) } != FILE_TYPE_PIPE {
+        return false;
+    }
+
+    let mut buf = FileNameBuf {
+        len: 0,
+        name: [0u16; MAX_PATH as usize],
+    };
+    if unsafe {
+        GetFileInformationByHandleEx(
+            handle,
+            FileNameInfo,
+            &mut buf as *mut FileNameBuf as *mut c_void,
+            size_of::<FileNameBuf>() as u32,
+        )
+    }
+    .is_err()
+    {
+        return false;
+    }
+
+    let len = (buf.len as usize / 2).min(buf.name.len());
+    let name = String::from_utf16_lossy(&buf.name[..len]);
+    (name.contains("msys-") || name.contains("cygwin-"))
+        && name.contains("-pty")
+        && (name.contains("-from-master") || name.contains("-to-master"))
+}
+
+/// How virtual terminal sequences are handled on the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VtMode {
+    /// Enable VT input/processing, falling back when the console rejects it.
+    Auto,
+    /// Enable VT input/processing and fail if the console rejects it.
+    Force,
+    /// Leave VT input/processing disabled.
+    Off,
+}
+
+/// Turns UTF-16 console input into UTF-8, holding a trailing high
+/// surrogate back until its low half arrives with the next read.
+#[derive(Default)]
+pub struct Utf16Decoder {
+    high: Option<u16>,
+}
+
+impl Utf16Decoder {
+    pub fn decode(&mut self, units: &[u16], out: &mut Vec<u8>) {
+        let mut units: Vec<u16> = self
+            .high
+            .take()
+            .into_iter()
+            .chain(units.iter().copied())
+            .collect();
+        if let Some(last) = units.last() {
+            if (0xD800..0xDC00).contains(last) {
+                self.high = units.pop();
+            }
+        }
+
+        for c in char::decode_utf16(units) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            let mut b = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+        }
+    }
+}
+
+/// What the std handles are connected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StdioKind {
+    /// A real console, driven through the console APIs.
+    Console,
+    /// An MSYS/Cygwin pty, relayed as raw bytes.
+    Pty,
+    /// Plain handles with no console APIs involved at all (--headless, or
+    /// stdin or stdout redirected).
+    Headless,
+}
+
+/// Code pages and modes of a console as found before `setup`.
+#[derive(Clone, Copy, Debug)]
+pub struct SavedState {
+    pub in_cp: u32,
+    pub out_cp: u32,
+    pub in_mode: u32,
+    pub out_mode: u32,
+}
+
+impl SavedState {
+    /// Puts the state back on the console this process is attached to,
+    /// without needing the std handles of the process that saved it.
+    pub fn restore(&self) -> windows::core::Result<()> {
+        let conin = OpenOptions::new().read(true).write(true).open("CONIN$")?;
+        let conout = OpenOptions::new().read(true).write(true).open("CONOUT$")?;
+        unsafe {
+            SetConsoleCP(self.in_cp)?;
+            SetConsoleOutputCP(self.out_cp)?;
+            SetConsoleMode(HANDLE(conin.as_raw_handle()), CONSOLE_MODE(self.in_mode))?;
+            SetConsoleMode(HANDLE(conout.as_raw_handle()), CONSOLE_MODE(self.out_mode))?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Console {
+    kind: StdioKind,
+    decoder: Mutex<Utf16Decoder>,
+    /// Decoded input that didn't fit the caller's buffer, returned first
+    /// by the next read.
+    decoded: Mutex<Vec<u8>>,
     orig_con_cp: u32,
     orig_con_ocp: u32,
     orig_in_mode: CONSOLE_MODE,
     orig_out_mode: CONSOLE_MODE,
+    /// Window title from before the first `set_title`.
+    orig_title: Mutex<Option<Vec<u16>>>,
+    /// Arguments of the last `setup`, for `resume`.
+    setup_args: Mutex<Option<(VtMode, bool)>>,
     stdin_handle: Arc<SafeHandle>,
     stdout_handle: Arc<SafeHandle>,
 }
@@ -47,12 +202,26 @@ unsafe impl Sync for SafeHandle {}
 
 impl Console {
     pub fn new() -> windows::core::Result<Self> {
-        let orig_con_cp = unsafe { GetConsoleCP() };
-        let orig_con_ocp = unsafe { GetConsoleOutputCP() };
-
         let stdin_handle = unsafe { GetStdHandle(STD_INPUT_HANDLE)? };
         let stdout_handle = unsafe { GetStdHandle(STD_OUTPUT_HANDLE)? };
 
+        // Under Git Bash/MSYS the std handles are pipes to a pty and the
+        // console APIs fail, so relay raw bytes and let the pty do the rest.
+        if is_msys_pty(stdin_handle) || is_msys_pty(stdout_handle) {
+            return Ok(Self::raw(StdioKind::Pty, stdin_handle, stdout_handle));
+        }
+
+        // Redirected to a file or pipe, e.g. `type in.txt | winpipe ... >
+        // out.txt`: there is no console to set up, so the std handles are
+        // used as --headless does and the session ends at stdin EOF.
+        if !is_console(stdin_handle) || !is_console(stdout_handle) {
+            info!("stdin or stdout is redirected, using plain file I/O");
+            return Ok(Self::raw(StdioKind::Headless, stdin_handle, stdout_handle));
+        }
+
+        let orig_con_cp = unsafe { GetConsoleCP() };
+        let orig_con_ocp = unsafe { GetConsoleOutputCP() };
+
         let mut orig_in_mode = CONSOLE_MODE(0);
         unsafe { GetConsoleMode(stdin_handle, &mut orig_in_mode)? };
 
@@ -60,16 +229,87 @@ impl Console {
         unsafe { GetConsoleMode(stdout_handle, &mut orig_out_mode)? };
 
         Ok(Self {
+            kind: StdioKind::Console,
+            decoder: Mutex::new(Utf16Decoder::default()),
+            decoded: Mutex::new(Vec::new()),
             orig_con_cp,
             orig_con_ocp,
             orig_in_mode,
             orig_out_mode,
+            orig_title: Mutex::new(None),
+            setup_args: Mutex::new(None),
             stdin_handle: Arc::new(SafeHandle(stdin_handle)),
             stdout_handle: Arc::new(SafeHandle(stdout_handle)),
         })
     }
 
+    /// Wraps the std handles for plain file I/O without ever calling a
+    /// console API, for services, scheduled tasks and CI runners.
+    pub fn new_headless() -> windows::core::Result<Self> {
+        let stdin_handle = unsafe { GetStdHandle(STD_INPUT_HANDLE)? };
+        let stdout_handle = unsafe { GetStdHandle(STD_OUTPUT_HANDLE)? };
+        Ok(Self::raw(StdioKind::Headless, stdin_handle, stdout_handle))
+    }
+
+    fn raw(kind: StdioKind, stdin_handle: HANDLE, stdout_handle: HANDLE) -> Self {
+        Self {
+            kind,
+            decoder: Mutex::new(Utf16Decoder::default()),
+            decoded: Mutex::new(Vec::new()),
+            orig_con_cp: 0,
+            orig_con_ocp: 0,
+            orig_in_mode: CONSOLE_MODE(0),
+            orig_out_mode: CONSOLE_MODE(0),
+            orig_title: Mutex::new(None),
+            setup_args: Mutex::new(None),
+            stdin_handle: Arc::new(SafeHandle(stdin_handle)),
+            stdout_handle: Arc::new(SafeHandle(stdout_handle)),
+        }
+    }
+
+    pub fn kind(&self) -> StdioKind {
+        self.kind
+    }
+
+    pub fn is_pty(&self) -> bool {
+        self.kind == StdioKind::Pty
+    }
+
+    /// Whether stdio is relayed as raw bytes rather than through a console.
+    pub fn is_raw(&self) -> bool {
+        self.kind != StdioKind::Console
+    }
+
+    /// The state `restore` goes back to, `None` when there is no console.
+    pub fn saved_state(&self) -> Option<SavedState> {
+        if self.is_raw() {
+            return None;
+        }
+        Some(SavedState {
+            in_cp: self.orig_con_cp,
+            out_cp: self.orig_con_ocp,
+            in_mode: self.orig_in_mode.0,
+            out_mode: self.orig_out_mode.0,
+        })
+    }
+
     pub fn restore(&self) -> windows::core::Result<()> {
+        if self.is_raw() {
+            return Ok(());
+        }
+        self.suspend()?;
+        if let Some(title) = self.orig_title.lock().unwrap().take() {
+            unsafe { SetConsoleTitleW(PCWSTR(title.as_ptr()))? };
+        }
+        Ok(())
+    }
+
+    /// Temporarily puts back the original code pages and modes, e.g. to
+    /// run a host command; `resume` returns to the session setup.
+    pub fn suspend(&self) -> windows::core::Result<()> {
+        if self.is_raw() {
+            return Ok(());
+        }
         unsafe {
             SetConsoleCP(self.orig_con_cp)?;
             SetConsoleOutputCP(self.orig_con_ocp)?;
@@ -79,31 +319,85 @@ impl Console {
         Ok(())
     }
 
-    pub fn setup(&self) -> windows::core::Result<()> {
-        unsafe {
-            SetConsoleCP(UNICODE_UTF8_CP_ID)?;
-            SetConsoleOutputCP(UNICODE_UTF8_CP_ID)?;
+    pub fn resume(&self) -> windows::core::Result<()> {
+        let setup_args = *self.setup_args.lock().unwrap();
+        match setup_args {
+            Some((vt, keep_cp)) => self.setup(vt, keep_cp),
+            None => Ok(()),
+        }
+    }
+
+    /// Puts the window title from before the first `set_title` back.
+    pub fn reset_title(&self) -> windows::core::Result<()> {
+        match self.orig_title.lock().unwrap().as_ref() {
+            Some(title) => unsafe { SetConsoleTitleW(PCWSTR(title.as_ptr())) },
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the window title; `restore` puts the original one back.
+    pub fn set_title(&self, title: &str) -> windows::core::Result<()> {
+        if self.is_raw() {
+            return Ok(());
+        }
+        let mut orig = self.orig_title.lock().unwrap();
+        if orig.is_none() {
+            let mut buffer = [0u16; 1024];
+            let n = unsafe { GetConsoleTitleW(&mut buffer) } as usize;
+            let mut saved = buffer[..n.min(buffer.len())].to_vec();
+            saved.push(0);
+            *orig = Some(saved);
+        }
+        let title: Vec<u16> = title.encode_utf16().chain(std::iter::once(0)).collect();
+        unsafe { SetConsoleTitleW(PCWSTR(title.as_ptr())) }
+    }
+
+    /// Switches the console to raw input and VT output. The code pages are
+    /// switched to UTF-8 unless `keep_cp` is set.
+    pub fn setup(&self, vt: VtMode, keep_cp: bool) -> windows::core::Result<()> {
+        if self.is_raw() {
+            return Ok(());
+        }
+        *self.setup_args.lock().unwrap() = Some((vt, keep_cp));
+        if !keep_cp {
+            unsafe {
+                SetConsoleCP(UNICODE_UTF8_CP_ID)?;
+                SetConsoleOutputCP(UNICODE_UTF8_CP_ID)?;
+            }
         }
         let mut mode =
             !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_INSERT_MODE | ENABLE_PROCESSED_INPUT)
                 | ENABLE_WINDOW_INPUT;
-        mode &= self.orig_in_mode | ENABLE_VIRTUAL_TERMINAL_INPUT;
+        mode &= match vt {
+            VtMode::Off => self.orig_in_mode & !ENABLE_VIRTUAL_TERMINAL_INPUT,
+            _ => self.orig_in_mode | ENABLE_VIRTUAL_TERMINAL_INPUT,
+        };
         unsafe {
             match SetConsoleMode(self.stdin_handle.0, mode) {
                 Ok(_) => (),
                 Err(e) => {
                     eprintln!("Failed to set console in mode: {:?}", e);
+                    if vt == VtMode::Force {
+                        return Err(e);
+                    }
                 }
             }
         }
 
-        mode = self.orig_out_mode
-            | ENABLE_PROCESSED_OUTPUT
-            | ENABLE_VIRTUAL_TERMINAL_PROCESSING
-            | DISABLE_NEWLINE_AUTO_RETURN;
+        let vt_out = ENABLE_VIRTUAL_TERMINAL_PROCESSING | DISABLE_NEWLINE_AUTO_RETURN;
+        mode = match vt {
+            VtMode::Off => (self.orig_out_mode | ENABLE_PROCESSED_OUTPUT) & !vt_out,
+            _ => self.orig_out_mode | ENABLE_PROCESSED_OUTPUT | vt_out,
+        };
         unsafe {
             match SetConsoleMode(self.stdout_handle.0, mode) {
                 Ok(_) => (),
+                // Consoles without VT support reject the flag, fall back to
+                // plain processed output unless VT was explicitly forced.
+                Err(e) if vt == VtMode::Auto => {
+                    eprintln!("Failed to enable VT processing: {:?}", e);
+                    SetConsoleMode(self.stdout_handle.0, mode & !vt_out)?;
+                }
                 Err(e) => {
                     eprintln!("Failed to set console out mode: {:?}", e);
                     return Err(e);
@@ -114,6 +408,24 @@ impl Console {
         Ok(())
     }
 
+    /// Visible window size as (columns, rows).
+    pub fn size(&self) -> windows::core::Result<(u16, u16)> {
+        let mut info = CONSOLE_SCREEN_BUFFER_INFO::default();
+        unsafe { GetConsoleScreenBufferInfo(self.stdout_handle.0, &mut info)? };
+        let cols = info.srWindow.Right - info.srWindow.Left + 1;
+        let rows = info.srWindow.Bottom - info.srWindow.Top + 1;
+        Ok((cols.max(1) as u16, rows.max(1) as u16))
+    }
+
+    /// Cursor position within the visible window as 1-based (row, column).
+    pub fn cursor(&self) -> windows::core::Result<(u16, u16)> {
+        let mut info = CONSOLE_SCREEN_BUFFER_INFO::default();
+        unsafe { GetConsoleScreenBufferInfo(self.stdout_handle.0, &mut info)? };
+        let row = info.dwCursorPosition.Y - info.srWindow.Top + 1;
+        let col = info.dwCursorPosition.X - info.srWindow.Left + 1;
+        Ok((row.max(1) as u16, col.max(1) as u16))
+    }
+
     pub fn write(&self, buffer: &[u8]) -> windows::core::Result<u32> {
         let mut bytes_written: u32 = buffer.len() as u32;
         match unsafe {
@@ -129,19 +441,140 @@ impl Console {
         }
     }
 
-    pub fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
-        let mut bytes_read: u32 = 0;
-        match unsafe {
-            ReadFile(
+    /// Reads into `buffer`, returning the byte count and whether the read
+    /// used up the whole request (i.e. more input may be queued).
+    fn read_some(&self, buffer: &mut [u8]) -> windows::core::Result<(u32, bool)> {
+        if self.is_raw() {
+            let mut bytes_read: u32 = 0;
+            unsafe {
+                ReadFile(
+                    self.stdin_handle.0,
+                    Some(buffer),
+                    Some(&mut bytes_read),
+                    None,
+                )?
+            };
+            return Ok((bytes_read, bytes_read as usize == buffer.len()));
+        }
+
+        let mut decoded = self.decoded.lock().unwrap();
+        if !decoded.is_empty() {
+            let n = decoded.len().min(buffer.len());
+            buffer[..n].copy_from_slice(&decoded[..n]);
+            decoded.drain(..n);
+            return Ok((n as u32, !decoded.is_empty()));
+        }
+
+        // A UTF-16 code unit expands to at most 3 bytes of UTF-8; what a
+        // held back surrogate or a tiny buffer leaves over is kept.
+        let mut wide: Vec<u16> = vec![0u16; (buffer.len() / 3).max(1)];
+        let mut chars_read: u32 = 0;
+        unsafe {
+            ReadConsoleW(
                 self.stdin_handle.0,
-                Some(buffer),
-                Some(&mut bytes_read),
+                wide.as_mut_ptr() as *mut c_void,
+                wide.len() as u32,
+                &mut chars_read,
                 None,
-            )
-        } {
-            Err(e) => Err(e),
-            Ok(_) => Ok(bytes_read),
+            )?
+        };
+
+        let mut utf8: Vec<u8> = Vec::with_capacity(buffer.len());
+        self.decoder
+            .lock()
+            .unwrap()
+            .decode(&wide[..chars_read as usize], &mut utf8);
+        let n = utf8.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&utf8[..n]);
+        decoded.extend_from_slice(&utf8[n..]);
+        Ok((
+            n as u32,
+            chars_read as usize == wide.len() || !decoded.is_empty(),
+        ))
+    }
+
+    pub fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        let (n, _) = self.read_some(buffer)?;
+        Ok(n)
+    }
+
+    /// Drains the console input queued so far and tells whether `key` was
+    /// typed in it. For commands that run a while, where other keys would
+    /// only get in the way; anything but a console never has it.
+    pub fn key_typed(&self, key: u8) -> windows::core::Result<bool> {
+        if self.is_raw() {
+            return Ok(false);
+        }
+        let mut typed = false;
+        while self.pending_input()? > 0 {
+            let mut records = [INPUT_RECORD::default(); 16];
+            let mut n: u32 = 0;
+            unsafe { ReadConsoleInputW(self.stdin_handle.0, &mut records, &mut n)? };
+            typed |= records[..n as usize].iter().any(|r| unsafe {
+                r.EventType == KEY_EVENT as u16
+                    && r.Event.KeyEvent.bKeyDown.as_bool()
+                    && r.Event.KeyEvent.uChar.UnicodeChar == key as u16
+            });
+        }
+        Ok(typed)
+    }
+
+    fn has_decoded(&self) -> bool {
+        !self.decoded.lock().unwrap().is_empty()
+    }
+
+    /// Number of input events (or raw bytes) waiting to be read.
+    pub fn pending_input(&self) -> windows::core::Result<u32> {
+        let mut pending: u32 = 0;
+        unsafe {
+            if self.is_raw() {
+                // Only pipes can be peeked, anything else reports nothing
+                // pending and is read one buffer per wakeup.
+                if PeekNamedPipe(self.stdin_handle.0, None, 0, None, Some(&mut pending), None)
+                    .is_err()
+                {
+                    return Ok(0);
+                }
+            } else {
+                GetNumberOfConsoleInputEvents(self.stdin_handle.0, &mut pending)?;
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Reads everything available in one wakeup, growing `buffer` while the
+    /// previous read filled it and more input is still queued.
+    pub fn read_available(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        let (n, mut filled) = self.read_some(buffer)?;
+        let mut total = n as usize;
+        while filled && (self.has_decoded() || self.pending_input()? > 0) {
+            let mut more = vec![0u8; buffer.len()];
+            let (n, f) = self.read_some(&mut more)?;
+            buffer.truncate(total);
+            buffer.extend_from_slice(&more[..n as usize]);
+            total += n as usize;
+            filled = f;
+        }
+        Ok(total as u32)
+    }
+
+    /// Waits until console input is queued or `wake` is set, and returns
+    /// false for the latter. Only a console is waited on; for other stdin
+    /// the read itself blocks.
+    pub fn wait_input(&self, wake: &Event) -> windows::core::Result<bool> {
+        Ok(self.wait_input_or(&[wake])?.is_none())
+    }
+
+    /// Waits until console input is queued, returning `None`, or one of
+    /// `events` is set, returning its index.
+    pub fn wait_input_or(&self, events: &[&Event]) -> windows::core::Result<Option<usize>> {
+        if self.is_raw() || self.has_decoded() {
+            return Ok(None);
         }
+        let handles: Vec<HANDLE> = std::iter::once(self.stdin_handle.0)
+            .chain(events.iter().map(|event| event.as_handle()))
+            .collect();
+        Ok(event::wait_any(&handles)?.checked_sub(1))
     }
 
     pub fn cancel_read(&self) -> windows::core::Result<()> {
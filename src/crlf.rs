@@ -0,0 +1,118 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use clap::ValueEnum;
+
+/// Which directions get their line ends translated to CR LF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Crlf {
+    /// input: CR or LF alone is sent as CR LF
+    In,
+    /// pipe output: LF alone is shown as CR LF
+    Out,
+    /// both directions
+    Both,
+}
+
+/// Line-ending translation and local echo of a session. Both relay threads
+/// read it and the escape menu switches it while they run.
+#[derive(Debug, Default)]
+pub struct LineModes {
+    crlf_in: AtomicBool,
+    crlf_out: AtomicBool,
+    local_echo: AtomicBool,
+}
+
+impl LineModes {
+    pub fn new(crlf: Option<Crlf>, local_echo: bool) -> Self {
+        let modes = Self::default();
+        modes.set_crlf(crlf);
+        modes.local_echo.store(local_echo, Ordering::SeqCst);
+        modes
+    }
+
+    pub fn crlf_in(&self) -> bool {
+        self.crlf_in.load(Ordering::SeqCst)
+    }
+
+    pub fn crlf_out(&self) -> bool {
+        self.crlf_out.load(Ordering::SeqCst)
+    }
+
+    pub fn local_echo(&self) -> bool {
+        self.local_echo.load(Ordering::SeqCst)
+    }
+
+    pub fn crlf(&self) -> Option<Crlf> {
+        match (self.crlf_in(), self.crlf_out()) {
+            (true, true) => Some(Crlf::Both),
+            (true, false) => Some(Crlf::In),
+            (false, true) => Some(Crlf::Out),
+            (false, false) => None,
+        }
+    }
+
+    fn set_crlf(&self, crlf: Option<Crlf>) {
+        let (crlf_in, crlf_out) = match crlf {
+            Some(Crlf::In) => (true, false),
+            Some(Crlf::Out) => (false, true),
+            Some(Crlf::Both) => (true, true),
+            None => (false, false),
+        };
+        self.crlf_in.store(crlf_in, Ordering::SeqCst);
+        self.crlf_out.store(crlf_out, Ordering::SeqCst);
+    }
+
+    /// Turns input translation off, leaving output as it is.
+    pub fn clear_crlf_in(&self) {
+        self.crlf_in.store(false, Ordering::SeqCst);
+    }
+
+    /// Steps the translation through off, in, out and both; returns the
+    /// new setting.
+    pub fn next_crlf(&self) -> Option<Crlf> {
+        let next = match self.crlf() {
+            None => Some(Crlf::In),
+            Some(Crlf::In) => Some(Crlf::Out),
+            Some(Crlf::Out) => Some(Crlf::Both),
+            Some(Crlf::Both) => None,
+        };
+        self.set_crlf(next);
+        next
+    }
+
+    /// Returns whether local echo is now on.
+    pub fn toggle_local_echo(&self) -> bool {
+        !self.local_echo.fetch_xor(true, Ordering::SeqCst)
+    }
+}
+
+pub fn name(crlf: Option<Crlf>) -> &'static str {
+    match crlf {
+        Some(Crlf::In) => "input",
+        Some(Crlf::Out) => "output",
+        Some(Crlf::Both) => "input and output",
+        None => "off",
+    }
+}
+
+/// Makes every line end CR LF: a LF without a CR before it gets one, and
+/// with `lone_cr` so does a CR without a LF after it. `last_cr` carries
+/// whether the previous chunk ended in CR.
+pub fn to_crlf(buf: &[u8], lone_cr: bool, last_cr: &mut bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len() + buf.len() / 8);
+    for &b in buf {
+        match b {
+            // Already sent with the CR.
+            b'\n' if *last_cr && lone_cr => {}
+            b'\n' if !*last_cr => out.extend_from_slice(b"\r\n"),
+            b'\r' if lone_cr => out.extend_from_slice(b"\r\n"),
+            _ => out.push(b),
+        }
+        *last_cr = b == b'\r';
+    }
+    out
+}
@@ -0,0 +1,97 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Once;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use log::info;
+use log::warn;
+
+use windows::Win32::Foundation::BOOL;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::TRUE;
+use windows::Win32::System::Console::SetConsoleCtrlHandler;
+use windows::Win32::System::Console::CTRL_BREAK_EVENT;
+use windows::Win32::System::Console::CTRL_CLOSE_EVENT;
+use windows::Win32::System::Console::CTRL_C_EVENT;
+use windows::Win32::System::Console::CTRL_LOGOFF_EVENT;
+use windows::Win32::System::Console::CTRL_SHUTDOWN_EVENT;
+
+/// What Ctrl+C does while winpipe owns the console.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CtrlC {
+    /// send it to the pipe, as any other key
+    #[default]
+    Passthrough,
+    /// end the session and exit winpipe
+    Exit,
+}
+
+/// A console interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    CtrlC,
+    CtrlBreak,
+}
+
+type Callback = Box<dyn Fn() + Send + Sync>;
+type InterruptCallback = Box<dyn Fn(Interrupt) + Send + Sync>;
+
+static ON_CLOSE: OnceLock<Callback> = OnceLock::new();
+static ON_INTERRUPT: OnceLock<InterruptCallback> = OnceLock::new();
+static INSTALL: Once = Once::new();
+
+unsafe extern "system" fn handler(ctrl_type: u32) -> BOOL {
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => match ON_INTERRUPT.get() {
+            Some(on_interrupt) => {
+                info!("Console interrupt (event {})", ctrl_type);
+                on_interrupt(if ctrl_type == CTRL_C_EVENT {
+                    Interrupt::CtrlC
+                } else {
+                    Interrupt::CtrlBreak
+                });
+                TRUE
+            }
+            None => FALSE,
+        },
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            warn!("Console is going away (event {}), cleaning up", ctrl_type);
+            if let Some(on_close) = ON_CLOSE.get() {
+                on_close();
+            }
+            // Windows ends the process once this returns, or when the grace
+            // period runs out.
+            TRUE
+        }
+        _ => FALSE,
+    }
+}
+
+/// Runs `on_close` when the console window is closed or the user logs off,
+/// within the few seconds Windows grants before it kills the process.
+pub fn on_close<F>(on_close: F) -> windows::core::Result<()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let _ = ON_CLOSE.set(Box::new(on_close));
+    install()
+}
+
+/// Runs `on_interrupt` instead of exiting on Ctrl+C and Ctrl+Break. With
+/// console input processing off, as in a session, Ctrl+C is read as a key
+/// and only Ctrl+Break (or a Ctrl+C in a pty or redirected stdin) gets here.
+pub fn on_interrupt<F>(on_interrupt: F) -> windows::core::Result<()>
+where
+    F: Fn(Interrupt) + Send + Sync + 'static,
+{
+    let _ = ON_INTERRUPT.set(Box::new(on_interrupt));
+    install()
+}
+
+fn install() -> windows::core::Result<()> {
+    let mut ret = Ok(());
+    INSTALL.call_once(|| ret = unsafe { SetConsoleCtrlHandler(Some(handler), TRUE) });
+    ret
+}
@@ -0,0 +1,70 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use log::error;
+
+use windows::core::HRESULT;
+use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
+use windows::Win32::Foundation::ERROR_BAD_NETPATH;
+use windows::Win32::Foundation::ERROR_BAD_PATHNAME;
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_FILE_NOT_FOUND;
+use windows::Win32::Foundation::ERROR_INVALID_NAME;
+use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+use windows::Win32::Foundation::ERROR_SEM_TIMEOUT;
+use windows::Win32::Foundation::WIN32_ERROR;
+
+/// Turns the common failures around opening and using `path` into a hint
+/// the user can act on. Returns `None` for anything not worth explaining.
+pub fn explain(code: HRESULT, path: &str) -> Option<String> {
+    let msg = if code == ERROR_FILE_NOT_FOUND.into() {
+        format!(
+            "no server is listening on {}; is the VM running? (--wait or --retries waits for it)",
+            path
+        )
+    } else if code == ERROR_ACCESS_DENIED.into() {
+        format!(
+            "access to {} was denied; the server may only accept elevated or other users",
+            path
+        )
+    } else if code == ERROR_PIPE_BUSY.into() {
+        format!(
+            "every instance of {} is busy; is another client already attached?",
+            path
+        )
+    } else if code == ERROR_BAD_PATHNAME.into() || code == ERROR_INVALID_NAME.into() {
+        format!(
+            "{} is not a valid pipe path; expected \\\\.\\pipe\\<name> or \\\\<server>\\pipe\\<name>",
+            path
+        )
+    } else if code == ERROR_BAD_NETPATH.into() {
+        format!("the server in {} can't be reached", path)
+    } else if code == ERROR_PIPE_NOT_CONNECTED.into() || code == ERROR_BROKEN_PIPE.into() {
+        format!("the server closed {}", path)
+    } else if code == ERROR_SEM_TIMEOUT.into() {
+        format!("timed out waiting for {}", path)
+    } else {
+        return None;
+    };
+    Some(msg)
+}
+
+/// Logs `what` failed on `path`, explained when the error is a known one.
+pub fn report(what: &str, e: &windows::core::Error, path: &str) {
+    match explain(e.code(), path) {
+        Some(msg) => error!("{}: {} ({})", what, msg, e.code()),
+        None => error!("{}: {:?}", what, e),
+    }
+}
+
+/// Like [`report`] for errors that went through `std::io`.
+pub fn report_io(what: &str, e: &std::io::Error, path: &str) {
+    let explained = e
+        .raw_os_error()
+        .and_then(|code| explain(WIN32_ERROR(code as u32).to_hresult(), path));
+    match explained {
+        Some(msg) => error!("{}: {}", what, msg),
+        None => error!("{}: {:?}", what, e),
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::TRUE;
+use windows::Win32::System::Threading::CreateEventW;
+use windows::Win32::System::Threading::ResetEvent;
+use windows::Win32::System::Threading::SetEvent;
+use windows::Win32::System::Threading::WaitForMultipleObjects;
+use windows::Win32::System::Threading::WaitForSingleObject;
+use windows::Win32::System::Threading::INFINITE;
+use windows::Win32::System::Threading::WAIT_OBJECT_0;
+
+use crate::named_pipe::HandleDesc;
+
+/// An unnamed manual-reset event: once set it stays set, waking every
+/// wait on it, until it is reset.
+#[derive(Debug)]
+pub struct Event {
+    handle: HandleDesc,
+}
+
+impl Event {
+    pub fn new() -> windows::core::Result<Self> {
+        let handle = unsafe { CreateEventW(None, TRUE, FALSE, PCWSTR::null())? };
+        Ok(Self {
+            handle: HandleDesc::from_handle(handle),
+        })
+    }
+
+    pub fn as_handle(&self) -> HANDLE {
+        self.handle.as_handle()
+    }
+
+    pub fn set(&self) {
+        unsafe {
+            let _ = SetEvent(self.as_handle());
+        }
+    }
+
+    pub fn reset(&self) {
+        unsafe {
+            let _ = ResetEvent(self.as_handle());
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        unsafe { WaitForSingleObject(self.as_handle(), 0) == WAIT_OBJECT_0 }
+    }
+}
+
+/// Blocks until one of `handles` is signalled and returns its index.
+pub fn wait_any(handles: &[HANDLE]) -> windows::core::Result<usize> {
+    let ret = unsafe { WaitForMultipleObjects(handles, FALSE, INFINITE) };
+    match ret.0.checked_sub(WAIT_OBJECT_0.0) {
+        Some(i) if (i as usize) < handles.len() => Ok(i as usize),
+        _ => Err(windows::core::Error::from_win32()),
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+use std::os::windows::io::FromRawHandle;
+use std::sync::Arc;
+use std::thread;
+
+use log::error;
+use log::info;
+use log::warn;
+
+use windows::core::PCWSTR;
+use windows::core::PWSTR;
+use windows::Win32::Foundation::CloseHandle;
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Console::ClosePseudoConsole;
+use windows::Win32::System::Console::CreatePseudoConsole;
+use windows::Win32::System::Console::COORD;
+use windows::Win32::System::Console::HPCON;
+use windows::Win32::System::JobObjects::AssignProcessToJobObject;
+use windows::Win32::System::JobObjects::CreateJobObjectW;
+use windows::Win32::System::JobObjects::JobObjectExtendedLimitInformation;
+use windows::Win32::System::JobObjects::SetInformationJobObject;
+use windows::Win32::System::JobObjects::TerminateJobObject;
+use windows::Win32::System::JobObjects::JOBOBJECT_EXTENDED_LIMIT_INFORMATION;
+use windows::Win32::System::JobObjects::JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+use windows::Win32::System::Pipes::CreatePipe;
+use windows::Win32::System::Threading::CreateProcessW;
+use windows::Win32::System::Threading::DeleteProcThreadAttributeList;
+use windows::Win32::System::Threading::GetExitCodeProcess;
+use windows::Win32::System::Threading::InitializeProcThreadAttributeList;
+use windows::Win32::System::Threading::ResumeThread;
+use windows::Win32::System::Threading::TerminateProcess;
+use windows::Win32::System::Threading::UpdateProcThreadAttribute;
+use windows::Win32::System::Threading::CREATE_SUSPENDED;
+use windows::Win32::System::Threading::EXTENDED_STARTUPINFO_PRESENT;
+use windows::Win32::System::Threading::LPPROC_THREAD_ATTRIBUTE_LIST;
+use windows::Win32::System::Threading::PROCESS_INFORMATION;
+use windows::Win32::System::Threading::PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE;
+use windows::Win32::System::Threading::STARTUPINFOEXW;
+
+use crate::event;
+use crate::event::Event;
+use crate::named_pipe::HandleDesc;
+use crate::transport::Transport;
+
+/// Size of the pseudo console the command runs in.
+const SIZE: COORD = COORD { X: 120, Y: 30 };
+
+/// A command running in a pseudo console. Its process tree is in a job
+/// that kills it when the job handle is closed, so nothing outlives
+/// winpipe or the connection, however either ends.
+struct Child {
+    pcon: HPCON,
+    process: HandleDesc,
+    job: HandleDesc,
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        unsafe { ClosePseudoConsole(self.pcon) };
+    }
+}
+
+/// A pipe pair for one direction of the pseudo console: (read, write).
+fn create_pipe() -> windows::core::Result<(HANDLE, HANDLE)> {
+    let mut read = HANDLE::default();
+    let mut write = HANDLE::default();
+    unsafe { CreatePipe(&mut read, &mut write, None, 0)? };
+    Ok((read, write))
+}
+
+fn kill_on_close_job() -> windows::core::Result<HandleDesc> {
+    let job = HandleDesc::from_handle(unsafe { CreateJobObjectW(None, PCWSTR::null())? });
+    let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+    limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+    unsafe {
+        SetInformationJobObject(
+            job.as_handle(),
+            JobObjectExtendedLimitInformation,
+            &limits as *const _ as *const c_void,
+            size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        )?;
+    }
+    Ok(job)
+}
+
+/// Starts `cmdline` in a new pseudo console, inside a kill-on-close job.
+/// Also returns the writer of its console input and the reader of its
+/// console output.
+fn spawn(cmdline: &str) -> windows::core::Result<(Child, File, File)> {
+    let (in_read, in_write) = create_pipe()?;
+    let (out_read, out_write) = create_pipe()?;
+    let pcon = unsafe { CreatePseudoConsole(SIZE, in_read, out_write, 0) };
+    // The pseudo console holds its own copies of its ends.
+    unsafe {
+        let _ = CloseHandle(in_read);
+        let _ = CloseHandle(out_write);
+    }
+    let input = unsafe { File::from_raw_handle(in_write.0) };
+    let output = unsafe { File::from_raw_handle(out_read.0) };
+    let pcon = pcon?;
+
+    let mut size: usize = 0;
+    // Fails with ERROR_INSUFFICIENT_BUFFER, having set the size needed.
+    let _ = unsafe {
+        InitializeProcThreadAttributeList(LPPROC_THREAD_ATTRIBUTE_LIST::default(), 1, 0, &mut size)
+    };
+    let mut attributes = vec![0u8; size];
+    let list = LPPROC_THREAD_ATTRIBUTE_LIST(attributes.as_mut_ptr() as *mut c_void);
+
+    let ret = unsafe {
+        InitializeProcThreadAttributeList(list, 1, 0, &mut size).and_then(|_| {
+            UpdateProcThreadAttribute(
+                list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+                Some(pcon.0 as *const c_void),
+                size_of::<HPCON>(),
+                None,
+                None,
+            )
+        })
+    };
+    if let Err(e) = ret {
+        unsafe { ClosePseudoConsole(pcon) };
+        return Err(e);
+    }
+
+    let mut startup = STARTUPINFOEXW::default();
+    startup.StartupInfo.cb = size_of::<STARTUPINFOEXW>() as u32;
+    startup.lpAttributeList = list;
+    let mut command: Vec<u16> = cmdline.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut info = PROCESS_INFORMATION::default();
+    let ret = unsafe {
+        CreateProcessW(
+            PCWSTR::null(),
+            PWSTR(command.as_mut_ptr()),
+            None,
+            None,
+            FALSE,
+            EXTENDED_STARTUPINFO_PRESENT | CREATE_SUSPENDED,
+            None,
+            PCWSTR::null(),
+            &startup.StartupInfo,
+            &mut info,
+        )
+    };
+    unsafe { DeleteProcThreadAttributeList(list) };
+    if let Err(e) = ret {
+        unsafe { ClosePseudoConsole(pcon) };
+        return Err(e);
+    }
+
+    let process = HandleDesc::from_handle(info.hProcess);
+    let thread = HandleDesc::from_handle(info.hThread);
+    // In the job before it runs, so its children are too.
+    let job = kill_on_close_job().and_then(|job| {
+        unsafe { AssignProcessToJobObject(job.as_handle(), process.as_handle())? };
+        Ok(job)
+    });
+    let job = match job {
+        Ok(job) => job,
+        Err(e) => {
+            unsafe {
+                let _ = TerminateProcess(process.as_handle(), 1);
+                ClosePseudoConsole(pcon);
+            }
+            return Err(e);
+        }
+    };
+    unsafe { ResumeThread(thread.as_handle()) };
+    info!("Started {:?}, pid {}", cmdline, info.dwProcessId);
+
+    Ok((Child { pcon, process, job }, input, output))
+}
+
+/// Pipe input to the command's console until the pipe goes away or `stop`
+/// is set.
+fn pipe_to_child(pipe: &dyn Transport, mut input: File, stop: &Event) {
+    loop {
+        let mut buffer: Vec<u8> = Vec::new();
+        match pipe.read_wait(&mut buffer, Some(stop)) {
+            Ok(0) => {
+                if stop.is_set() {
+                    break;
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(e)
+                if e.code() == ERROR_PIPE_NOT_CONNECTED.into()
+                    || e.code() == ERROR_BROKEN_PIPE.into() =>
+            {
+                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
+                break;
+            }
+            Err(e) => {
+                error!("Failed to read from pipe: {:?}", e);
+                break;
+            }
+        }
+        if let Err(e) = input.write_all(&buffer) {
+            warn!("Failed to write to the command: {:?}", e);
+            break;
+        }
+    }
+}
+
+/// The command's console output to the pipe, until the pseudo console is
+/// closed.
+fn child_to_pipe(mut output: File, pipe: &dyn Transport) {
+    let mut buffer: Vec<u8> = vec![0u8; 4096];
+    loop {
+        let n = match output.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if let Err(e) = pipe.write_all(&buffer[..n]) {
+            warn!("Failed to write to pipe: {:?}", e);
+            break;
+        }
+    }
+}
+
+/// Runs `cmdline` in a pseudo console bridged to `pipe` until it exits or
+/// the pipe disconnects, which ends the command. Returns its exit code.
+pub fn run(cmdline: &str, pipe: Arc<dyn Transport>) -> windows::core::Result<u32> {
+    let (child, input, output) = spawn(cmdline)?;
+    let stop = Arc::new(Event::new()?);
+    let gone = Arc::new(Event::new()?);
+
+    let th_input = {
+        let pipe = Arc::clone(&pipe);
+        let stop = Arc::clone(&stop);
+        let gone = Arc::clone(&gone);
+        thread::spawn(move || {
+            pipe_to_child(&*pipe, input, &stop);
+            gone.set();
+        })
+    };
+    let th_output = {
+        let pipe = Arc::clone(&pipe);
+        thread::spawn(move || child_to_pipe(output, &*pipe))
+    };
+
+    let ended = event::wait_any(&[child.process.as_handle(), gone.as_handle()])? == 0;
+    if !ended {
+        info!("Ending {:?}", cmdline);
+        unsafe { TerminateJobObject(child.job.as_handle(), 1)? };
+    }
+    let mut code: u32 = 0;
+    unsafe { GetExitCodeProcess(child.process.as_handle(), &mut code)? };
+    info!("{:?} exited with {}", cmdline, code);
+
+    stop.set();
+    let _ = th_input.join();
+    // Closing the pseudo console flushes its last output and ends the
+    // output thread.
+    drop(child);
+    let _ = th_output.join();
+    Ok(code)
+}
@@ -0,0 +1,202 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::ValueEnum;
+use log::info;
+use log::warn;
+
+use crate::clipboard;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Longest escape sequence held back while waiting for its end; anything
+/// longer is passed through as is.
+const MAX_PENDING: usize = 4096;
+
+/// Kinds of guest output that can affect the host terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ControlClass {
+    /// bell characters
+    Bell,
+    /// window title changes (OSC 0, 1 and 2)
+    Title,
+    /// every other OSC sequence (clipboard, colors, hyperlinks, ...)
+    Osc,
+    /// DEC private mode changes (alternate screen, mouse tracking, cursor)
+    Modes,
+    /// full and soft terminal resets
+    Reset,
+}
+
+/// What to do with OSC 52 (set clipboard) requests from the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Osc52 {
+    /// hand them to the terminal
+    #[default]
+    Pass,
+    /// set the Windows clipboard and drop the sequence
+    Clipboard,
+    /// drop them
+    Drop,
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+fn base64_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in data.iter().copied().filter(|c| *c != b'=') {
+        acc = (acc << 6) | base64_value(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Sets the clipboard from an `ESC ] 52 ; selection ; base64 terminator`
+/// sequence. A `?` payload asks to read the clipboard, which is ignored.
+fn set_clipboard(seq: &[u8]) {
+    let body = seq
+        .strip_suffix(b"\x07")
+        .or_else(|| seq.strip_suffix(b"\x1b\\"))
+        .unwrap_or(seq);
+    let Some(payload) = body.splitn(3, |b| *b == b';').nth(2) else {
+        return;
+    };
+    if payload == b"?" {
+        return;
+    }
+    match base64_decode(payload) {
+        Some(text) => {
+            let text = String::from_utf8_lossy(&text);
+            match clipboard::set_text(&text) {
+                Ok(_) => info!("Guest set the clipboard ({} bytes)", text.len()),
+                Err(e) => warn!("Failed to set clipboard: {:?}", e),
+            }
+        }
+        None => warn!("Ignored OSC 52 with invalid base64"),
+    }
+}
+
+/// Drops the configured control sequence classes from a stream of output.
+/// Sequences split across reads are held back until they are complete.
+pub struct ControlFilter {
+    classes: Vec<ControlClass>,
+    osc52: Osc52,
+    pending: Vec<u8>,
+}
+
+enum Token {
+    Byte,
+    Sequence(Option<ControlClass>),
+    Clipboard,
+    Incomplete,
+}
+
+/// Classifies the sequence starting at `buf[0] == ESC` and returns its
+/// length along with the class it belongs to, if any.
+fn scan_escape(buf: &[u8]) -> (usize, Token) {
+    match buf.get(1) {
+        None => (buf.len(), Token::Incomplete),
+        Some(b'[') => {
+            let Some(end) = buf[2..].iter().position(|b| (0x40..=0x7e).contains(b)) else {
+                return (buf.len(), Token::Incomplete);
+            };
+            let seq = &buf[..end + 3];
+            let fin = seq[seq.len() - 1];
+            let class = if seq.get(2) == Some(&b'?') && (fin == b'h' || fin == b'l') {
+                Some(ControlClass::Modes)
+            } else if fin == b'p' && seq.contains(&b'!') {
+                Some(ControlClass::Reset)
+            } else {
+                None
+            };
+            (seq.len(), Token::Sequence(class))
+        }
+        Some(b']') => {
+            let mut i = 2;
+            let len = loop {
+                match buf.get(i) {
+                    None => return (buf.len(), Token::Incomplete),
+                    Some(&BEL) => break i + 1,
+                    Some(&ESC) if buf.get(i + 1) == Some(&b'\\') => break i + 2,
+                    Some(&ESC) if i + 1 == buf.len() => return (buf.len(), Token::Incomplete),
+                    _ => i += 1,
+                }
+            };
+            let param = buf[2..len].split(|b| *b == b';').next().unwrap_or_default();
+            match param {
+                b"0" | b"1" | b"2" => (len, Token::Sequence(Some(ControlClass::Title))),
+                b"52" => (len, Token::Clipboard),
+                _ => (len, Token::Sequence(Some(ControlClass::Osc))),
+            }
+        }
+        Some(b'c') => (2, Token::Sequence(Some(ControlClass::Reset))),
+        Some(_) => (2, Token::Sequence(None)),
+    }
+}
+
+impl ControlFilter {
+    pub fn new(classes: &[ControlClass], osc52: Osc52) -> Self {
+        Self {
+            classes: classes.to_vec(),
+            osc52,
+            pending: Vec::new(),
+        }
+    }
+
+    fn drops(&self, class: Option<ControlClass>) -> bool {
+        class.is_some_and(|c| self.classes.contains(&c))
+    }
+
+    /// Returns `buf` without the filtered sequences. A trailing incomplete
+    /// sequence is kept for the next call.
+    pub fn apply(&mut self, buf: &[u8]) -> Vec<u8> {
+        if self.classes.is_empty() && self.osc52 == Osc52::Pass {
+            return buf.to_vec();
+        }
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(buf);
+
+        let mut out = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            let (len, token) = match input[i] {
+                ESC => scan_escape(&input[i..]),
+                BEL => (1, Token::Sequence(Some(ControlClass::Bell))),
+                _ => (1, Token::Byte),
+            };
+            match token {
+                Token::Incomplete if len < MAX_PENDING => {
+                    self.pending = input[i..].to_vec();
+                    break;
+                }
+                Token::Sequence(class) if self.drops(class) => {}
+                Token::Clipboard => match self.osc52 {
+                    Osc52::Pass if !self.drops(Some(ControlClass::Osc)) => {
+                        out.extend_from_slice(&input[i..i + len])
+                    }
+                    Osc52::Clipboard => set_clipboard(&input[i..i + len]),
+                    _ => {}
+                },
+                _ => out.extend_from_slice(&input[i..i + len]),
+            }
+            i += len;
+        }
+        out
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+
+/// Output without a newline is cut into lines of at most this many bytes.
+const MAX_LINE: usize = 4096;
+
+/// Recent session output kept as lines, oldest first.
+pub struct History {
+    lines: VecDeque<Vec<u8>>,
+    partial: Vec<u8>,
+    max_lines: usize,
+}
+
+impl History {
+    pub fn new(max_lines: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            partial: Vec::new(),
+            max_lines,
+        }
+    }
+
+    pub fn push(&mut self, buf: &[u8]) {
+        if self.max_lines == 0 {
+            return;
+        }
+        for mut piece in buf.split_inclusive(|b| *b == b'\n') {
+            while self.partial.len() + piece.len() > MAX_LINE {
+                let (head, rest) = piece.split_at(MAX_LINE - self.partial.len());
+                self.partial.extend_from_slice(head);
+                self.end_line();
+                piece = rest;
+            }
+            self.partial.extend_from_slice(piece);
+            if piece.ends_with(b"\n") {
+                self.end_line();
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line = std::mem::take(&mut self.partial);
+        self.lines.push_back(line);
+        if self.lines.len() > self.max_lines {
+            self.lines.pop_front();
+        }
+    }
+
+    /// All kept lines, including the unterminated one being received.
+    pub fn lines(&self) -> Vec<Vec<u8>> {
+        let mut lines: Vec<Vec<u8>> = self.lines.iter().cloned().collect();
+        if !self.partial.is_empty() {
+            lines.push(self.partial.clone());
+        }
+        lines
+    }
+
+    /// The last `n` lines, including the unterminated one being received.
+    pub fn tail(&self, n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let partial = usize::from(!self.partial.is_empty());
+        let skip = (self.lines.len() + partial).saturating_sub(n);
+        for line in self.lines.iter().skip(skip) {
+            out.extend_from_slice(line);
+        }
+        if partial == 1 && n > 0 {
+            out.extend_from_slice(&self.partial);
+        }
+        out
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::process::Child;
+use std::process::Command;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::error;
+use log::info;
+
+/// Runs `cmd` through `cmd /C` with the session described in `WINPIPE_*`
+/// environment variables.
+fn spawn(cmd: &str, event: &str, path: &str, reason: &str) -> Option<Child> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    info!("Running {} hook: {}", event, cmd);
+    match Command::new("cmd")
+        .arg("/C")
+        .arg(cmd)
+        .env("WINPIPE_EVENT", event)
+        .env("WINPIPE_PATH", path)
+        .env("WINPIPE_TIMESTAMP", timestamp.to_string())
+        .env("WINPIPE_REASON", reason)
+        .spawn()
+    {
+        Ok(child) => Some(child),
+        Err(e) => {
+            error!("Failed to run {} hook {:?}: {:?}", event, cmd, e);
+            None
+        }
+    }
+}
+
+/// Starts the connect hook without waiting for it.
+pub fn on_connect(cmd: &str, path: &str) {
+    let _ = spawn(cmd, "connect", path, "connected");
+}
+
+/// Runs the disconnect hook to completion, since the process is usually
+/// about to exit.
+pub fn on_disconnect(cmd: &str, path: &str, reason: &str) {
+    if let Some(mut child) = spawn(cmd, "disconnect", path, reason) {
+        if let Err(e) = child.wait() {
+            error!("Failed to wait for disconnect hook: {:?}", e);
+        }
+    }
+}
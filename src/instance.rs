@@ -0,0 +1,103 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use log::warn;
+
+use windows::core::HSTRING;
+use windows::Win32::Foundation::CloseHandle;
+use windows::Win32::Foundation::GetLastError;
+use windows::Win32::Foundation::ERROR_ALREADY_EXISTS;
+use windows::Win32::Foundation::ERROR_TIMEOUT;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::TRUE;
+use windows::Win32::Foundation::WAIT_ABANDONED;
+use windows::Win32::Foundation::WAIT_OBJECT_0;
+use windows::Win32::System::Threading::CreateEventW;
+use windows::Win32::System::Threading::CreateMutexW;
+use windows::Win32::System::Threading::SetEvent;
+use windows::Win32::System::Threading::WaitForSingleObject;
+use windows::Win32::System::Threading::INFINITE;
+
+/// How long a takeover waits for the other instance to let go.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kernel object names can't contain backslashes past the namespace, and
+/// pipe paths are case insensitive.
+fn object_name(kind: &str, path: &str) -> HSTRING {
+    let path = path.to_lowercase().replace('\\', "/");
+    HSTRING::from(format!("Local\\winpipe-{}-{}", kind, path))
+}
+
+/// The per-pipe mutex, held for as long as the process runs.
+pub struct Lock {
+    mutex: HANDLE,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.mutex);
+        }
+    }
+}
+
+/// Takes the lock on `path`. Returns `None` while another winpipe holds it.
+pub fn acquire(path: &str) -> windows::core::Result<Option<Lock>> {
+    let mutex = unsafe { CreateMutexW(None, TRUE, &object_name("lock", path))? };
+    if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+        unsafe {
+            let _ = CloseHandle(mutex);
+        }
+        return Ok(None);
+    }
+    Ok(Some(Lock { mutex }))
+}
+
+/// Asks the winpipe holding the lock on `path` to end its session, then
+/// takes the lock over.
+pub fn take_over(path: &str) -> windows::core::Result<Lock> {
+    let event = unsafe { CreateEventW(None, FALSE, FALSE, &object_name("takeover", path))? };
+    let ret = unsafe { SetEvent(event) };
+    unsafe {
+        let _ = CloseHandle(event);
+    }
+    ret?;
+    let mutex = unsafe { CreateMutexW(None, FALSE, &object_name("lock", path))? };
+    let ms = TAKEOVER_TIMEOUT.as_millis() as u32;
+    match unsafe { WaitForSingleObject(mutex, ms) } {
+        // The other instance exits without releasing, which abandons it.
+        WAIT_OBJECT_0 | WAIT_ABANDONED => Ok(Lock { mutex }),
+        _ => {
+            unsafe {
+                let _ = CloseHandle(mutex);
+            }
+            Err(ERROR_TIMEOUT.to_hresult().into())
+        }
+    }
+}
+
+/// Runs `on_takeover` once another winpipe asks for the lock on `path`.
+pub fn watch_takeover<F>(path: &str, on_takeover: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let name = object_name("takeover", path);
+    thread::spawn(move || {
+        let event = match unsafe { CreateEventW(None, FALSE, FALSE, &name) } {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Failed to create the takeover event: {:?}", e);
+                return;
+            }
+        };
+        if unsafe { WaitForSingleObject(event, INFINITE) } == WAIT_OBJECT_0 {
+            info!("Another winpipe took over the pipe");
+            on_takeover();
+        }
+    });
+}
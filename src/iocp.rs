@@ -0,0 +1,178 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::ptr::null_mut;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use log::error;
+use log::warn;
+
+use windows::Win32::Foundation::ERROR_IO_PENDING;
+use windows::Win32::Foundation::ERROR_MORE_DATA;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::INVALID_HANDLE_VALUE;
+use windows::Win32::Storage::FileSystem::ReadFile;
+use windows::Win32::System::Threading::INFINITE;
+use windows::Win32::System::IO::CreateIoCompletionPort;
+use windows::Win32::System::IO::GetQueuedCompletionStatus;
+use windows::Win32::System::IO::OVERLAPPED;
+
+use crate::named_pipe::HandleDesc;
+use crate::named_pipe::NamedPipe;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+type DataCallback = Box<dyn FnMut(&[u8]) -> bool + Send>;
+type CloseCallback = Box<dyn FnOnce(Option<windows::core::Error>) + Send>;
+
+/// A pipe with one outstanding overlapped read. Boxed so the OVERLAPPED
+/// and the buffer keep their address while the read is in flight.
+struct Registration {
+    pipe: NamedPipe,
+    ov: OVERLAPPED,
+    buffer: Vec<u8>,
+    on_data: DataCallback,
+    on_close: CloseCallback,
+}
+
+unsafe impl Send for Registration {}
+
+/// Services pipe reads for many connections on a small pool of worker
+/// threads through an I/O completion port, instead of one reader thread
+/// per pipe.
+pub struct Reactor {
+    port: HandleDesc,
+    registrations: Mutex<HashMap<usize, Box<Registration>>>,
+    next_key: AtomicUsize,
+}
+
+impl Reactor {
+    pub fn new(workers: usize) -> windows::core::Result<Arc<Self>> {
+        let port =
+            unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, HANDLE::default(), 0, 0)? };
+        let reactor = Arc::new(Self {
+            port: HandleDesc::from_handle(port),
+            registrations: Mutex::new(HashMap::new()),
+            next_key: AtomicUsize::new(1),
+        });
+
+        for _ in 0..workers.max(1) {
+            let reactor = Arc::clone(&reactor);
+            thread::spawn(move || reactor.worker());
+        }
+        Ok(reactor)
+    }
+
+    /// Reads `pipe` on the worker pool. `on_data` gets every chunk read and
+    /// returns false to stop; `on_close` runs once reading stops, with the
+    /// error that ended it, if any.
+    pub fn register<D, C>(
+        &self,
+        pipe: NamedPipe,
+        on_data: D,
+        on_close: C,
+    ) -> windows::core::Result<()>
+    where
+        D: FnMut(&[u8]) -> bool + Send + 'static,
+        C: FnOnce(Option<windows::core::Error>) + Send + 'static,
+    {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        unsafe { CreateIoCompletionPort(pipe.as_handle(), self.port.as_handle(), key, 0)? };
+
+        let reg = Box::new(Registration {
+            pipe,
+            ov: OVERLAPPED::default(),
+            buffer: vec![0u8; READ_BUFFER_SIZE],
+            on_data: Box::new(on_data),
+            on_close: Box::new(on_close),
+        });
+        self.start_read(key, reg);
+        Ok(())
+    }
+
+    /// Parks `reg` in the table and issues its next read. The completion may
+    /// be picked up by any worker as soon as ReadFile is called.
+    fn start_read(&self, key: usize, mut reg: Box<Registration>) {
+        reg.ov = OVERLAPPED::default();
+        let handle = reg.pipe.as_handle();
+        let ov: *mut OVERLAPPED = &mut reg.ov;
+        let buffer: *mut [u8] = reg.buffer.as_mut_slice();
+        self.registrations.lock().unwrap().insert(key, reg);
+
+        let ret = unsafe { ReadFile(handle, Some(&mut *buffer), None, Some(ov)) };
+        match ret {
+            Ok(_) => {}
+            Err(e) if e.code() == ERROR_IO_PENDING.into() => {}
+            Err(e) => {
+                // No completion is queued for a failed call.
+                if let Some(reg) = self.registrations.lock().unwrap().remove(&key) {
+                    (reg.on_close)(Some(e));
+                }
+            }
+        }
+    }
+
+    fn worker(&self) {
+        loop {
+            let mut bytes: u32 = 0;
+            let mut key: usize = 0;
+            let mut ov: *mut OVERLAPPED = null_mut();
+            let ret = unsafe {
+                GetQueuedCompletionStatus(
+                    self.port.as_handle(),
+                    &mut bytes,
+                    &mut key,
+                    &mut ov,
+                    INFINITE,
+                )
+            };
+            if ov.is_null() {
+                if let Err(e) = ret {
+                    error!("Completion port failed: {:?}", e);
+                    return;
+                }
+                continue;
+            }
+
+            // Writes on the same handles complete here too; only our own
+            // reads are dispatched.
+            let mut reg = {
+                let mut registrations = self.registrations.lock().unwrap();
+                let ours = registrations
+                    .get(&key)
+                    .is_some_and(|reg| std::ptr::eq(&reg.ov, ov));
+                if !ours {
+                    continue;
+                }
+                registrations.remove(&key).unwrap()
+            };
+
+            match ret {
+                Ok(_) if bytes == 0 => {
+                    warn!("Pipe read returned no data, closing");
+                    (reg.on_close)(None);
+                }
+                Ok(_) => {
+                    if (reg.on_data)(&reg.buffer[..bytes as usize]) {
+                        self.start_read(key, reg);
+                    } else {
+                        (reg.on_close)(None);
+                    }
+                }
+                Err(e) if e.code() == ERROR_MORE_DATA.into() => {
+                    if (reg.on_data)(&reg.buffer[..bytes as usize]) {
+                        self.start_read(key, reg);
+                    } else {
+                        (reg.on_close)(None);
+                    }
+                }
+                Err(e) => (reg.on_close)(Some(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use log::warn;
+
+use crate::transport::Transport;
+
+/// When input was last written to the pipe, shared with the keepalive.
+pub type LastWrite = Mutex<Instant>;
+
+/// Longest sleep between checks, so `stop` is noticed soon enough.
+const POLL: Duration = Duration::from_secs(1);
+
+/// Writes `bytes` to `pipe` whenever nothing else has been written for
+/// `interval`, until `stop` is set or a write fails.
+pub fn spawn(
+    pipe: Arc<dyn Transport>,
+    bytes: Vec<u8>,
+    interval: Duration,
+    last_write: Arc<LastWrite>,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        info!("Keepalive after {:?} without input", interval);
+        while !stop.load(Ordering::SeqCst) {
+            let idle = last_write.lock().unwrap().elapsed();
+            if idle < interval {
+                thread::sleep((interval - idle).min(POLL));
+                continue;
+            }
+            if let Err(e) = pipe.write_all(&bytes) {
+                warn!("Keepalive stopped: {:?}", e);
+                break;
+            }
+            *last_write.lock().unwrap() = Instant::now();
+        }
+    })
+}
@@ -0,0 +1,100 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::ValueEnum;
+
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7f;
+const DELETE_KEY: &[u8] = b"\x1b[3~";
+
+/// What the Backspace key sends to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backspace {
+    /// ^H (0x08)
+    Bs,
+    /// ^? (0x7f)
+    Del,
+}
+
+/// What the Delete key sends to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Delete {
+    /// the VT sequence ESC [ 3 ~
+    Vt,
+    /// ^? (0x7f)
+    Del,
+}
+
+/// What the Enter key sends to the guest. Only typed input is affected;
+/// pastes and pipe output keep their line ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Enter {
+    /// carriage return only
+    Cr,
+    /// line feed only
+    Lf,
+    /// carriage return and line feed
+    Crlf,
+}
+
+impl Enter {
+    /// The setting after this one, for toggling from the escape menu.
+    pub fn next(self) -> Self {
+        match self {
+            Enter::Cr => Enter::Lf,
+            Enter::Lf => Enter::Crlf,
+            Enter::Crlf => Enter::Cr,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Enter::Cr => "CR",
+            Enter::Lf => "LF",
+            Enter::Crlf => "CR LF",
+        }
+    }
+
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            Enter::Cr => b"\r",
+            Enter::Lf => b"\n",
+            Enter::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// Rewrites Backspace (either 0x08 or 0x7f, depending on the console) and
+/// Delete and Enter (CR, or CR LF from some consoles) in typed input;
+/// `None` leaves a key as the console sent it.
+pub fn map(
+    buf: &[u8],
+    backspace: Option<Backspace>,
+    delete: Option<Delete>,
+    enter: Option<Enter>,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut i = 0;
+    while i < buf.len() {
+        if let Some(delete) = delete.filter(|_| buf[i..].starts_with(DELETE_KEY)) {
+            match delete {
+                Delete::Vt => out.extend_from_slice(DELETE_KEY),
+                Delete::Del => out.push(DEL),
+            }
+            i += DELETE_KEY.len();
+            continue;
+        }
+        if let Some(enter) = enter.filter(|_| buf[i] == b'\r') {
+            out.extend_from_slice(enter.bytes());
+            i += if buf[i..].starts_with(b"\r\n") { 2 } else { 1 };
+            continue;
+        }
+        match (buf[i], backspace) {
+            (BS | DEL, Some(Backspace::Bs)) => out.push(BS),
+            (BS | DEL, Some(Backspace::Del)) => out.push(DEL),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
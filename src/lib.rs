@@ -0,0 +1,58 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bridges a Windows named pipe to the console, or to a socket.
+//!
+//! The `winpipe` binary is a thin command line over these modules; tools
+//! that need a pipe bridge can use [`named_pipe::NamedPipe`],
+//! [`console::Console`] and [`bridge::Bridge`] directly, over any
+//! [`transport::Transport`].
+
+pub mod ansi;
+pub mod answerback;
+pub mod audit;
+pub mod background;
+pub mod banner;
+pub mod bridge;
+pub mod capture;
+pub mod clipboard;
+pub mod console;
+pub mod crlf;
+pub mod ctrl;
+pub mod errors;
+pub mod event;
+pub mod exec;
+pub mod filter;
+pub mod history;
+pub mod hooks;
+pub mod instance;
+pub mod iocp;
+pub mod keepalive;
+pub mod keys;
+pub mod logger;
+pub mod menu;
+pub mod named_pipe;
+pub mod notify;
+pub mod owner;
+pub mod pager;
+pub mod paste;
+pub mod ping;
+pub mod pipeline;
+pub mod plugin;
+pub mod progress;
+pub mod receive;
+pub mod record;
+pub mod relay;
+pub mod screen;
+pub mod script;
+pub mod scripting;
+pub mod send;
+pub mod stats;
+pub mod summary;
+pub mod syslog;
+pub mod tcp;
+pub mod throttle;
+pub mod transport;
+pub mod udp;
+pub mod watchdog;
+pub mod win32_input;
@@ -2,42 +2,126 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 
 use log::error;
 use log::info;
+use log::Record;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::console::Target;
-use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::Appender;
 use log4rs::config::Root;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::filter::Filter;
+use log4rs::filter::Response;
 use log4rs::Config;
 
-pub fn setup_logger(path: &Option<PathBuf>) -> Result<(), std::io::Error> {
-    let level = log::LevelFilter::Trace;
-    let stdout = ConsoleAppender::builder().target(Target::Stdout).build();
-    let console_appender = Appender::builder().build("stdout", Box::new(stdout));
-    let console_appender_root = "stdout".to_string();
+use crate::syslog::SyslogAppender;
+use crate::syslog::SyslogTarget;
+use crate::throttle::Throttle;
+
+/// Whether the stdout appender may write; off while a session owns the
+/// console, so log lines don't land in the middle of the guest's output.
+static CONSOLE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns logging to stdout off and back on around an interactive session.
+pub fn set_console(enabled: bool) {
+    CONSOLE_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+#[derive(Debug)]
+struct ConsoleGate;
+
+impl Filter for ConsoleGate {
+    fn filter(&self, _record: &Record) -> Response {
+        if CONSOLE_ENABLED.load(Ordering::SeqCst) {
+            Response::Neutral
+        } else {
+            Response::Reject
+        }
+    }
+}
+
+/// A log file that is rotated by size: once it grows past `max_size` it
+/// becomes `PATH.1`, the older ones shift up, and only `keep` are kept.
+#[derive(Clone, Debug)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub max_size: u64,
+    pub keep: u32,
+}
+
+/// Sets up logging at `level` to stdout (if `console`), to `file` and to a
+/// `syslog` collector. `pattern` is a log4rs pattern replacing the default
+/// encoder of the first two. `rate_limit` caps similar messages per second
+/// and `dedupe` folds repeated identical messages.
+pub fn setup_logger(
+    level: log::LevelFilter,
+    file: Option<&LogFile>,
+    console: bool,
+    pattern: Option<&str>,
+    syslog: Option<&SyslogTarget>,
+    rate_limit: Option<u32>,
+    dedupe: bool,
+) -> Result<(), std::io::Error> {
+    let encoder = || -> Box<PatternEncoder> {
+        Box::new(match pattern {
+            Some(pattern) => PatternEncoder::new(pattern),
+            None => PatternEncoder::default(),
+        })
+    };
 
     let mut appenders: Vec<Appender> = Vec::new();
     let mut root_appenders: Vec<String> = Vec::new();
 
-    appenders.push(console_appender);
-    root_appenders.push(console_appender_root);
+    if console {
+        let stdout = ConsoleAppender::builder()
+            .target(Target::Stdout)
+            .encoder(encoder())
+            .build();
+        appenders.push(
+            Appender::builder()
+                .filter(Box::new(ConsoleGate))
+                .build("stdout", Box::new(stdout)),
+        );
+        root_appenders.push("stdout".to_string());
+    }
 
-    if path.is_some() {
-        let file = FileAppender::builder().build(path.clone().unwrap().into_os_string())?;
-        let file_app = Appender::builder().build("logfile", Box::new(file));
-        appenders.push(file_app);
+    if let Some(file) = file {
+        let pattern = format!("{}.{{}}", file.path.display());
+        let roller = FixedWindowRoller::builder()
+            .build(&pattern, file.keep)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        let policy =
+            CompoundPolicy::new(Box::new(SizeTrigger::new(file.max_size)), Box::new(roller));
+        let file = RollingFileAppender::builder()
+            .encoder(encoder())
+            .build(&file.path, Box::new(policy))?;
+        appenders.push(Appender::builder().build("logfile", Box::new(file)));
         root_appenders.push("logfile".to_string());
     }
 
+    if let Some(target) = syslog {
+        let syslog = SyslogAppender::new(target);
+        appenders.push(Appender::builder().build("syslog", Box::new(syslog)));
+        root_appenders.push("syslog".to_string());
+    }
+
     let config = Config::builder()
         .appenders(appenders)
         .build(Root::builder().appenders(root_appenders).build(level))
         .unwrap();
 
-    let _handle = match log4rs::init_config(config) {
-        Ok(handle) => handle,
+    let logger = log4rs::Logger::new(config);
+    let max_level = logger.max_log_level();
+    let throttle = Throttle::new(Box::new(logger), rate_limit, dedupe);
+    match log::set_boxed_logger(Box::new(throttle)) {
+        Ok(_) => log::set_max_level(max_level),
         Err(e) => {
             error!("Failed to initialize logger: {:?}", e);
             return Err(std::io::Error::new(
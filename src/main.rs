@@ -1,121 +1,1274 @@
 // Copyright 2024 Qi, Yadong.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::Write;
+use std::ffi::c_void;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::thread;
 use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use clap::error::ErrorKind;
+use clap::ArgMatches;
+use clap::CommandFactory;
+use clap::FromArgMatches;
 use clap::Parser;
+use clap::Subcommand;
 
+use log::debug;
 use log::error;
 use log::info;
 use log::warn;
 
-use logger::setup_logger;
+use windows::Win32::Foundation::ERROR_SEM_TIMEOUT;
+use windows::Win32::Foundation::HANDLE;
 
-use windows::Win32::Foundation::ERROR_OPERATION_ABORTED;
-use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
-use windows::Win32::Foundation::STATUS_INTERRUPTED;
+use winpipe::ansi;
+use winpipe::audit;
+use winpipe::background;
+use winpipe::banner;
+use winpipe::bridge;
+use winpipe::capture;
+use winpipe::console;
+use winpipe::crlf;
+use winpipe::ctrl;
+use winpipe::errors;
+use winpipe::exec;
+use winpipe::filter;
+use winpipe::hooks;
+use winpipe::instance;
+use winpipe::iocp;
+use winpipe::keys;
+use winpipe::logger;
+use winpipe::logger::setup_logger;
+use winpipe::menu;
+use winpipe::named_pipe;
+use winpipe::notify;
+use winpipe::owner;
+use winpipe::paste;
+use winpipe::ping;
+use winpipe::pipeline;
+use winpipe::plugin;
+use winpipe::relay;
+use winpipe::script;
+use winpipe::scripting;
+use winpipe::send;
+use winpipe::stats;
+use winpipe::summary;
+use winpipe::syslog;
+use winpipe::tcp;
+use winpipe::transport;
+use winpipe::transport::Transport;
+use winpipe::udp;
+use winpipe::watchdog;
 
-pub mod console;
-pub mod logger;
-pub mod named_pipe;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// measure round-trip time against an echoing peer
+    Ping {
+        /// path of named pipe
+        #[arg(short, long)]
+        path: String,
+
+        /// number of probes to send
+        #[arg(short, long, default_value_t = 10)]
+        count: u32,
+
+        /// delay between probes
+        #[arg(short, long, value_name = "MS", default_value_t = 1000)]
+        interval: u64,
+
+        /// how long to wait for each reply
+        #[arg(short, long, value_name = "MS", default_value_t = 2000)]
+        timeout: u64,
+    },
+    /// wait until the pipe exists, without connecting to it
+    Wait {
+        /// path of named pipe
+        #[arg(short, long)]
+        path: String,
+
+        /// give up after this many seconds (waits forever by default)
+        #[arg(short, long, value_name = "SECS")]
+        timeout: Option<u64>,
+    },
+    /// restore a console once the given winpipe process exits (started internally)
+    #[command(hide = true)]
+    Watchdog {
+        #[arg(long)]
+        pid: u32,
+        #[arg(long)]
+        in_cp: u32,
+        #[arg(long)]
+        out_cp: u32,
+        #[arg(long)]
+        in_mode: u32,
+        #[arg(long)]
+        out_mode: u32,
+    },
+}
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// path of named pipe
-    #[arg(short, long)]
-    path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// path of named pipe, or pipe://PATH, tcp://HOST:PORT or hvsock://VMID:SERVICE
+    #[arg(short, long, required_unless_present = "handle")]
+    path: Option<String>,
+
+    /// use this inherited pipe handle (opened with FILE_FLAG_OVERLAPPED) instead of opening --path
+    #[arg(long, value_name = "N", conflicts_with_all = ["path", "tcp_listen"])]
+    handle: Option<usize>,
+
+    /// create --path as a pipe server and wait for a client instead of connecting to it
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "path",
+        conflicts_with_all = ["tcp_listen", "expect_owner", "require_image"]
+    )]
+    listen: bool,
+
+    /// most instances of the --listen pipe that may exist at once
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "listen")]
+    listen_instances: u32,
+
+    /// input buffer size of the --listen pipe
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 4096,
+        requires = "listen"
+    )]
+    in_buffer_size: u32,
+
+    /// output buffer size of the --listen pipe
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 4096,
+        requires = "listen"
+    )]
+    out_buffer_size: u32,
 
     /// whether to wait for the pipe be ready
     #[arg(short, long, default_value_t = false)]
     wait: bool,
 
-    /// path of file to redirect
-    #[arg(short, long, value_name="PATH", value_hint = clap::ValueHint::FilePath)]
+    /// retry opening a missing pipe this many times before giving up
+    #[arg(long, value_name = "N", default_value_t = 0, conflicts_with = "wait")]
+    retries: u32,
+
+    /// capture pipe output into this file
+    #[arg(short, long, visible_alias = "redir-out", value_name="PATH", value_hint = clap::ValueHint::FilePath)]
     redir: Option<PathBuf>,
+
+    /// capture console input sent to the pipe into this file
+    #[arg(long, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    redir_in: Option<PathBuf>,
+
+    /// write a session header (version, path, start time, console size, command line) and an end footer into capture files
+    #[arg(long, default_value_t = false)]
+    capture_metadata: bool,
+
+    /// prefix every line of pipe output in the capture files with the local time
+    #[arg(long, default_value_t = false)]
+    timestamps: bool,
+
+    /// record pipe output with its timing as an asciinema v2 cast
+    #[arg(long, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    record: Option<PathBuf>,
+
+    /// continue capture files in new numbered files (out-001.log, ...) at size:BYTES, duration:SECS or marker:STR
+    #[arg(long, value_name = "WHEN")]
+    record_split: Option<capture::Split>,
+
+    /// write log messages to this file, rotated by size
+    #[arg(long, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
+
+    /// most verbose log messages to write
+    #[arg(long, value_name = "LEVEL", default_value_t = log::LevelFilter::Info)]
+    log_level: log::LevelFilter,
+
+    /// rotate the log file once it grows past this size
+    #[arg(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024, requires = "log_file")]
+    log_max_size: u64,
+
+    /// number of rotated log files (PATH.1, PATH.2, ...) to keep
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "log_file")]
+    log_keep: u32,
+
+    /// log4rs pattern for log lines, e.g. "{d(%Y-%m-%dT%H:%M:%S%.3f)} {T} {M} {l} {m}{n}"
+    #[arg(long, value_name = "PATTERN")]
+    log_pattern: Option<String>,
+
+    /// log at most this many similar messages per second and count the rest
+    #[arg(long, value_name = "N")]
+    log_rate_limit: Option<u32>,
+
+    /// fold runs of identical log messages into "last message repeated N times"
+    #[arg(long, default_value_t = false)]
+    log_dedupe: bool,
+
+    /// also send log messages to this syslog collector (udp://host:port or tcp://host:port)
+    #[arg(long, value_name = "URL")]
+    syslog: Option<syslog::SyslogTarget>,
+
+    /// write a JSON summary of the session (connections, bytes, matched patterns, exit reason) here on exit
+    #[arg(long, value_name = "PATH")]
+    summary_json: Option<PathBuf>,
+
+    /// append connection events (who, what pipe, when, why it ended) to this file
+    #[arg(long, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    audit_log: Option<PathBuf>,
+
+    /// always enable virtual terminal processing/input
+    #[arg(long, default_value_t = false, conflicts_with = "no_vt")]
+    force_vt: bool,
+
+    /// never enable virtual terminal processing/input
+    #[arg(long, default_value_t = false)]
+    no_vt: bool,
+
+    /// drop these kinds of control sequences from pipe output before it reaches the console, comma separated
+    #[arg(long, value_name = "CLASS", value_delimiter = ',')]
+    filter_control: Vec<filter::ControlClass>,
+
+    /// when to show connect and disconnect banners in the session
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = banner::Banner::Auto)]
+    banner: banner::Banner,
+
+    /// answer terminal queries (device attributes, status, cursor position) from the guest locally
+    #[arg(long, default_value_t = false)]
+    answerback: bool,
+
+    /// filter applied to pipe output, repeatable: strip-ansi, hexdump, grep:REGEX, redact:REGEX
+    #[arg(long, value_name = "FILTER")]
+    output_filter: Vec<pipeline::FilterSpec>,
+
+    /// filter applied to input before it is sent, repeatable (same filters as --output-filter)
+    #[arg(long, value_name = "FILTER")]
+    input_filter: Vec<pipeline::FilterSpec>,
+
+    /// load a filter plugin DLL, initialized with ARG; repeatable, runs after the other filters
+    #[arg(long, value_name = "DLL[=ARG]")]
+    plugin: Vec<String>,
+
+    /// rhai script with on_connect/on_output/on_input handlers for triggers and filters
+    #[arg(long, value_name = "FILE")]
+    rhai: Option<PathBuf>,
+
+    /// what to do with OSC 52 clipboard requests from the guest
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = filter::Osc52::Pass)]
+    osc52: filter::Osc52,
+
+    /// reset attributes, cursor and guest-enabled modes (mouse, bracketed paste, alternate screen) on exit
+    #[arg(long, default_value_t = false)]
+    reset_on_exit: bool,
+
+    /// keep the console code pages instead of switching them to UTF-8
+    #[arg(long, default_value_t = false)]
+    no_cp_change: bool,
+
+    /// console window title for the session; {path}, {name} and {state} are replaced
+    #[arg(long, value_name = "TEXT")]
+    title: Option<String>,
+
+    /// don't start the helper that restores the console if winpipe is killed
+    #[arg(long, default_value_t = false)]
+    no_restore_watchdog: bool,
+
+    /// maximum number of bytes per pipe write (input, file sends, pastes)
+    #[arg(long, value_name = "BYTES", default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    write_chunk_size: u32,
+
+    /// ask for confirmation before sending more than this many bytes at once
+    #[arg(long, value_name = "BYTES")]
+    paste_guard: Option<usize>,
+
+    /// what the Backspace key sends (as the console produces it by default)
+    #[arg(long, value_enum, value_name = "KEY")]
+    backspace: Option<keys::Backspace>,
+
+    /// what the Delete key sends (as the console produces it by default)
+    #[arg(long, value_enum, value_name = "KEY")]
+    delete: Option<keys::Delete>,
+
+    /// what the Enter key sends (as the console produces it by default); toggled with "e" in the escape menu
+    #[arg(long, value_enum, value_name = "KEY")]
+    enter: Option<keys::Enter>,
+
+    /// transformations applied to pasted text, comma separated
+    #[arg(long, value_enum, value_delimiter = ',')]
+    paste_transform: Vec<paste::PasteTransform>,
+
+    /// tab stop width used by the expand-tabs paste transformation
+    #[arg(long, value_name = "COLUMNS", default_value_t = 8)]
+    paste_tab_width: usize,
+
+    /// bridge the pipe to this UDP peer instead of the console
+    #[arg(long, value_name = "ADDR")]
+    udp_peer: Option<SocketAddr>,
+
+    /// local address of the UDP endpoint
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:0")]
+    udp_bind: SocketAddr,
+
+    /// maximum size of a UDP datagram
+    #[arg(long, value_name = "BYTES", default_value_t = 1472, value_parser = clap::value_parser!(u16).range(1..))]
+    udp_max_packet: u16,
+
+    /// accept TCP clients on this address and bridge each one to the pipe
+    #[arg(long, value_name = "ADDR", conflicts_with = "udp_peer")]
+    tcp_listen: Option<SocketAddr>,
+
+    /// detach from the console and keep the --tcp-listen/--udp-peer bridge running in the background
+    #[arg(long, default_value_t = false)]
+    background: bool,
+
+    /// set on the process --background starts, which serves the control pipe
+    #[arg(long, default_value_t = false, hide = true)]
+    detached: bool,
+
+    /// share one pipe connection between all TCP clients
+    #[arg(long, default_value_t = false, requires = "tcp_listen")]
+    tcp_shared: bool,
+
+    /// read client pipes on this many I/O completion port workers instead of a thread per client
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "tcp_listen",
+        conflicts_with = "tcp_shared"
+    )]
+    iocp_workers: Option<usize>,
+
+    /// relay raw stdin/stdout without console APIs or stdout logging (for socat/WSL)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["tcp_listen", "udp_peer"])]
+    relay: bool,
+
+    /// frame messages on the relay's stdin/stdout so their boundaries survive
+    #[arg(long, value_enum, value_name = "FRAMING", requires = "relay")]
+    frame: Option<relay::Frame>,
+
+    /// run this command in a pseudo console and bridge it to the pipe instead of the console
+    #[arg(long, value_name = "CMDLINE", conflicts_with_all = ["relay", "tcp_listen", "udp_peer"])]
+    exec: Option<String>,
+
+    /// send this string (supports \r \n \t \0 \\ \xNN escapes), may be repeated
+    #[arg(long, value_name = "STR")]
+    send: Vec<String>,
+
+    /// send these bytes given as hex (e.g. "1b 5b 41"), may be repeated and mixed with --send
+    #[arg(long, value_name = "HEX")]
+    send_hex: Vec<String>,
+
+    /// wait for output matching this regex, with an optional ":SECS" timeout; may be repeated
+    #[arg(long, value_name = "REGEX[:SECS]")]
+    expect: Vec<String>,
+
+    /// run the send/send-hex/expect/expect-text/sleep steps in this file, one per line
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["send", "send_hex", "expect"])]
+    script: Option<PathBuf>,
+
+    /// exit after the --send/--send-hex/--expect or --script steps instead of going interactive
+    #[arg(long, default_value_t = false)]
+    batch: bool,
+
+    /// terminate the whole run after this many seconds, regardless of activity
+    #[arg(long, value_name = "SECS")]
+    session_timeout: Option<u64>,
+
+    /// never call console APIs, use plain file I/O on the std handles
+    #[arg(long, default_value_t = false, conflicts_with_all = ["force_vt", "no_vt"])]
+    headless: bool,
+
+    /// stream this file into the pipe once connected, before interactive input
+    #[arg(long, visible_alias = "send-file", value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    input_file: Option<PathBuf>,
+
+    /// pause this many milliseconds after each line of a sent file (--input-file, menu paste)
+    #[arg(long, visible_alias = "line-delay", value_name = "MS")]
+    input_line_delay: Option<u64>,
+
+    /// pause this many milliseconds after each byte of a sent file, for consoles that drop input
+    #[arg(long, value_name = "MS")]
+    char_delay: Option<u64>,
+
+    /// don't read console input after --input-file has been sent
+    #[arg(long, default_value_t = false, requires = "input_file")]
+    input_only: bool,
+
+    /// show bytes, rate and ETA of --input-file in the console window title
+    #[arg(long, default_value_t = false, requires = "input_file")]
+    progress: bool,
+
+    /// bytes sent right after the pipe connects (supports \r \n \xNN escapes)
+    #[arg(long, value_name = "BYTES")]
+    init_send: Option<script::EscapedBytes>,
+
+    /// bytes sent to the guest when no input was sent for --keepalive-interval (supports \r \n \xNN escapes)
+    #[arg(long, value_name = "BYTES")]
+    keepalive_send: Option<script::EscapedBytes>,
+
+    /// idle time before --keepalive-send bytes are sent
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "keepalive_send"
+    )]
+    keepalive_interval: u64,
+
+    /// bytes sent to the pipe when the console window is closed (supports \r \n \xNN escapes)
+    #[arg(long, value_name = "BYTES")]
+    goodbye: Option<script::EscapedBytes>,
+
+    /// send the console size on connect; {c} and {r} are replaced by columns and rows
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        num_args = 0..=1,
+        default_missing_value = "stty cols {c} rows {r}\\n"
+    )]
+    announce_size: Option<String>,
+
+    /// host command run when the pipe connects
+    #[arg(long, value_name = "CMD")]
+    on_connect: Option<String>,
+
+    /// host command run when the session ends
+    #[arg(long, value_name = "CMD")]
+    on_disconnect: Option<String>,
+
+    /// show a desktop notification when the pipe disconnects or (with --wait) connects
+    #[arg(long, default_value_t = false)]
+    notify: bool,
+
+    /// beep along with --notify
+    #[arg(long, default_value_t = false, requires = "notify")]
+    notify_bell: bool,
+
+    /// reconnect by itself when the pipe goes away, retrying with the --reconnect-* backoff
+    #[arg(long, default_value_t = false, conflicts_with = "handle")]
+    reconnect: bool,
+
+    /// initial delay between connection attempts, doubled after each failure
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    reconnect_delay: u64,
+
+    /// upper bound of the delay between connection attempts
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    reconnect_max_delay: u64,
+
+    /// give up after this many failed connection attempts (unlimited by default)
+    #[arg(long, value_name = "N")]
+    reconnect_attempts: Option<u32>,
+
+    /// open the pipe for reading only and ignore console input
+    #[arg(long, default_value_t = false, conflicts_with = "write_only")]
+    read_only: bool,
+
+    /// open the pipe for writing only and don't relay its output
+    #[arg(long, default_value_t = false)]
+    write_only: bool,
+
+    /// read the pipe as a byte stream or as messages (auto follows the pipe type)
+    #[arg(long, visible_alias = "pipe-mode", value_enum, value_name = "MODE", default_value_t = named_pipe::ReadMode::Auto)]
+    read_mode: named_pipe::ReadMode,
+
+    /// cancel pipe reads and writes that are stuck for this long
+    #[arg(long, value_name = "MS")]
+    io_timeout: Option<u64>,
+
+    /// allow the pipe server to impersonate this user (only identification is allowed by default)
+    #[arg(long, default_value_t = false, conflicts_with = "impersonation_level")]
+    allow_impersonation: bool,
+
+    /// what the pipe server may do with this user's identity
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t = named_pipe::Impersonation::Identification)]
+    impersonation_level: named_pipe::Impersonation,
+
+    /// give up waiting for a missing or busy pipe after this long (exit code 6)
+    #[arg(long, value_name = "MS")]
+    connect_timeout: Option<u64>,
+
+    /// refuse to relay unless the pipe server runs as this account (SID or DOMAIN\name)
+    #[arg(long, value_name = "ACCOUNT")]
+    expect_owner: Option<String>,
+
+    /// refuse to relay unless the pipe server runs this executable (file name, or full path)
+    #[arg(long, value_name = "NAME")]
+    require_image: Option<String>,
+
+    /// print pipe read/write size and latency histograms to stderr on exit
+    #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text")]
+    stats_at_exit: Option<stats::Format>,
+
+    /// refuse to start while another winpipe with this option is attached to the same pipe
+    #[arg(long, default_value_t = false)]
+    single_instance: bool,
+
+    /// with --single-instance, end the other session and take the pipe over instead of refusing
+    #[arg(long, default_value_t = false, requires = "single_instance")]
+    takeover: bool,
+
+    /// number of output lines kept for the escape menu (Ctrl+]) and its scrollback view
+    #[arg(long, value_name = "LINES", default_value_t = 1000)]
+    scrollback: usize,
+
+    /// key that opens the escape menu, as ^X, 0xNN or a character
+    #[arg(long, value_name = "KEY", default_value = "^]", value_parser = menu::parse_escape)]
+    escape: u8,
+
+    /// pass every key to the pipe, without an escape menu
+    #[arg(long, default_value_t = false)]
+    no_escape: bool,
+
+    /// translate lone CR/LF to CR LF in input, lone LF in pipe output, or both; input conflicts with --enter
+    #[arg(long, value_enum, value_name = "DIR")]
+    crlf: Option<crlf::Crlf>,
+
+    /// show typed input on the console, for guests that don't echo it
+    #[arg(long, default_value_t = false)]
+    local_echo: bool,
+
+    /// send Ctrl+C to the pipe, or end the session and exit (Ctrl+Break always exits)
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ctrl::CtrlC::Passthrough)]
+    ctrlc: ctrl::CtrlC,
 }
 
-fn stdin_to_pipe(
-    pipe: named_pipe::NamedPipe,
-    con: Arc<console::Console>,
-) -> Result<(), std::io::Error> {
-    loop {
-        let mut buf: Vec<u8> = vec![0u8; 1024];
-        let n = match con.read(&mut buf) {
-            Ok(n) => n,
-            Err(e) if e.code() == STATUS_INTERRUPTED.into() => {
-                info!("interrupted!");
-                thread::sleep(Duration::from_millis(100));
-                continue;
-            }
-            Err(e) if e.code() == ERROR_OPERATION_ABORTED.into() => {
-                warn!("Operation aborted!");
-                break Ok(());
-            }
+impl Args {
+    /// The pipe path, or the tcp:// or hvsock:// address; always present
+    /// unless a subcommand is run or the pipe is an inherited --handle.
+    fn path(&self) -> &str {
+        match (&self.path, self.handle) {
+            (Some(path), _) => path.strip_prefix("pipe://").unwrap_or(path),
+            (None, Some(_)) => "<inherited handle>",
+            (None, None) => "",
+        }
+    }
+
+    fn pipe_config(&self) -> named_pipe::PipeConfig {
+        let access = if self.read_only {
+            named_pipe::Access::Read
+        } else if self.write_only {
+            named_pipe::Access::Write
+        } else {
+            named_pipe::Access::ReadWrite
+        };
+        named_pipe::PipeConfig {
+            wait: self.wait,
+            retries: self.retries,
+            backoff: named_pipe::Backoff {
+                initial: Duration::from_millis(self.reconnect_delay),
+                max: Duration::from_millis(self.reconnect_max_delay),
+                attempts: self.reconnect_attempts,
+            },
+            access,
+            write_chunk_size: Some(self.write_chunk_size as usize),
+            impersonation: if self.allow_impersonation {
+                named_pipe::Impersonation::Impersonation
+            } else {
+                self.impersonation_level
+            },
+            connect_timeout: self.connect_timeout.map(Duration::from_millis),
+            io_timeout: self.io_timeout.map(Duration::from_millis),
+            read_mode: self.read_mode,
+        }
+    }
+
+    /// Like `pipe_config`, but waits for the pipe to come back as --reconnect
+    /// does after a disconnect.
+    fn reconnect_config(&self) -> named_pipe::PipeConfig {
+        named_pipe::PipeConfig {
+            wait: true,
+            ..self.pipe_config()
+        }
+    }
+
+    fn server_config(&self) -> named_pipe::ServerConfig {
+        named_pipe::ServerConfig {
+            instances: self.listen_instances,
+            in_buffer_size: self.in_buffer_size,
+            out_buffer_size: self.out_buffer_size,
+            message: self.read_mode == named_pipe::ReadMode::Message,
+        }
+    }
+
+    fn bridge_options(&self) -> bridge::Options {
+        bridge::Options {
+            read_only: self.read_only,
+            write_only: self.write_only,
+            redir: self.redir.clone(),
+            redir_in: self.redir_in.clone(),
+            timestamps: self.timestamps,
+            record: self.record.clone(),
+            input_file: self.input_file.clone(),
+            pacing: send::Pacing {
+                char_delay: self.char_delay.map(Duration::from_millis),
+                line_delay: self.input_line_delay.map(Duration::from_millis),
+            },
+            input_only: self.input_only,
+            progress: self.progress,
+            title: title(self, "connected"),
+            backspace: self.backspace,
+            delete: self.delete,
+            enter: self.enter,
+            paste_transform: self.paste_transform.clone(),
+            paste_tab_width: self.paste_tab_width,
+            paste_guard: self.paste_guard,
+            input_filter: self.input_filter.clone(),
+            output_filter: self.output_filter.clone(),
+            filter_control: self.filter_control.clone(),
+            osc52: self.osc52,
+            escape: (!self.no_escape).then_some(self.escape),
+            line_modes: Arc::new(crlf::LineModes::new(self.crlf, self.local_echo)),
+            ctrl_c_exits: self.ctrlc == ctrl::CtrlC::Exit,
+            answerback: self.answerback,
+            keepalive: self.keepalive_send.as_ref().map(|bytes| {
+                (
+                    bytes.0.clone(),
+                    Duration::from_secs(self.keepalive_interval),
+                )
+            }),
+        }
+    }
+}
+
+/// Process exit code when --session-timeout expires.
+const EXIT_SESSION_TIMEOUT: i32 = 5;
+/// Process exit code when --connect-timeout expires.
+const EXIT_CONNECT_TIMEOUT: i32 = 6;
+/// Process exit code when an interrupt ends winpipe outside a session.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// The exit code for failing to connect with `e`.
+fn connect_exit_code(e: &windows::core::Error) -> i32 {
+    if e.code() == ERROR_SEM_TIMEOUT.into() {
+        EXIT_CONNECT_TIMEOUT
+    } else {
+        1
+    }
+}
+
+/// Exits the process once `secs` have elapsed, restoring the console first
+/// if one has been set up by then.
+fn start_session_timer(secs: u64, con: Arc<OnceLock<Arc<console::Console>>>) {
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(secs));
+        error!("Session timeout after {} seconds", secs);
+        if let Some(con) = con.get() {
+            let _ = con.restore();
+        }
+        watchdog::disarm();
+        notify::remove_icon();
+        summary::finish("session timeout", EXIT_SESSION_TIMEOUT);
+        std::process::exit(EXIT_SESSION_TIMEOUT);
+    });
+}
+
+/// Takes the --single-instance lock on the pipe, or exits if another
+/// winpipe holds it and --takeover wasn't given. Whoever holds the lock
+/// ends its session when a later --takeover asks for it.
+fn single_instance(args: &Args, con: Arc<OnceLock<Arc<console::Console>>>) -> instance::Lock {
+    let lock = match instance::acquire(args.path()) {
+        Ok(Some(lock)) => lock,
+        Ok(None) if args.takeover => match instance::take_over(args.path()) {
+            Ok(lock) => lock,
             Err(e) => {
-                error!("Failed to read from stdin: {:?}", e);
-                break Err(e.into());
+                error!("Failed to take over {}: {:?}", args.path(), e);
+                std::process::exit(1);
             }
-        };
-        buf.truncate(n as usize);
+        },
+        Ok(None) => {
+            error!(
+                "Another winpipe is attached to {}, use --takeover to end its session",
+                args.path()
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Failed to create the instance lock: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    instance::watch_takeover(args.path(), move || {
+        if let Some(con) = con.get() {
+            let _ = con.write(b"\r\n[winpipe] session taken over by another winpipe\r\n");
+            let _ = con.restore();
+        }
+        watchdog::disarm();
+        notify::remove_icon();
+        summary::finish("taken over", 1);
+        std::process::exit(1);
+    });
+    lock
+}
+
+/// Collects --send/--send-hex/--expect values in the order they were given,
+/// or the steps of --script.
+fn script_steps(matches: &ArgMatches) -> Result<Vec<script::Step>, String> {
+    if let Some(path) = matches.get_one::<PathBuf>("script") {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        return script::parse_file(&text).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+    let mut steps: Vec<(usize, script::Step)> = Vec::new();
+    if let (Some(values), Some(indices)) = (
+        matches.get_many::<String>("send"),
+        matches.indices_of("send"),
+    ) {
+        for (value, index) in values.zip(indices) {
+            steps.push((index, script::parse_send(value)?));
+        }
+    }
+    if let (Some(values), Some(indices)) = (
+        matches.get_many::<String>("send_hex"),
+        matches.indices_of("send_hex"),
+    ) {
+        for (value, index) in values.zip(indices) {
+            steps.push((index, script::parse_send_hex(value)?));
+        }
+    }
+    if let (Some(values), Some(indices)) = (
+        matches.get_many::<String>("expect"),
+        matches.indices_of("expect"),
+    ) {
+        for (value, index) in values.zip(indices) {
+            steps.push((index, script::parse_expect(value)?));
+        }
+    }
+    steps.sort_by_key(|(index, _)| *index);
+    Ok(steps.into_iter().map(|(_, step)| step).collect())
+}
 
-        pipe.write(&buf)?;
+/// Appends `text` to every capture file when --capture-metadata is set.
+fn write_capture_metadata(args: &Args, text: &str) {
+    if !args.capture_metadata {
+        return;
     }
+    let ret = capture::open(&[&args.redir, &args.redir_in])
+        .and_then(|mut captures| captures.write(text.as_bytes()));
+    if let Err(e) = ret {
+        error!("Failed to write capture metadata: {:?}", e);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Describes the session at the top of the capture files.
+fn capture_header(args: &Args, con: &console::Console) -> String {
+    let size = match con.size() {
+        Ok((cols, rows)) => format!("{}x{}", cols, rows),
+        Err(_) => "unknown".to_string(),
+    };
+    let cmdline: Vec<String> = std::env::args().collect();
+    format!(
+        "# winpipe {} session\n# path: {}\n# started: {}\n# console: {}\n# command line: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        args.path(),
+        unix_time(),
+        size,
+        cmdline.join(" ")
+    )
+}
+
+/// Undoes terminal state left behind by the guest, with --reset-on-exit.
+fn reset_terminal(args: &Args, con: &console::Console) {
+    if args.reset_on_exit && con.kind() != console::StdioKind::Headless {
+        let _ = con.write(ansi::RESET_TERMINAL);
+    }
+}
+
+/// The --title template, if any, filled in for the session in `state`.
+fn title(args: &Args, state: &str) -> Option<String> {
+    let template = args.title.as_ref()?;
+    let path = args.path();
+    let name = path.rsplit(['\\', '/']).next().unwrap_or(path);
+    Some(
+        template
+            .replace("{path}", path)
+            .replace("{name}", name)
+            .replace("{state}", state),
+    )
 }
 
-fn pipe_to_stdout(
-    pipe: named_pipe::NamedPipe,
-    con: Arc<console::Console>,
-    path: &Option<PathBuf>,
-) -> windows::core::Result<()> {
-    let mut redir_file = match path {
-        Some(path) => {
-            let f = std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)?;
-            Some(f)
+/// Sets the --title template, if any, for the session in `state`.
+fn update_title(args: &Args, con: &console::Console, state: &str) {
+    let Some(title) = title(args, state) else {
+        return;
+    };
+    if let Err(e) = con.set_title(&title) {
+        warn!("Failed to set console title: {:?}", e);
+    }
+}
+
+/// Sends the console size through the --announce-size template.
+fn announce_size(
+    pipe: &dyn Transport,
+    con: &console::Console,
+    template: &str,
+) -> Result<(), String> {
+    let (cols, rows) = con.size().map_err(|e| format!("{:?}", e))?;
+    let text = template
+        .replace("{c}", &cols.to_string())
+        .replace("{r}", &rows.to_string());
+    let bytes = script::unescape(&text)?;
+    info!("Announcing console size {}x{}", cols, rows);
+    pipe.write_all(&bytes).map_err(|e| format!("{:?}", e))
+}
+
+/// How the session is attached to the pipe, for the audit log.
+fn client_name(args: &Args) -> String {
+    if args.relay {
+        "relay".to_string()
+    } else if args.exec.is_some() {
+        "exec".to_string()
+    } else if let Some(peer) = args.udp_peer {
+        format!("udp:{}", peer)
+    } else {
+        "console".to_string()
+    }
+}
+
+/// Connects to the pipe, or with --listen waits for a client of `server`.
+/// Fails with the exit code to give up with.
+fn open_pipe(
+    args: &Args,
+    server: Option<&named_pipe::Server>,
+    config: &named_pipe::PipeConfig,
+) -> Result<named_pipe::NamedPipe, i32> {
+    debug!("Pipe connecting: {:?}", args.path());
+    let pipe = match (server, args.handle) {
+        (Some(server), _) => server.accept(config),
+        (None, Some(handle)) => Ok(named_pipe::NamedPipe::from_raw_handle(
+            HANDLE(handle as *mut c_void),
+            config,
+        )),
+        (None, None) => named_pipe::NamedPipe::try_open(args.path(), config),
+    };
+    match pipe {
+        Ok(pipe) => {
+            debug!("Pipe connected: {:?}", args.path());
+            if let Some(expected) = &args.expect_owner {
+                if let Err(e) = owner::verify(&pipe, expected) {
+                    error!("Pipe owner check failed: {:?}", e);
+                    audit::record("refused", args.path(), &client_name(args), "owner mismatch");
+                    return Err(1);
+                }
+            }
+            if let Some(expected) = &args.require_image {
+                if let Err(e) = owner::verify_image(&pipe, expected) {
+                    error!("Pipe server image check failed: {:?}", e);
+                    audit::record("refused", args.path(), &client_name(args), "image mismatch");
+                    return Err(1);
+                }
+            }
+            connected(args, &pipe).then_some(pipe).ok_or(1)
+        }
+        Err(e) => {
+            errors::report("Failed to open pipe", &e, args.path());
+            Err(connect_exit_code(&e))
         }
-        None => None,
+    }
+}
+
+/// Connects the console session to --path, whichever transport it names.
+fn open_transport(
+    args: &Args,
+    server: Option<&named_pipe::Server>,
+    config: &named_pipe::PipeConfig,
+) -> Result<Arc<dyn Transport>, i32> {
+    let target = match (&args.path, args.handle) {
+        (Some(path), _) => path.parse::<transport::Target>().map_err(|_| 1)?,
+        (None, _) => transport::Target::Pipe(String::new()),
     };
+    if let transport::Target::Pipe(_) = target {
+        return open_pipe(args, server, config).map(|pipe| Arc::new(pipe) as Arc<dyn Transport>);
+    }
+    info!("Connecting: {:?}", args.path());
+    match transport::connect(&target, config) {
+        Ok(stream) => connected(args, &*stream).then_some(stream).ok_or(1),
+        Err(e) => {
+            errors::report("Failed to connect", &e, args.path());
+            Err(connect_exit_code(&e))
+        }
+    }
+}
 
+/// Records a new connection and runs what is due on connect; false if the
+/// init bytes couldn't be sent.
+fn connected(args: &Args, pipe: &dyn Transport) -> bool {
+    audit::record("connect", args.path(), &client_name(args), "connected");
+    summary::connected();
+    scripting::on_connect();
+    if let Some(init) = &args.init_send {
+        if let Err(e) = pipe.write_all(&init.0) {
+            error!("Failed to send init bytes: {:?}", e);
+            return false;
+        }
+    }
+    if let Some(cmd) = &args.on_connect {
+        hooks::on_connect(cmd, args.path());
+    }
+    if args.notify && args.wait {
+        notify::notify("Pipe connected", args.path(), args.notify_bell);
+    }
+    true
+}
+
+fn session_ended<E: std::fmt::Debug>(args: &Args, ret: &Result<(), E>) {
+    let reason = match ret {
+        Ok(_) => "disconnected".to_string(),
+        Err(e) => format!("error: {:?}", e),
+    };
+    session_ended_with(args, &reason);
+}
+
+fn session_ended_with(args: &Args, reason: &str) {
+    audit::record("disconnect", args.path(), &client_name(args), reason);
+    summary::disconnected(reason);
+    write_capture_metadata(args, &format!("\n# ended: {} ({})\n", unix_time(), reason));
+    if args.notify {
+        notify::notify("Pipe disconnected", args.path(), args.notify_bell);
+    }
+    if let Some(cmd) = &args.on_disconnect {
+        hooks::on_disconnect(cmd, args.path(), reason);
+    }
+}
+
+/// Offers to reconnect once the pipe has gone away, so a rebooting VM can
+/// be picked up again without relaunching. Only asked on a real console.
+fn ask_reconnect(args: &Args, con: &console::Console) -> bool {
+    if con.kind() != console::StdioKind::Console || args.handle.is_some() {
+        return false;
+    }
+    let prompt = b"\r\n[winpipe] pipe disconnected: [r]econnect, [q]uit ";
+    if con.write(prompt).is_err() {
+        return false;
+    }
     loop {
-        let mut buffer: Vec<u8> = Vec::new();
-        match pipe.read(&mut buffer) {
-            Ok(n) => {
-                if n == 0 {
-                    thread::sleep(Duration::from_millis(100));
-                    continue;
+        match menu::read_key(con) {
+            Ok(b'r' | b'R') => {
+                let _ = con.write(b"r\r\n");
+                return true;
+            }
+            Ok(b'q' | b'Q' | 0x03 | 0x1b) => {
+                let _ = con.write(b"q\r\n");
+                return false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Failed to read the reconnect answer: {:?}", e);
+                return false;
+            }
+        }
+    }
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let args = Arc::new(Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()));
+    let steps = match script_steps(&matches) {
+        Ok(steps) => steps,
+        Err(e) => Args::command().error(ErrorKind::ValueValidation, e).exit(),
+    };
+    if args.enter.is_some() && matches!(args.crlf, Some(crlf::Crlf::In | crlf::Crlf::Both)) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--enter and --crlf in/both both set the line ends sent; use one of them",
+            )
+            .exit()
+    }
+    if let Some(path) = &args.path {
+        let pipe_only = args.relay
+            || args.tcp_listen.is_some()
+            || args.udp_peer.is_some()
+            || args.listen
+            || args.expect_owner.is_some()
+            || args.require_image.is_some();
+        match path.parse::<transport::Target>() {
+            Ok(transport::Target::Pipe(pipe)) if args.listen && named_pipe::is_remote(&pipe) => {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--listen can only create a pipe on this machine",
+                    )
+                    .exit()
+            }
+            Ok(transport::Target::Pipe(_)) => {}
+            Ok(_) if pipe_only => Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--relay, --tcp-listen, --udp-peer, --listen, --expect-owner and --require-image need a named pipe",
+                )
+                .exit(),
+            Ok(_) => {}
+            Err(e) => Args::command().error(ErrorKind::ValueValidation, e).exit(),
+        }
+    }
+
+    if let Some(Command::Watchdog {
+        pid,
+        in_cp,
+        out_cp,
+        in_mode,
+        out_mode,
+    }) = &args.command
+    {
+        let state = console::SavedState {
+            in_cp: *in_cp,
+            out_cp: *out_cp,
+            in_mode: *in_mode,
+            out_mode: *out_mode,
+        };
+        let ret = watchdog::run(*pid, &state);
+        std::process::exit(if ret.is_ok() { 0 } else { 1 });
+    }
+
+    if args.background {
+        if args.tcp_listen.is_none() && args.udp_peer.is_none() {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--background needs --tcp-listen or --udp-peer",
+                )
+                .exit();
+        }
+        match background::detach() {
+            Ok(pid) => {
+                println!("winpipe running in background, pid {}", pid);
+                println!("control pipe {}", background::control_pipe(pid));
+                if let Some(path) = &args.log_file {
+                    println!("logging to {}", path.display());
                 }
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Failed to start in background: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Log lines would end up in the output when it is redirected.
+    let log_file = args.log_file.as_ref().map(|path| logger::LogFile {
+        path: path.clone(),
+        max_size: args.log_max_size,
+        keep: args.log_keep,
+    });
+    let _ = setup_logger(
+        args.log_level,
+        log_file.as_ref(),
+        !args.relay && std::io::stdout().is_terminal(),
+        args.log_pattern.as_deref(),
+        args.syslog.as_ref(),
+        args.log_rate_limit,
+        args.log_dedupe,
+    );
+
+    if args.detached {
+        background::serve_control(args.path().to_string());
+    }
+
+    for spec in &args.plugin {
+        if let Err(e) = plugin::load(spec) {
+            error!("Failed to load plugin {}: {:?}", spec, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.rhai {
+        if let Err(e) = scripting::load(path) {
+            error!("Failed to load script {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(split) = &args.record_split {
+        capture::set_split(split.clone());
+    }
+
+    if let Some(path) = &args.audit_log {
+        if let Err(e) = audit::open(path) {
+            error!("Failed to open audit log {:?}: {:?}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(Command::Wait { path, timeout }) = &args.command {
+        let found = named_pipe::NamedPipe::wait_exists(path, timeout.map(Duration::from_secs));
+        if !found {
+            error!("Timed out waiting for pipe: {:?}", path);
+        }
+        std::process::exit(if found { 0 } else { 1 });
+    }
+
+    if let Some(Command::Ping {
+        path,
+        count,
+        interval,
+        timeout,
+    }) = &args.command
+    {
+        let pipe = match named_pipe::NamedPipe::try_open(path, &args.pipe_config()) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                errors::report("Failed to open pipe", &e, path);
+                std::process::exit(connect_exit_code(&e));
             }
-            Err(e) if e.code() == ERROR_PIPE_NOT_CONNECTED.into() => {
-                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
-                con.cancel_read()?;
-                break Ok(());
+        };
+        if let Some(expected) = &args.expect_owner {
+            if let Err(e) = owner::verify(&pipe, expected) {
+                error!("Pipe owner check failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(expected) = &args.require_image {
+            if let Err(e) = owner::verify_image(&pipe, expected) {
+                error!("Pipe server image check failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        let interval = Duration::from_millis(*interval);
+        let timeout = Duration::from_millis(*timeout);
+        // The replies are the output; log lines go to --log-file meanwhile.
+        logger::set_console(false);
+        let ret = ping::run(&pipe, *count, interval, timeout);
+        logger::set_console(true);
+        match ret {
+            Ok(stats) => {
+                println!("{}", stats.summary());
+                std::process::exit(if stats.received > 0 { 0 } else { 1 });
             }
             Err(e) => {
-                error!("Failed to read from pipe: {:?}", e);
-                break Err(e);
+                error!("Ping failed: {:?}", e);
+                std::process::exit(1);
             }
         }
+    }
 
-        con.write(buffer.as_slice())?;
-        if let Some(ref mut file) = redir_file {
-            file.write_all(buffer.as_slice())?;
+    if let Some(path) = &args.summary_json {
+        summary::open(path, args.path());
+    }
+
+    let con_slot: Arc<OnceLock<Arc<console::Console>>> = Arc::new(OnceLock::new());
+    if let Some(secs) = args.session_timeout {
+        start_session_timer(secs, Arc::clone(&con_slot));
+    }
+
+    let _lock = if args.single_instance && args.handle.is_none() {
+        Some(single_instance(&args, Arc::clone(&con_slot)))
+    } else {
+        None
+    };
+
+    let server = if args.listen {
+        match named_pipe::Server::create(args.path(), &args.server_config()) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                errors::report("Failed to create pipe", &e, args.path());
+                std::process::exit(1);
+            }
         }
+    } else {
+        None
+    };
+
+    if args.relay {
+        let pipe = match open_pipe(&args, server.as_ref(), &args.pipe_config()) {
+            Ok(pipe) => pipe,
+            Err(code) => std::process::exit(code),
+        };
+        let ret = relay::run(pipe, args.frame);
+        session_ended(&args, &ret);
+        if let Err(e) = ret {
+            error!("Error in relay: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-}
 
-fn main() {
-    let args = Args::parse();
+    if let Some(cmdline) = &args.exec {
+        loop {
+            let pipe = match open_transport(&args, server.as_ref(), &args.pipe_config()) {
+                Ok(pipe) => pipe,
+                Err(code) => std::process::exit(code),
+            };
+            let ret = exec::run(cmdline, pipe);
+            session_ended(&args, &ret.as_ref().map(|_| ()));
+            let code = match ret {
+                Ok(code) => code,
+                Err(e) => {
+                    error!("Failed to run {:?}: {:?}", cmdline, e);
+                    std::process::exit(1);
+                }
+            };
+            match &server {
+                // Each client gets a fresh command.
+                Some(server) => server.disconnect(),
+                None => std::process::exit(code as i32),
+            }
+        }
+    }
+
+    if let Some(addr) = args.tcp_listen {
+        let reactor = match args.iocp_workers.map(iocp::Reactor::new).transpose() {
+            Ok(reactor) => reactor,
+            Err(e) => {
+                error!("Failed to create completion port: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = tcp::listen(
+            addr,
+            args.path(),
+            args.pipe_config(),
+            args.tcp_shared,
+            args.expect_owner.clone(),
+            reactor,
+        ) {
+            errors::report_io("Error in TCP listener", &e, args.path());
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let _ = setup_logger(&args.redir);
+    if let Some(peer) = args.udp_peer {
+        let pipe = match open_pipe(&args, server.as_ref(), &args.pipe_config()) {
+            Ok(pipe) => pipe,
+            Err(code) => std::process::exit(code),
+        };
+        let ret = udp::bridge(pipe, args.udp_bind, peer, args.udp_max_packet as usize);
+        session_ended(&args, &ret);
+        if let Err(e) = ret {
+            error!("Error in UDP bridge: {:?}", e);
+        }
+        return;
+    }
 
-    let con = Arc::new(match console::Console::new() {
+    let con = if args.headless {
+        console::Console::new_headless()
+    } else {
+        console::Console::new()
+    };
+    let con = Arc::new(match con {
         Ok(con) => con,
         Err(e) => {
             error!("Failed to create console: {:?}", e);
@@ -123,7 +1276,51 @@ fn main() {
         }
     });
 
-    match con.setup() {
+    let _ = con_slot.set(Arc::clone(&con));
+
+    // The pipe of the current connection, for the goodbye bytes.
+    let pipe_slot: Arc<Mutex<Option<Arc<dyn Transport>>>> = Arc::new(Mutex::new(None));
+    let close_args = Arc::clone(&args);
+    let close_con = Arc::clone(&con);
+    let close_pipe = Arc::clone(&pipe_slot);
+    let ret = ctrl::on_close(move || {
+        if let (Some(bytes), Some(pipe)) = (&close_args.goodbye, &*close_pipe.lock().unwrap()) {
+            if let Err(e) = pipe.write_all(&bytes.0) {
+                warn!("Failed to send goodbye bytes: {:?}", e);
+            }
+        }
+        reset_terminal(&close_args, &close_con);
+        let _ = close_con.restore();
+        session_ended_with(&close_args, "console closed");
+        capture::flush_all();
+        watchdog::disarm();
+        notify::remove_icon();
+        summary::finish("console closed", 0);
+        log::logger().flush();
+    });
+    if let Err(e) = ret {
+        warn!("Failed to install the console close handler: {:?}", e);
+    }
+
+    if con.is_pty() {
+        info!("MSYS/Cygwin pty detected, relaying raw stdio");
+    }
+
+    let vt = if args.force_vt {
+        console::VtMode::Force
+    } else if args.no_vt {
+        console::VtMode::Off
+    } else {
+        console::VtMode::Auto
+    };
+
+    if !args.no_restore_watchdog {
+        if let Some(state) = con.saved_state() {
+            watchdog::spawn(&state);
+        }
+    }
+
+    match con.setup(vt, args.no_cp_change) {
         Ok(_) => {}
         Err(e) => {
             error!("Failed to setup console: {:?}", e);
@@ -131,46 +1328,198 @@ fn main() {
         }
     }
 
-    info!("Pipe connecting: {:?}", args.path);
-    let pipe_stp = {
-        match named_pipe::NamedPipe::try_open(&args.path, args.wait) {
-            Ok(pipe) => pipe,
-            Err(e) => {
-                error!("Failed to open pipe: {:?}", e);
-                return;
-            }
+    let bridge = match bridge::Bridge::new(Arc::clone(&con), args.bridge_options(), args.scrollback)
+    {
+        Ok(bridge) => Arc::new(bridge),
+        Err(e) => {
+            error!("Failed to set up the bridge: {:?}", e);
+            return;
         }
     };
 
-    info!("Pipe connected: {:?}", args.path);
-    let pipe_pts = pipe_stp.clone();
-
-    let arc_con_r = Arc::clone(&con);
-    let th_stdin_to_pipe = std::thread::spawn(move || match stdin_to_pipe(pipe_stp, arc_con_r) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Error in stdin_to_pipe: {:?}", e);
+    // A session is ended through the bridge so both of its threads stop and
+    // the console is restored on the way out; outside one there is nothing
+    // to wait for.
+    let int_args = Arc::clone(&args);
+    let int_con = Arc::clone(&con);
+    let int_pipe = Arc::clone(&pipe_slot);
+    let int_bridge = Arc::clone(&bridge);
+    let ret = ctrl::on_interrupt(move |interrupt| {
+        let pipe = int_pipe.lock().unwrap().clone();
+        match pipe {
+            Some(pipe)
+                if interrupt == ctrl::Interrupt::CtrlC
+                    && int_args.ctrlc == ctrl::CtrlC::Passthrough =>
+            {
+                if let Err(e) = pipe.write_all(&[0x03]) {
+                    warn!("Failed to send Ctrl+C: {:?}", e);
+                }
+            }
+            Some(_) => int_bridge.interrupt(),
+            None => {
+                reset_terminal(&int_args, &int_con);
+                let _ = int_con.restore();
+                capture::flush_all();
+                watchdog::disarm();
+                notify::remove_icon();
+                summary::finish("interrupted", EXIT_INTERRUPTED);
+                log::logger().flush();
+                std::process::exit(EXIT_INTERRUPTED);
+            }
         }
     });
+    if let Err(e) = ret {
+        warn!("Failed to install the console interrupt handler: {:?}", e);
+    }
+    let mut first = true;
+    let mut reason: &str;
 
-    let arc_con_w = Arc::clone(&con);
-    let th_pipe_to_stdout =
-        std::thread::spawn(
-            move || match pipe_to_stdout(pipe_pts, arc_con_w, &args.redir) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error in pipe_to_stdout: {:?}", e);
-                }
-            },
+    loop {
+        update_title(&args, &con, "connecting");
+        let action = if args.listen {
+            "waiting for a client on"
+        } else {
+            "connecting to"
+        };
+        banner::show(&con, args.banner, &format!("{} {}", action, args.path()));
+        let config = if args.reconnect && !first {
+            args.reconnect_config()
+        } else {
+            args.pipe_config()
+        };
+        let pipe_stp = match open_transport(&args, server.as_ref(), &config) {
+            Ok(pipe) => pipe,
+            Err(EXIT_CONNECT_TIMEOUT) if first => {
+                let _ = con.restore();
+                watchdog::disarm();
+                notify::remove_icon();
+                summary::finish("connect timed out", EXIT_CONNECT_TIMEOUT);
+                std::process::exit(EXIT_CONNECT_TIMEOUT);
+            }
+            Err(code) if first => {
+                let _ = con.restore();
+                watchdog::disarm();
+                notify::remove_icon();
+                summary::finish("connect failed", code);
+                std::process::exit(code);
+            }
+            Err(_) if !args.reconnect && ask_reconnect(&args, &con) => continue,
+            Err(_) => {
+                reason = "reconnect failed";
+                break;
+            }
+        };
+        update_title(&args, &con, "connected");
+        // Listening, the server is winpipe itself; sockets have no owner.
+        let owner = match pipe_stp.as_named_pipe() {
+            Some(pipe) if !args.listen => owner::server_owner(pipe).ok(),
+            _ => None,
+        };
+        let identity = match owner {
+            Some(owner) => {
+                info!("Pipe server is {}", owner.describe());
+                format!(" (server {})", owner.describe())
+            }
+            None => String::new(),
+        };
+        let action = if first {
+            "connected to"
+        } else {
+            "reconnected to"
+        };
+        banner::show(
+            &con,
+            args.banner,
+            &format!("{} {}{}", action, args.path(), identity),
         );
+        if let Some(template) = &args.announce_size {
+            if let Err(e) = announce_size(&*pipe_stp, &con, template) {
+                warn!("Failed to announce console size: {}", e);
+            }
+        }
 
-    th_pipe_to_stdout.join().unwrap();
-    th_stdin_to_pipe.join().unwrap();
+        write_capture_metadata(&args, &capture_header(&args, &con));
 
+        // The session owns the console until the pipe goes away.
+        logger::set_console(false);
+        if first && !steps.is_empty() {
+            let ret = capture::open(&[&args.redir])
+                .map_err(script::ScriptError::Io)
+                .and_then(|mut captures| {
+                    let mut filter = filter::ControlFilter::new(&args.filter_control, args.osc52);
+                    let mut output = |buf: &[u8]| {
+                        con.write(&filter.apply(buf))?;
+                        captures.write(buf)
+                    };
+                    script::run(&steps, &*pipe_stp, &mut output)?;
+                    if args.batch {
+                        // Don't lose what the guest printed after the last step.
+                        script::drain(&*pipe_stp, &mut output)?;
+                    }
+                    Ok(())
+                });
+            if let Err(e) = ret {
+                logger::set_console(true);
+                error!("Script failed: {:?}", e);
+                reset_terminal(&args, &con);
+                let _ = con.restore();
+                watchdog::disarm();
+                notify::remove_icon();
+                summary::finish(&format!("script failed: {:?}", e), e.exit_code());
+                std::process::exit(e.exit_code());
+            }
+            if args.batch {
+                reset_terminal(&args, &con);
+                let _ = con.restore();
+                watchdog::disarm();
+                notify::remove_icon();
+                summary::finish("batch complete", 0);
+                return;
+            }
+        }
+        first = false;
+
+        *pipe_slot.lock().unwrap() = Some(Arc::clone(&pipe_stp));
+        let ret = bridge.run(pipe_stp);
+        *pipe_slot.lock().unwrap() = None;
+        logger::set_console(true);
+        if let Some(server) = &server {
+            server.disconnect();
+        }
+
+        banner::show(
+            &con,
+            args.banner,
+            &format!("disconnected from {}", args.path()),
+        );
+        session_ended(&args, &ret);
+        reason = if bridge.quit() {
+            "quit"
+        } else if bridge.input_closed() {
+            "input closed"
+        } else if ret.is_err() {
+            "error"
+        } else {
+            "disconnected"
+        };
+        if bridge.quit() || bridge.input_closed() || !(args.reconnect || ask_reconnect(&args, &con))
+        {
+            break;
+        }
+    }
+
+    reset_terminal(&args, &con);
     match con.restore() {
         Ok(_) => {}
         Err(e) => {
             error!("Failed to restore console: {:?}", e);
         }
     }
+
+    if let Some(format) = args.stats_at_exit {
+        eprint!("{}", stats::dump(format));
+    }
+    watchdog::disarm();
+    notify::remove_icon();
+    summary::finish(reason, 0);
 }
@@ -0,0 +1,311 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::ansi;
+use crate::capture;
+use crate::clipboard;
+use crate::console::Console;
+use crate::crlf;
+use crate::history::History;
+use crate::keys;
+use crate::pager;
+use crate::receive;
+use crate::script;
+use crate::stats;
+
+const DEFAULT_COPY_LINES: usize = 20;
+
+/// What the input loop should do once the menu is closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Resume,
+    /// Write these bytes to the pipe, then resume.
+    Send(Vec<u8>),
+    /// Stream this file into the pipe, then resume.
+    SendFile(PathBuf),
+    /// Leave this connection; --reconnect still applies.
+    Detach,
+    /// End winpipe.
+    Quit,
+}
+
+/// Parses an escape key given as `^]` style caret notation, `0x1d` or a
+/// single character.
+pub fn parse_escape(s: &str) -> Result<u8, String> {
+    let bytes = s.as_bytes();
+    match bytes {
+        [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => {
+            Ok(c.to_ascii_uppercase() & 0x1f)
+        }
+        [c] => Ok(*c),
+        _ => match s.strip_prefix("0x").map(|hex| u8::from_str_radix(hex, 16)) {
+            Some(Ok(b)) => Ok(b),
+            _ => Err(format!("expected a key like ^] or 0x1d, got {:?}", s)),
+        },
+    }
+}
+
+/// `key` as typed: `^]` for control keys.
+pub fn key_name(key: u8) -> String {
+    match key {
+        0..=0x1f => format!("^{}", (key | 0x40) as char),
+        0x7f => "^?".to_string(),
+        _ => (key as char).to_string(),
+    }
+}
+
+fn say(con: &Console, msg: &str) -> windows::core::Result<()> {
+    con.write(format!("\r\n[winpipe] {}", msg).as_bytes())?;
+    Ok(())
+}
+
+/// Reads the bytes of the next key press, which may be an escape sequence.
+pub fn read_input(con: &Console) -> windows::core::Result<Vec<u8>> {
+    let mut key: Vec<u8> = vec![0u8; 16];
+    loop {
+        let n = con.read(&mut key)? as usize;
+        if n > 0 {
+            key.truncate(n);
+            return Ok(key);
+        }
+    }
+}
+
+pub fn read_key(con: &Console) -> windows::core::Result<u8> {
+    Ok(read_input(con)?[0])
+}
+
+/// Reads a line with local echo; Escape cancels and returns `None`.
+pub fn read_line(con: &Console) -> windows::core::Result<Option<String>> {
+    let mut line = String::new();
+    loop {
+        match read_key(con)? {
+            b'\r' | b'\n' => return Ok(Some(line)),
+            0x1b => return Ok(None),
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    con.write(b"\x08 \x08")?;
+                }
+            }
+            c if (0x20..0x7f).contains(&c) => {
+                line.push(c as char);
+                con.write(&[c])?;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn copy_lines(con: &Console, history: &Mutex<History>) -> windows::core::Result<()> {
+    say(con, &format!("lines to copy [{}]: ", DEFAULT_COPY_LINES))?;
+    let n = match read_line(con)? {
+        Some(line) if line.trim().is_empty() => DEFAULT_COPY_LINES,
+        Some(line) => match line.trim().parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => return say(con, "not a number\r\n"),
+        },
+        None => return con.write(b"\r\n").map(|_| ()),
+    };
+
+    let text = ansi::strip(&history.lock().unwrap().tail(n));
+    let text = String::from_utf8_lossy(&text);
+    match clipboard::set_text(&text) {
+        Ok(_) => {
+            info!("Copied {} lines to the clipboard", n);
+            say(con, &format!("copied {} lines\r\n", n))
+        }
+        Err(e) => say(con, &format!("copy failed: {}\r\n", e.message())),
+    }
+}
+
+/// Runs a host command through `cmd /C` on the console in its original
+/// (cooked) mode, then returns to the session.
+fn shell_command(con: &Console) -> windows::core::Result<()> {
+    say(con, "host command: ")?;
+    let cmd = match read_line(con)? {
+        Some(cmd) if !cmd.trim().is_empty() => cmd,
+        _ => return con.write(b"\r\n").map(|_| ()),
+    };
+    con.write(b"\r\n")?;
+
+    info!("Running host command: {}", cmd);
+    con.suspend()?;
+    let status = Command::new("cmd").arg("/C").arg(&cmd).status();
+    con.resume()?;
+    match status {
+        Ok(status) => say(con, &format!("command exited with {}\r\n", status)),
+        Err(e) => say(con, &format!("failed to run command: {}\r\n", e)),
+    }
+}
+
+/// Starts capturing pipe output into a host file, up to a byte count or a
+/// marker string (with `script::unescape` escapes).
+fn receive_file(con: &Console, slot: &receive::Slot) -> windows::core::Result<()> {
+    say(con, "receive into file: ")?;
+    let path = match read_line(con)? {
+        Some(path) if !path.trim().is_empty() => PathBuf::from(path.trim()),
+        _ => return con.write(b"\r\n").map(|_| ()),
+    };
+    say(con, "byte count or end marker: ")?;
+    let until = match read_line(con)? {
+        Some(s) if !s.trim().is_empty() => match s.trim().parse::<usize>() {
+            Ok(n) => receive::Until::Bytes(n),
+            Err(_) => match script::unescape(&s) {
+                Ok(marker) => receive::Until::Marker(marker),
+                Err(e) => return say(con, &format!("{}\r\n", e)),
+            },
+        },
+        _ => return con.write(b"\r\n").map(|_| ()),
+    };
+
+    match receive::Receive::new(path.clone(), until) {
+        Ok(r) => {
+            info!("Receiving into {:?}", path);
+            *slot.lock().unwrap() = Some(r);
+            say(con, &format!("receiving into {}\r\n", path.display()))
+        }
+        Err(e) => say(con, &format!("can't create {}: {}\r\n", path.display(), e)),
+    }
+}
+
+/// Reads bytes typed as hex, see `script::parse_hex`.
+fn send_hex(con: &Console) -> windows::core::Result<Action> {
+    say(con, "hex bytes to send: ")?;
+    let line = match read_line(con)? {
+        Some(line) if !line.trim().is_empty() => line,
+        _ => {
+            con.write(b"\r\n")?;
+            return Ok(Action::Resume);
+        }
+    };
+    con.write(b"\r\n")?;
+    match script::parse_hex(&line) {
+        Ok(bytes) => {
+            info!("Sending {} bytes typed as hex", bytes.len());
+            Ok(Action::Send(bytes))
+        }
+        Err(e) => {
+            say(con, &format!("{}\r\n", e))?;
+            Ok(Action::Resume)
+        }
+    }
+}
+
+/// Asks for a file to stream into the pipe.
+fn paste_file(con: &Console) -> windows::core::Result<Action> {
+    say(con, "file to send: ")?;
+    let path = match read_line(con)? {
+        Some(path) if !path.trim().is_empty() => PathBuf::from(path.trim()),
+        _ => {
+            con.write(b"\r\n")?;
+            return Ok(Action::Resume);
+        }
+    };
+    con.write(b"\r\n")?;
+    if !path.is_file() {
+        say(con, &format!("no such file {}\r\n", path.display()))?;
+        return Ok(Action::Resume);
+    }
+    Ok(Action::SendFile(path))
+}
+
+/// Shows the escape menu and runs the chosen command. `escape` is the key
+/// that opened it; pressing it again sends it to the pipe.
+pub fn run(
+    con: &Console,
+    escape: u8,
+    history: &Mutex<History>,
+    receive: &receive::Slot,
+    enter: &mut Option<keys::Enter>,
+    line_modes: &crlf::LineModes,
+) -> windows::core::Result<Action> {
+    let key = key_name(escape);
+    say(
+        con,
+        &format!(
+            "c: copy last lines to clipboard, s: scrollback, r: receive file, x: send hex bytes, f: paste file, e: Enter key, n: CR LF translation, l: local echo, k: split captures, o: captures on/off, i: I/O statistics, !: host command, d: detach, q: quit, {}: send {}, Enter: resume > ",
+            key, key
+        ),
+    )?;
+    match read_key(con)? {
+        k if k == escape => {
+            con.write(b"\r\n")?;
+            return Ok(Action::Send(vec![escape]));
+        }
+        b'q' | b'Q' => {
+            info!("Quit from the escape menu");
+            con.write(b"\r\n")?;
+            return Ok(Action::Quit);
+        }
+        b'd' | b'D' => {
+            info!("Detached from the escape menu");
+            con.write(b"\r\n")?;
+            return Ok(Action::Detach);
+        }
+        b'o' | b'O' => {
+            let state = if capture::toggle_paused() {
+                "paused"
+            } else {
+                "resumed"
+            };
+            say(con, &format!("captures {}\r\n", state))?;
+        }
+        b'c' | b'C' => copy_lines(con, history)?,
+        b's' | b'S' => pager::run(con, history)?,
+        b'r' | b'R' => receive_file(con, receive)?,
+        b'x' | b'X' => return send_hex(con),
+        b'f' | b'F' => return paste_file(con),
+        b'e' | b'E' => {
+            let next = enter.map_or(keys::Enter::Cr, keys::Enter::next);
+            *enter = Some(next);
+            info!("Enter now sends {:?}", next);
+            say(con, &format!("Enter sends {}\r\n", next.name()))?;
+            // Input translation would change what Enter sends again.
+            if line_modes.crlf_in() {
+                line_modes.clear_crlf_in();
+                let now = crlf::name(line_modes.crlf());
+                info!("CR LF translation now {}", now);
+                say(con, &format!("CR LF translation {}\r\n", now))?;
+            }
+        }
+        b'n' | b'N' => {
+            let next = line_modes.next_crlf();
+            info!("CR LF translation now {}", crlf::name(next));
+            say(con, &format!("CR LF translation {}\r\n", crlf::name(next)))?;
+            // Translated input sends CR LF for Enter whatever it is mapped to.
+            if line_modes.crlf_in() && enter.take().is_some() {
+                info!("Enter now sent as typed");
+                say(con, "Enter sent as typed\r\n")?;
+            }
+        }
+        b'l' | b'L' => {
+            let state = if line_modes.toggle_local_echo() {
+                "on"
+            } else {
+                "off"
+            };
+            info!("Local echo {}", state);
+            say(con, &format!("local echo {}\r\n", state))?;
+        }
+        b'k' | b'K' => {
+            let segment = capture::split();
+            say(
+                con,
+                &format!("captures continue in segment {}\r\n", segment),
+            )?;
+        }
+        b'i' | b'I' => {
+            let text = stats::dump(stats::Format::Text).replace('\n', "\r\n");
+            con.write(format!("\r\n{}", text).as_bytes())?;
+        }
+        b'!' => shell_command(con)?,
+        _ => con.write(b"\r\n").map(|_| ())?,
+    }
+    Ok(Action::Resume)
+}
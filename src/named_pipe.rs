@@ -1,37 +1,81 @@
 // Copyright 2024 Qi, Yadong.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::fs::OpenOptions;
+use std::io::IoSlice;
+use std::io::IoSliceMut;
 use std::os::windows::fs::OpenOptionsExt;
 use std::os::windows::io::IntoRawHandle;
 use std::os::windows::io::RawHandle;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
 use std::thread::sleep;
 use std::time;
 
+use clap::ValueEnum;
 use log::error;
+use log::info;
+use log::warn;
 
+use windows::core::HRESULT;
+use windows::core::HSTRING;
 use windows::core::PCSTR;
+use windows::Win32::Foundation::CloseHandle;
 use windows::Win32::Foundation::DuplicateHandle;
 use windows::Win32::Foundation::DUPLICATE_SAME_ACCESS;
+use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
 use windows::Win32::Foundation::ERROR_FILE_NOT_FOUND;
 use windows::Win32::Foundation::ERROR_IO_PENDING;
+use windows::Win32::Foundation::ERROR_MORE_DATA;
+use windows::Win32::Foundation::ERROR_OPERATION_ABORTED;
+use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+use windows::Win32::Foundation::ERROR_PIPE_CONNECTED;
+use windows::Win32::Foundation::ERROR_SEM_TIMEOUT;
+use windows::Win32::Foundation::ERROR_TIMEOUT;
+use windows::Win32::Foundation::ERROR_WRITE_FAULT;
+use windows::Win32::Foundation::FALSE;
 use windows::Win32::Foundation::HANDLE;
 use windows::Win32::Foundation::TRUE;
+use windows::Win32::Foundation::WAIT_TIMEOUT;
 
 use windows::Win32::Storage::FileSystem::ReadFile;
 use windows::Win32::Storage::FileSystem::WriteFile;
+use windows::Win32::Storage::FileSystem::FILE_FLAG_FIRST_PIPE_INSTANCE;
 use windows::Win32::Storage::FileSystem::FILE_FLAG_OVERLAPPED;
+use windows::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+use windows::Win32::Storage::FileSystem::SECURITY_ANONYMOUS;
+use windows::Win32::Storage::FileSystem::SECURITY_IDENTIFICATION;
+use windows::Win32::Storage::FileSystem::SECURITY_IMPERSONATION;
 use windows::Win32::Storage::FileSystem::SECURITY_SQOS_PRESENT;
+use windows::Win32::System::Pipes::ConnectNamedPipe;
+use windows::Win32::System::Pipes::CreateNamedPipeW;
+use windows::Win32::System::Pipes::DisconnectNamedPipe;
+use windows::Win32::System::Pipes::GetNamedPipeInfo;
 use windows::Win32::System::Pipes::PeekNamedPipe;
 use windows::Win32::System::Pipes::SetNamedPipeHandleState;
 use windows::Win32::System::Pipes::WaitNamedPipeA;
 use windows::Win32::System::Pipes::NAMED_PIPE_MODE;
 use windows::Win32::System::Pipes::PIPE_READMODE_BYTE;
+use windows::Win32::System::Pipes::PIPE_READMODE_MESSAGE;
+use windows::Win32::System::Pipes::PIPE_REJECT_REMOTE_CLIENTS;
+use windows::Win32::System::Pipes::PIPE_TYPE_BYTE;
+use windows::Win32::System::Pipes::PIPE_TYPE_MESSAGE;
+use windows::Win32::System::Pipes::PIPE_WAIT;
 use windows::Win32::System::Threading::GetCurrentProcess;
+use windows::Win32::System::IO::CancelIoEx;
 use windows::Win32::System::IO::GetOverlappedResult;
+use windows::Win32::System::IO::GetOverlappedResultEx;
 use windows::Win32::System::IO::OVERLAPPED;
 
+use crate::event;
+use crate::event::Event;
+use crate::stats;
+
+const READ_GROW_SIZE: usize = 4096;
+
 #[derive(Debug)]
 pub struct HandleDesc {
     pub handle: isize,
@@ -77,9 +121,289 @@ impl Clone for HandleDesc {
     }
 }
 
+/// Every clone is a duplicated handle, so each one is closed on its own.
+impl Drop for HandleDesc {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.as_handle());
+        }
+    }
+}
+
+/// Issues an overlapped ReadFile on `handle` and waits for it to complete
+/// or for `wake`, whichever is first. A read abandoned for `wake` fails
+/// with ERROR_OPERATION_ABORTED. The byte count is returned alongside the
+/// status since ERROR_MORE_DATA still transfers data.
+pub fn read_overlapped(
+    handle: HANDLE,
+    buffer: &mut [u8],
+    wake: Option<&Event>,
+) -> (u32, windows::core::Result<()>) {
+    let done = match Event::new() {
+        Ok(done) => done,
+        Err(e) => return (0, Err(e)),
+    };
+    let mut ov = OVERLAPPED {
+        hEvent: done.as_handle(),
+        ..Default::default()
+    };
+    let mut bytes_read: u32 = 0;
+
+    let ret = match unsafe { ReadFile(handle, Some(buffer), Some(&mut bytes_read), Some(&mut ov)) }
+    {
+        Err(e) if e.code() == ERROR_IO_PENDING.into() => {
+            let mut handles = vec![done.as_handle()];
+            handles.extend(wake.map(Event::as_handle));
+            let woken = event::wait_any(&handles);
+            if woken.as_ref().map_or(true, |i| *i != 0) {
+                unsafe {
+                    let _ = CancelIoEx(handle, Some(&ov as *const OVERLAPPED));
+                }
+            }
+            // The OVERLAPPED must stay alive until the cancel lands, and the
+            // read may still have completed before it did.
+            let ret = unsafe { GetOverlappedResult(handle, &ov, &mut bytes_read, TRUE) };
+            woken.and(ret)
+        }
+        ret => ret,
+    };
+    (bytes_read, ret)
+}
+
+/// Retry policy for (re)connecting: the delay starts at `initial` and
+/// doubles after every failed attempt up to `max`. `attempts` bounds the
+/// number of retries, `None` retries forever.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: time::Duration,
+    pub max: time::Duration,
+    pub attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: time::Duration::from_millis(100),
+            max: time::Duration::from_millis(2000),
+            attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn delays(&self) -> impl Iterator<Item = time::Duration> {
+        let max = self.max.max(self.initial);
+        let delays = std::iter::successors(Some(self.initial), move |d| Some((*d * 2).min(max)));
+        delays.take(self.attempts.map_or(usize::MAX, |n| n as usize))
+    }
+}
+
+/// Which directions a client connection is opened for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Access {
+    #[default]
+    ReadWrite,
+    Read,
+    Write,
+}
+
+/// How pipe reads are done: a byte stream or one message per read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReadMode {
+    /// follow the pipe type the server created
+    #[default]
+    Auto,
+    /// read a byte stream
+    Byte,
+    /// read whole messages
+    Message,
+}
+
+/// What the pipe server may do with the client's identity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Impersonation {
+    /// the server can't tell who the client is
+    Anonymous,
+    /// the server can identify the client but not act as it
+    #[default]
+    Identification,
+    /// the server can act as the client
+    Impersonation,
+}
+
+/// Whether `name` is a pipe on another machine, `\\server\pipe\name`.
+pub fn is_remote(name: &str) -> bool {
+    match name.strip_prefix(r"\\").or_else(|| name.strip_prefix("//")) {
+        Some(rest) => {
+            let server = rest.split(['\\', '/']).next().unwrap_or_default();
+            server != "." && server != "?"
+        }
+        None => false,
+    }
+}
+
+/// How a client connection to a pipe is made.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PipeConfig {
+    /// Keep retrying while the pipe doesn't exist yet.
+    pub wait: bool,
+    /// Without `wait`, retry a missing pipe this many times before failing.
+    pub retries: u32,
+    pub backoff: Backoff,
+    pub access: Access,
+    /// Upper bound of a single WriteFile issued by `write_all`, for servers
+    /// that misbehave on large writes. `None` writes buffers in one go.
+    pub write_chunk_size: Option<usize>,
+    pub impersonation: Impersonation,
+    /// Give up waiting for a missing or busy pipe after this long, failing
+    /// with ERROR_SEM_TIMEOUT.
+    pub connect_timeout: Option<time::Duration>,
+    /// Cancel reads and writes that haven't completed after this long.
+    pub io_timeout: Option<time::Duration>,
+    pub read_mode: ReadMode,
+}
+
+/// How `Server` creates its pipe.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerConfig {
+    /// Most instances of the pipe that may exist at once. Past the first,
+    /// a spare instance is kept open so the next client doesn't find the
+    /// pipe busy while the current one is served.
+    pub instances: u32,
+    pub in_buffer_size: u32,
+    pub out_buffer_size: u32,
+    /// Create a message pipe instead of a byte pipe.
+    pub message: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            instances: 1,
+            in_buffer_size: 4096,
+            out_buffer_size: 4096,
+            message: false,
+        }
+    }
+}
+
+/// The server end of a pipe winpipe creates itself, for peers that connect
+/// rather than listen. One client is served at a time.
+pub struct Server {
+    name: String,
+    config: ServerConfig,
+    /// The instance being served first, then the spare one if any.
+    instances: Mutex<VecDeque<HandleDesc>>,
+}
+
+/// Creates one instance of `name`. Only the first is created with
+/// FILE_FLAG_FIRST_PIPE_INSTANCE, the spares join the pipe it made.
+fn create_instance(
+    name: &str,
+    config: &ServerConfig,
+    first: bool,
+) -> windows::core::Result<HandleDesc> {
+    let pipe_type = if config.message {
+        PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE
+    } else {
+        PIPE_TYPE_BYTE | PIPE_READMODE_BYTE
+    };
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let handle = unsafe {
+        CreateNamedPipeW(
+            &HSTRING::from(name),
+            open_mode,
+            pipe_type | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            config.instances.max(1),
+            config.out_buffer_size,
+            config.in_buffer_size,
+            0,
+            None,
+        )
+    }?;
+    Ok(HandleDesc::from_handle(handle))
+}
+
+impl Server {
+    /// Creates the first instance of `name`. It fails if a pipe of that
+    /// name already exists, so clients can't be split between winpipe and
+    /// another server squatting on the name.
+    pub fn create(name: &str, config: &ServerConfig) -> windows::core::Result<Server> {
+        match create_instance(name, config, true) {
+            Ok(instance) => {
+                info!("Listening on {}", name);
+                Ok(Server {
+                    name: name.to_string(),
+                    config: *config,
+                    instances: Mutex::new(VecDeque::from([instance])),
+                })
+            }
+            Err(e) if e.code() == ERROR_ACCESS_DENIED.into() => {
+                error!("{} already exists, another server owns the name", name);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for a client to connect and returns the connection.
+    pub fn accept(&self, config: &PipeConfig) -> windows::core::Result<NamedPipe> {
+        let instance = self.instances.lock().unwrap()[0].try_clone()?;
+        let pipe = NamedPipe {
+            pipe_handle: instance,
+            write_chunk_size: config.write_chunk_size,
+            io_timeout: config.io_timeout,
+        };
+        let mut ov = OVERLAPPED::default();
+        match unsafe { ConnectNamedPipe(pipe.as_handle(), Some(&mut ov)) } {
+            Ok(_) => {}
+            // The client got in between creating the pipe and this call.
+            Err(e) if e.code() == ERROR_PIPE_CONNECTED.into() => {}
+            Err(e) if e.code() == ERROR_IO_PENDING.into() => {
+                let mut bytes: u32 = 0;
+                unsafe { GetOverlappedResult(pipe.as_handle(), &ov, &mut bytes, TRUE)? };
+            }
+            Err(e) => return Err(e),
+        }
+        info!("Client connected");
+        self.add_spare();
+        Ok(pipe)
+    }
+
+    /// Opens the spare instance the next client connects to, when
+    /// `instances` allows one.
+    fn add_spare(&self) {
+        let mut instances = self.instances.lock().unwrap();
+        if self.config.instances < 2 || instances.len() > 1 {
+            return;
+        }
+        match create_instance(&self.name, &self.config, false) {
+            Ok(spare) => instances.push_back(spare),
+            Err(e) => warn!("Failed to create a spare instance: {:?}", e),
+        }
+    }
+
+    /// Drops the current client so the next one can be accepted.
+    pub fn disconnect(&self) {
+        let mut instances = self.instances.lock().unwrap();
+        if let Err(e) = unsafe { DisconnectNamedPipe(instances[0].as_handle()) } {
+            warn!("Failed to disconnect the client: {:?}", e);
+        }
+        // Move on to the spare, a client may already be waiting on it.
+        if instances.len() > 1 {
+            instances.pop_front();
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct NamedPipe {
     pipe_handle: HandleDesc,
+    write_chunk_size: Option<usize>,
+    io_timeout: Option<time::Duration>,
 }
 
 unsafe fn set_named_pipe_handle_state(
@@ -93,6 +417,36 @@ unsafe fn set_named_pipe_handle_state(
     Ok(())
 }
 
+/// Picks the client read mode matching the type of pipe the server
+/// created, honouring an explicit `requested` mode where the type allows.
+fn pick_read_mode(pipe_handle: HANDLE, requested: ReadMode) -> NAMED_PIPE_MODE {
+    let mut flags = NAMED_PIPE_MODE(0);
+    let ret = unsafe { GetNamedPipeInfo(pipe_handle, Some(&mut flags), None, None, None) };
+    let message = match ret {
+        Ok(_) => {
+            let message = flags.0 & PIPE_TYPE_MESSAGE.0 != 0;
+            info!("Pipe type: {}", if message { "message" } else { "byte" });
+            message
+        }
+        Err(e) => {
+            warn!("Failed to query the pipe type: {:?}", e);
+            requested == ReadMode::Message
+        }
+    };
+    match (requested, message) {
+        (ReadMode::Auto, true) | (ReadMode::Message, true) => PIPE_READMODE_MESSAGE,
+        (ReadMode::Message, false) => {
+            warn!("--read-mode message needs a message pipe, reading bytes");
+            PIPE_READMODE_BYTE
+        }
+        (ReadMode::Byte, true) => {
+            warn!("Reading a message pipe in byte mode, message boundaries are lost");
+            PIPE_READMODE_BYTE
+        }
+        _ => PIPE_READMODE_BYTE,
+    }
+}
+
 #[allow(dead_code)]
 unsafe fn wait_named_pipe(name: &str, timeout: u32) -> windows::core::Result<()> {
     let pipe_name = CString::new(name).unwrap();
@@ -114,22 +468,89 @@ impl NamedPipe {
         self.as_handle().0 as RawHandle
     }
 
-    pub fn try_open(name: &str, wait: bool) -> windows::core::Result<NamedPipe> {
-        if wait {
-            Self::open_wait(name)
+    /// Polls until a pipe called `name` exists, without connecting to it,
+    /// or until `timeout` passes. Returns whether it showed up.
+    pub fn wait_exists(name: &str, timeout: Option<time::Duration>) -> bool {
+        let pipe_name = CString::new(name).unwrap();
+        let deadline = timeout.map(|t| time::Instant::now() + t);
+        loop {
+            match unsafe { WaitNamedPipeA(PCSTR(pipe_name.as_ptr() as *const u8), 1) } {
+                Ok(()) => return true,
+                // Every instance is busy, but the pipe is there.
+                Err(e) if e.code() == ERROR_SEM_TIMEOUT.into() => return true,
+                Err(_) => {}
+            }
+            if deadline.is_some_and(|d| time::Instant::now() >= d) {
+                return false;
+            }
+            sleep(time::Duration::from_millis(100));
+        }
+    }
+
+    /// Wraps a pipe handle opened by someone else, e.g. inherited from a
+    /// parent process. It must have been opened with FILE_FLAG_OVERLAPPED,
+    /// and is closed when the pipe is dropped.
+    pub fn from_raw_handle(handle: HANDLE, config: &PipeConfig) -> NamedPipe {
+        NamedPipe {
+            pipe_handle: HandleDesc::from_handle(handle),
+            write_chunk_size: config.write_chunk_size,
+            io_timeout: config.io_timeout,
+        }
+    }
+
+    pub fn try_open(name: &str, config: &PipeConfig) -> windows::core::Result<NamedPipe> {
+        if config.retries > 0 {
+            let mut config = *config;
+            config.backoff.attempts = Some(config.retries);
+            Self::open_wait(name, &config)
+        } else if config.wait || config.connect_timeout.is_some() {
+            Self::open_wait(name, config)
         } else {
-            Self::open(name)
+            Self::open(name, config)
         }
     }
 
-    pub fn open_wait(name: &str) -> windows::core::Result<NamedPipe> {
+    pub fn open_wait(name: &str, config: &PipeConfig) -> windows::core::Result<NamedPipe> {
+        let mut delays = config.backoff.delays();
+        let deadline = config.connect_timeout.map(|t| time::Instant::now() + t);
         loop {
-            match Self::open(name) {
+            let ret = match deadline {
+                Some(deadline) => Self::open_until(name, config, deadline),
+                None => Self::open(name, config),
+            };
+            match ret {
                 Ok(pipe) => return Ok(pipe),
+                Err(e) if e == ERROR_SEM_TIMEOUT.into() => {
+                    warn!(
+                        "Gave up connecting to {} after {:?}",
+                        name,
+                        config.connect_timeout.unwrap_or_default()
+                    );
+                    break Err(e);
+                }
                 Err(e) => {
-                    if e == ERROR_FILE_NOT_FOUND.into() {
-                        let duration = time::Duration::from_millis(100);
-                        sleep(duration);
+                    // A busy pipe is only waited out with a deadline, or a
+                    // server that keeps one client would hang us for good.
+                    let busy = e == ERROR_PIPE_BUSY.into() && deadline.is_some();
+                    if e == ERROR_FILE_NOT_FOUND.into() || busy {
+                        let duration = match delays.next() {
+                            Some(duration) => duration,
+                            None => break Err(e),
+                        };
+                        if let Some(deadline) = deadline {
+                            let left = deadline.saturating_duration_since(time::Instant::now());
+                            if left.is_zero() {
+                                warn!(
+                                    "Gave up connecting to {} after {:?}",
+                                    name,
+                                    config.connect_timeout.unwrap_or_default()
+                                );
+                                break Err(ERROR_SEM_TIMEOUT.to_hresult().into());
+                            }
+                            sleep(duration.min(left));
+                        } else {
+                            sleep(duration);
+                        }
                         continue;
                     } else {
                         break Err(e);
@@ -139,23 +560,62 @@ impl NamedPipe {
         }
     }
 
-    pub fn open(name: &str) -> windows::core::Result<NamedPipe> {
+    /// `open` that gives up with ERROR_SEM_TIMEOUT at `deadline`. Opening
+    /// a remote pipe goes over SMB and can block for a long time, so it is
+    /// done on a helper thread that is left to finish on its own; a pipe it
+    /// opens too late is closed.
+    fn open_until(
+        name: &str,
+        config: &PipeConfig,
+        deadline: time::Instant,
+    ) -> windows::core::Result<NamedPipe> {
+        if !is_remote(name) {
+            return Self::open(name, config);
+        }
+        let (tx, rx) = mpsc::channel();
+        let owned = name.to_string();
+        let config = *config;
+        thread::spawn(move || {
+            let _ = tx.send(Self::open(&owned, &config));
+        });
+        match rx.recv_timeout(deadline.saturating_duration_since(time::Instant::now())) {
+            Ok(ret) => ret,
+            Err(_) => Err(ERROR_SEM_TIMEOUT.to_hresult().into()),
+        }
+    }
+
+    pub fn open(name: &str, config: &PipeConfig) -> windows::core::Result<NamedPipe> {
+        let readable = config.access != Access::Write;
+        let writable = config.access != Access::Read;
+        let sqos = match config.impersonation {
+            Impersonation::Anonymous => SECURITY_ANONYMOUS,
+            Impersonation::Identification => SECURITY_IDENTIFICATION,
+            Impersonation::Impersonation => SECURITY_IMPERSONATION,
+        };
         let raw_handle = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .custom_flags(SECURITY_SQOS_PRESENT.0 | FILE_FLAG_OVERLAPPED.0)
+            .read(readable)
+            .write(writable)
+            .create(writable)
+            .custom_flags(SECURITY_SQOS_PRESENT.0 | sqos.0 | FILE_FLAG_OVERLAPPED.0)
             .open(name)?
             .into_raw_handle();
         let pipe_handle = HANDLE(raw_handle);
 
-        unsafe {
-            let client_mode = PIPE_READMODE_BYTE;
-            set_named_pipe_handle_state(pipe_handle, Some(&client_mode))?;
-        };
+        // Changing the read mode needs write access, a read-only handle
+        // stays in the default byte mode.
+        if writable {
+            unsafe {
+                let client_mode = pick_read_mode(pipe_handle, config.read_mode);
+                set_named_pipe_handle_state(pipe_handle, Some(&client_mode))?;
+            };
+        } else if config.read_mode == ReadMode::Message {
+            warn!("--read-mode message needs write access, reading bytes");
+        }
 
         Ok(NamedPipe {
             pipe_handle: HandleDesc::from_handle(pipe_handle),
+            write_chunk_size: config.write_chunk_size,
+            io_timeout: config.io_timeout,
         })
     }
 
@@ -177,14 +637,65 @@ impl NamedPipe {
         }
     }
 
-    pub fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+    pub fn get_message_bytes_left(&self) -> windows::core::Result<u32> {
+        let mut bytes_left_this_message = 0;
+
+        match unsafe {
+            PeekNamedPipe(
+                self.as_handle(),
+                None,
+                0,
+                None,
+                None,
+                Some(&mut bytes_left_this_message),
+            )
+        } {
+            Err(e) => Err(e),
+            Ok(_) => Ok(bytes_left_this_message),
+        }
+    }
+
+    /// Waits for the overlapped `op` to finish. Past the I/O timeout it is
+    /// logged and cancelled with CancelIoEx, so a server that stops
+    /// servicing the pipe can't freeze the session. ERROR_TIMEOUT is only
+    /// returned when nothing was transferred; otherwise `bytes` tells how
+    /// far it got.
+    fn complete(&self, ov: &OVERLAPPED, bytes: &mut u32, op: &str) -> windows::core::Result<()> {
+        let Some(timeout) = self.io_timeout else {
+            return unsafe { GetOverlappedResult(self.as_handle(), ov, bytes, TRUE) };
+        };
+        let ms = timeout.as_millis().min(u32::MAX as u128 - 1) as u32;
+        match unsafe { GetOverlappedResultEx(self.as_handle(), ov, bytes, ms, FALSE) } {
+            Err(e) if e.code() == HRESULT::from_win32(WAIT_TIMEOUT.0) => {
+                warn!(
+                    "Pipe {} stuck for {:?} (handle {:#x}), cancelling",
+                    op, timeout, self.pipe_handle.handle
+                );
+                let ret = unsafe {
+                    let _ = CancelIoEx(self.as_handle(), Some(ov as *const OVERLAPPED));
+                    // The OVERLAPPED must stay alive until the cancel lands.
+                    GetOverlappedResult(self.as_handle(), ov, bytes, TRUE)
+                };
+                match ret {
+                    // It finished as the cancel landed.
+                    Ok(()) => Ok(()),
+                    Err(e) if e.code() == ERROR_MORE_DATA.into() => Err(e),
+                    // A short transfer, which the callers already handle.
+                    Err(_) if *bytes > 0 => Ok(()),
+                    Err(_) => Err(ERROR_TIMEOUT.to_hresult().into()),
+                }
+            }
+            ret => ret,
+        }
+    }
+
+    /// Issues a single ReadFile into `buffer`. The byte count is returned
+    /// alongside the status since ERROR_MORE_DATA still transfers data.
+    fn read_some(&self, buffer: &mut [u8]) -> (u32, windows::core::Result<()>) {
         let mut bytes_read: u32 = 0;
         let mut ov = OVERLAPPED::default();
 
-        let avail_bytes = self.get_available_byte_count()?;
-        buffer.resize(avail_bytes as usize, 0);
-
-        match unsafe {
+        let ret = match unsafe {
             ReadFile(
                 self.as_handle(),
                 Some(buffer),
@@ -192,25 +703,96 @@ impl NamedPipe {
                 Some(&mut ov),
             )
         } {
-            Err(e) => {
-                if e.code() == ERROR_IO_PENDING.into() {
-                    unsafe {
-                        GetOverlappedResult(self.as_handle(), &ov, &mut bytes_read, TRUE)?;
-                    }
-                    Ok(bytes_read)
-                } else {
-                    Err(e)
+            Err(e) if e.code() == ERROR_IO_PENDING.into() => {
+                self.complete(&ov, &mut bytes_read, "read")
+            }
+            ret => ret,
+        };
+        (bytes_read, ret)
+    }
+
+    /// Reads into the spare capacity of `buffer` so it never has to be
+    /// zero-filled; `buffer` only ever holds bytes that were actually read.
+    pub fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        let start = time::Instant::now();
+        let avail_bytes = self.get_available_byte_count()?;
+        buffer.clear();
+        if avail_bytes == 0 {
+            // Nothing buffered; ReadFile would block until the guest writes.
+            return Ok(0);
+        }
+        let mut want = avail_bytes as usize;
+        buffer.reserve(want);
+
+        loop {
+            let total = buffer.len();
+            let spare = &mut buffer.spare_capacity_mut()[..want];
+            // ReadFile only writes into the slice, nothing reads it before
+            // the length is moved past the bytes it reported.
+            let spare = unsafe {
+                std::slice::from_raw_parts_mut(spare.as_mut_ptr() as *mut u8, spare.len())
+            };
+            let (n, ret) = self.read_some(spare);
+            unsafe { buffer.set_len(total + n as usize) };
+            match ret {
+                Ok(_) => break,
+                // The message is larger than the buffer (or the peeked size
+                // was stale), grow it and read the rest of the message.
+                Err(e) if e.code() == ERROR_MORE_DATA.into() => {
+                    want = match self.get_message_bytes_left()? {
+                        0 => READ_GROW_SIZE,
+                        n => n as usize,
+                    };
+                    buffer.reserve(want);
                 }
+                Err(e) => return Err(e),
             }
-            Ok(_) => Ok(bytes_read),
         }
+        if !buffer.is_empty() {
+            stats::record_read(buffer.len(), start.elapsed());
+        }
+        Ok(buffer.len() as u32)
+    }
+
+    /// Like `read`, but waits for data instead of returning 0 when none is
+    /// buffered: an overlapped ReadFile signals its own event as soon as
+    /// bytes arrive. Setting `wake` abandons the read and returns 0.
+    pub fn read_wait(
+        &self,
+        buffer: &mut Vec<u8>,
+        wake: Option<&Event>,
+    ) -> windows::core::Result<u32> {
+        buffer.clear();
+        buffer.resize(READ_GROW_SIZE, 0);
+        let (n, ret) = read_overlapped(self.as_handle(), buffer, wake);
+
+        // Time spent waiting for the guest to write isn't read latency.
+        let start = time::Instant::now();
+        buffer.truncate(n as usize);
+        let more = match ret {
+            Ok(_) => false,
+            Err(e) if e.code() == ERROR_OPERATION_ABORTED.into() => return Ok(0),
+            Err(e) if e.code() == ERROR_MORE_DATA.into() => true,
+            Err(e) => return Err(e),
+        };
+        if !buffer.is_empty() {
+            stats::record_read(buffer.len(), start.elapsed());
+        }
+        if more {
+            // The rest of a message that didn't fit.
+            let mut rest = Vec::new();
+            self.read(&mut rest)?;
+            buffer.extend_from_slice(&rest);
+        }
+        Ok(buffer.len() as u32)
     }
 
     pub fn write(&self, buffer: &[u8]) -> windows::core::Result<u32> {
         let mut bytes_written: u32 = buffer.len() as u32;
         let mut ov = OVERLAPPED::default();
+        let start = time::Instant::now();
 
-        match unsafe {
+        if let Err(e) = unsafe {
             WriteFile(
                 self.as_handle(),
                 Some(buffer),
@@ -218,17 +800,74 @@ impl NamedPipe {
                 Some(&mut ov),
             )
         } {
-            Err(e) => {
-                if e.code() == ERROR_IO_PENDING.into() {
-                    unsafe {
-                        GetOverlappedResult(self.as_handle(), &ov, &mut bytes_written, TRUE)?;
-                    }
-                    Ok(bytes_written)
-                } else {
-                    Err(e)
-                }
+            if e.code() != ERROR_IO_PENDING.into() {
+                return Err(e);
+            }
+            self.complete(&ov, &mut bytes_written, "write")?;
+        }
+        stats::record_write(bytes_written as usize, start.elapsed());
+        Ok(bytes_written)
+    }
+
+    /// Reads what is available into `bufs` in order. Pipes can't scatter
+    /// natively, so this is a single ReadFile into a temporary buffer.
+    pub fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> windows::core::Result<u32> {
+        let capacity: usize = bufs.iter().map(|b| b.len()).sum();
+        let avail_bytes = self.get_available_byte_count()? as usize;
+        let mut buffer: Vec<u8> = vec![0u8; avail_bytes.min(capacity)];
+
+        let (n, ret) = self.read_some(&mut buffer);
+        // Like `read`, a message that doesn't fit is not an error here; the
+        // rest of it is returned by the next read.
+        match ret {
+            Err(e) if e.code() != ERROR_MORE_DATA.into() => return Err(e),
+            _ => {}
+        }
+
+        let mut data = &buffer[..n as usize];
+        for buf in bufs.iter_mut() {
+            if data.is_empty() {
+                break;
             }
-            Ok(_) => Ok(bytes_written),
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(n)
+    }
+
+    /// Writes `bufs` back to back. Pipes can't gather natively, so the
+    /// slices are copied into one buffer and sent with `write_all`.
+    pub fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> windows::core::Result<u32> {
+        let buffer: Vec<u8> = bufs.iter().flat_map(|b| b.iter().copied()).collect();
+        self.write_all(&buffer)?;
+        Ok(buffer.len() as u32)
+    }
+
+    /// Writes the whole buffer in writes of at most the configured chunk
+    /// size, retrying short writes. A write that makes no progress is
+    /// reported as `ERROR_WRITE_FAULT`.
+    pub fn write_all(&self, mut buffer: &[u8]) -> windows::core::Result<()> {
+        let chunk_size = self.write_chunk_size.unwrap_or(usize::MAX).max(1);
+        while !buffer.is_empty() {
+            let n = self.write(&buffer[..buffer.len().min(chunk_size)])? as usize;
+            if n == 0 {
+                error!("Short write to pipe: {} bytes left", buffer.len());
+                return Err(ERROR_WRITE_FAULT.to_hresult().into());
+            }
+            buffer = &buffer[n.min(buffer.len())..];
+        }
+        Ok(())
+    }
+
+    /// Writes `buffer` in a single WriteFile, so on a message pipe it
+    /// arrives as one message.
+    pub fn write_message(&self, buffer: &[u8]) -> windows::core::Result<()> {
+        let n = self.write(buffer)? as usize;
+        if n != buffer.len() {
+            error!("Short write to pipe: {} of {} bytes", n, buffer.len());
+            return Err(ERROR_WRITE_FAULT.to_hresult().into());
         }
+        Ok(())
     }
 }
@@ -0,0 +1,122 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::c_void;
+use std::mem::size_of;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+
+use windows::Win32::Foundation::HWND;
+use windows::Win32::System::Console::GetConsoleWindow;
+use windows::Win32::UI::Shell::Shell_NotifyIconW;
+use windows::Win32::UI::Shell::NIF_ICON;
+use windows::Win32::UI::Shell::NIF_INFO;
+use windows::Win32::UI::Shell::NIF_TIP;
+use windows::Win32::UI::Shell::NIIF_INFO;
+use windows::Win32::UI::Shell::NIM_ADD;
+use windows::Win32::UI::Shell::NIM_DELETE;
+use windows::Win32::UI::Shell::NOTIFYICONDATAW;
+use windows::Win32::UI::WindowsAndMessaging::FlashWindowEx;
+use windows::Win32::UI::WindowsAndMessaging::LoadIconW;
+use windows::Win32::UI::WindowsAndMessaging::MessageBeep;
+use windows::Win32::UI::WindowsAndMessaging::FLASHWINFO;
+use windows::Win32::UI::WindowsAndMessaging::FLASHW_ALL;
+use windows::Win32::UI::WindowsAndMessaging::FLASHW_TIMERNOFG;
+use windows::Win32::UI::WindowsAndMessaging::IDI_INFORMATION;
+use windows::Win32::UI::WindowsAndMessaging::MB_ICONINFORMATION;
+
+/// How long the tray icon carrying the balloon stays around.
+const BALLOON_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The tray icon being shown, as the raw console window it belongs to and
+/// the notification that added it.
+static ICON: Mutex<Option<(isize, u64)>> = Mutex::new(None);
+static SHOWN: AtomicU64 = AtomicU64::new(0);
+
+fn copy_wide(dst: &mut [u16], src: &str) {
+    let wide: Vec<u16> = src.encode_utf16().take(dst.len() - 1).collect();
+    dst[..wide.len()].copy_from_slice(&wide);
+    dst[wide.len()] = 0;
+}
+
+fn delete_icon(icon: Option<(isize, u64)>) {
+    let Some((raw_hwnd, _)) = icon else {
+        return;
+    };
+    let nid = NOTIFYICONDATAW {
+        cbSize: size_of::<NOTIFYICONDATAW>() as u32,
+        hWnd: HWND(raw_hwnd as *mut c_void),
+        uID: 1,
+        ..Default::default()
+    };
+    unsafe {
+        let _ = Shell_NotifyIconW(NIM_DELETE, &nid);
+    }
+}
+
+/// Takes the tray icon of the last notification down, if it is still up.
+/// Called on the way out, as the shell only drops a dead process's icon
+/// once the mouse passes over it.
+pub fn remove_icon() {
+    delete_icon(ICON.lock().unwrap().take());
+}
+
+/// Shows a balloon notification and flashes the console window until it
+/// gets focus, optionally with a beep.
+pub fn notify(title: &str, message: &str, bell: bool) {
+    let hwnd = unsafe { GetConsoleWindow() };
+
+    let mut nid = NOTIFYICONDATAW {
+        cbSize: size_of::<NOTIFYICONDATAW>() as u32,
+        hWnd: hwnd,
+        uID: 1,
+        uFlags: NIF_ICON | NIF_TIP | NIF_INFO,
+        dwInfoFlags: NIIF_INFO,
+        ..Default::default()
+    };
+    nid.hIcon = unsafe { LoadIconW(None, IDI_INFORMATION) }.unwrap_or_default();
+    copy_wide(&mut nid.szTip, "winpipe");
+    copy_wide(&mut nid.szInfoTitle, title);
+    copy_wide(&mut nid.szInfo, message);
+
+    // A balloon still up is replaced rather than added twice.
+    remove_icon();
+    if unsafe { Shell_NotifyIconW(NIM_ADD, &nid) }.as_bool() {
+        let id = SHOWN.fetch_add(1, Ordering::SeqCst) + 1;
+        // HWND isn't Send, the raw value is kept instead.
+        *ICON.lock().unwrap() = Some((hwnd.0 as isize, id));
+        thread::spawn(move || {
+            thread::sleep(BALLOON_LIFETIME);
+            let mut icon = ICON.lock().unwrap();
+            if icon.is_some_and(|(_, shown)| shown == id) {
+                delete_icon(icon.take());
+            }
+        });
+    } else {
+        warn!("Failed to show notification: {}", message);
+    }
+
+    if !hwnd.is_invalid() {
+        let fwi = FLASHWINFO {
+            cbSize: size_of::<FLASHWINFO>() as u32,
+            hwnd,
+            dwFlags: FLASHW_ALL | FLASHW_TIMERNOFG,
+            uCount: 0,
+            dwTimeout: 0,
+        };
+        unsafe {
+            let _ = FlashWindowEx(&fwi);
+        }
+    }
+
+    if bell {
+        unsafe {
+            let _ = MessageBeep(MB_ICONINFORMATION);
+        }
+    }
+}
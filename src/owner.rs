@@ -0,0 +1,215 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::c_void;
+
+use log::info;
+use log::warn;
+
+use windows::core::PCWSTR;
+use windows::core::PWSTR;
+use windows::Win32::Foundation::CloseHandle;
+use windows::Win32::Foundation::LocalFree;
+use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::HLOCAL;
+use windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+use windows::Win32::Security::GetTokenInformation;
+use windows::Win32::Security::LookupAccountSidW;
+use windows::Win32::Security::TokenUser;
+use windows::Win32::Security::PSID;
+use windows::Win32::Security::SID_NAME_USE;
+use windows::Win32::Security::TOKEN_QUERY;
+use windows::Win32::Security::TOKEN_USER;
+use windows::Win32::System::Pipes::GetNamedPipeServerProcessId;
+use windows::Win32::System::Threading::OpenProcess;
+use windows::Win32::System::Threading::OpenProcessToken;
+use windows::Win32::System::Threading::QueryFullProcessImageNameW;
+use windows::Win32::System::Threading::PROCESS_NAME_WIN32;
+use windows::Win32::System::Threading::PROCESS_QUERY_LIMITED_INFORMATION;
+
+use crate::named_pipe::NamedPipe;
+
+/// The process serving a pipe and the account it runs as.
+#[derive(Debug)]
+pub struct Owner {
+    pub pid: u32,
+    /// Full path of the executable, when the process can be queried.
+    pub image: Option<String>,
+    /// String form of the SID, e.g. `S-1-5-18`.
+    pub sid: String,
+    /// `DOMAIN\name`, when the SID resolves to an account.
+    pub account: Option<String>,
+}
+
+impl Owner {
+    /// Compares `expected` against the SID or the account name, ignoring case.
+    pub fn matches(&self, expected: &str) -> bool {
+        self.sid.eq_ignore_ascii_case(expected)
+            || self
+                .account
+                .as_deref()
+                .is_some_and(|a| a.eq_ignore_ascii_case(expected))
+    }
+
+    /// Compares `expected` against the image: the full path when it has a
+    /// directory, otherwise the file name with or without `.exe`.
+    pub fn image_matches(&self, expected: &str) -> bool {
+        self.image
+            .as_deref()
+            .is_some_and(|image| image_matches(image, expected))
+    }
+
+    /// One line for banners and logs, e.g. `pid 42, C:\vm\qemu.exe, HOST\me`.
+    pub fn describe(&self) -> String {
+        format!(
+            "pid {}, {}, {}",
+            self.pid,
+            self.image.as_deref().unwrap_or("unknown image"),
+            self.account.as_deref().unwrap_or(&self.sid)
+        )
+    }
+}
+
+fn image_matches(image: &str, expected: &str) -> bool {
+    if expected.contains(['\\', '/']) {
+        return image.eq_ignore_ascii_case(&expected.replace('/', "\\"));
+    }
+    let name = image.rsplit('\\').next().unwrap_or(image);
+    name.eq_ignore_ascii_case(expected) || name.eq_ignore_ascii_case(&format!("{}.exe", expected))
+}
+
+fn image_path(process: HANDLE) -> Option<String> {
+    let mut path = [0u16; 1024];
+    let mut len = path.len() as u32;
+    unsafe {
+        QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(path.as_mut_ptr()),
+            &mut len,
+        )
+        .ok()?;
+    }
+    Some(String::from_utf16_lossy(&path[..len as usize]))
+}
+
+fn sid_to_string(sid: PSID) -> windows::core::Result<String> {
+    let mut s = PWSTR::null();
+    unsafe {
+        ConvertSidToStringSidW(sid, &mut s)?;
+        let ret = String::from_utf16_lossy(s.as_wide());
+        let _ = LocalFree(HLOCAL(s.0 as *mut c_void));
+        Ok(ret)
+    }
+}
+
+fn lookup_account(sid: PSID) -> Option<String> {
+    let mut name = [0u16; 256];
+    let mut domain = [0u16; 256];
+    let mut name_len = name.len() as u32;
+    let mut domain_len = domain.len() as u32;
+    let mut usage = SID_NAME_USE::default();
+    unsafe {
+        LookupAccountSidW(
+            PCWSTR::null(),
+            sid,
+            PWSTR(name.as_mut_ptr()),
+            &mut name_len,
+            PWSTR(domain.as_mut_ptr()),
+            &mut domain_len,
+            &mut usage,
+        )
+        .ok()?;
+    }
+    let name = String::from_utf16_lossy(&name[..name_len as usize]);
+    let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+    Some(format!("{}\\{}", domain, name))
+}
+
+fn token_owner(token: HANDLE, pid: u32) -> windows::core::Result<Owner> {
+    let mut len: u32 = 0;
+    // The first call only reports the size needed.
+    let _ = unsafe { GetTokenInformation(token, TokenUser, None, 0, &mut len) };
+    let mut buffer: Vec<u64> = vec![0; (len as usize).div_ceil(8)];
+    unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            Some(buffer.as_mut_ptr() as *mut c_void),
+            len,
+            &mut len,
+        )?;
+    }
+    let user = unsafe { &*(buffer.as_ptr() as *const TOKEN_USER) };
+    Ok(Owner {
+        pid,
+        image: None,
+        sid: sid_to_string(user.User.Sid)?,
+        account: lookup_account(user.User.Sid),
+    })
+}
+
+/// Looks up the process on the server end of `pipe` and its account.
+pub fn server_owner(pipe: &NamedPipe) -> windows::core::Result<Owner> {
+    let mut pid: u32 = 0;
+    unsafe {
+        GetNamedPipeServerProcessId(pipe.as_handle(), &mut pid)?;
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid)?;
+        let image = image_path(process);
+        let mut token = HANDLE::default();
+        let ret = OpenProcessToken(process, TOKEN_QUERY, &mut token);
+        let _ = CloseHandle(process);
+        ret?;
+        let ret = token_owner(token, pid);
+        let _ = CloseHandle(token);
+        ret.map(|owner| Owner { image, ..owner })
+    }
+}
+
+/// Fails with ERROR_ACCESS_DENIED unless the pipe server runs as `expected`,
+/// given as a SID string or `DOMAIN\name`.
+pub fn verify(pipe: &NamedPipe, expected: &str) -> windows::core::Result<()> {
+    let owner = server_owner(pipe)?;
+    if owner.matches(expected) {
+        info!("Pipe server pid {} runs as {}", owner.pid, expected);
+        return Ok(());
+    }
+    warn!(
+        "Pipe server pid {} runs as {} ({}), expected {}",
+        owner.pid,
+        owner.account.as_deref().unwrap_or("unknown account"),
+        owner.sid,
+        expected
+    );
+    Err(ERROR_ACCESS_DENIED.to_hresult().into())
+}
+
+/// Fails with ERROR_ACCESS_DENIED unless the pipe server runs `expected`,
+/// see [`Owner::image_matches`].
+/// Only the image is queried, so a server whose token can't be opened,
+/// e.g. one running as another user, can still be checked.
+pub fn verify_image(pipe: &NamedPipe, expected: &str) -> windows::core::Result<()> {
+    let mut pid: u32 = 0;
+    let image = unsafe {
+        GetNamedPipeServerProcessId(pipe.as_handle(), &mut pid)?;
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid)?;
+        let image = image_path(process);
+        let _ = CloseHandle(process);
+        image
+    };
+    let described = image.as_deref().unwrap_or("unknown image");
+    if image
+        .as_deref()
+        .is_some_and(|image| image_matches(image, expected))
+    {
+        info!("Pipe server is pid {}, {}", pid, described);
+        return Ok(());
+    }
+    warn!(
+        "Pipe server is pid {}, {}, expected image {}",
+        pid, described, expected
+    );
+    Err(ERROR_ACCESS_DENIED.to_hresult().into())
+}
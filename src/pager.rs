@@ -0,0 +1,143 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Mutex;
+
+use crate::ansi;
+use crate::console::Console;
+use crate::history::History;
+use crate::menu;
+
+const ALT_SCREEN_ON: &[u8] = b"\x1b[?1049h";
+const ALT_SCREEN_OFF: &[u8] = b"\x1b[?1049l";
+
+struct Pager<'a> {
+    con: &'a Console,
+    lines: Vec<String>,
+    top: usize,
+    rows: usize,
+    cols: usize,
+    query: Option<String>,
+    status: String,
+}
+
+impl<'a> Pager<'a> {
+    fn page(&self) -> usize {
+        self.rows.saturating_sub(1).max(1)
+    }
+
+    fn last_top(&self) -> usize {
+        self.lines.len().saturating_sub(self.page())
+    }
+
+    fn scroll(&mut self, delta: isize) {
+        let top = self.top as isize + delta;
+        self.top = top.clamp(0, self.last_top() as isize) as usize;
+    }
+
+    fn render(&self) -> windows::core::Result<()> {
+        let mut screen: Vec<u8> = b"\x1b[H\x1b[2J".to_vec();
+        for line in self.lines.iter().skip(self.top).take(self.page()) {
+            let line: String = line.chars().take(self.cols).collect();
+            screen.extend_from_slice(line.as_bytes());
+            screen.extend_from_slice(b"\r\n");
+        }
+
+        let end = (self.top + self.page()).min(self.lines.len());
+        let status = format!(
+            "\x1b[{};1H\x1b[7m lines {}-{}/{}  q:quit j/k:line space/b:page /:search n/N:next/prev {}\x1b[0m",
+            self.rows,
+            self.top + 1,
+            end,
+            self.lines.len(),
+            self.status
+        );
+        screen.extend_from_slice(status.as_bytes());
+        self.con.write(&screen)?;
+        Ok(())
+    }
+
+    /// Moves to the next line matching the query, searching forward or
+    /// backward from the current top line.
+    fn find(&mut self, forward: bool) {
+        let query = match &self.query {
+            Some(q) if !q.is_empty() => q.to_lowercase(),
+            _ => return,
+        };
+        let matches = |i: &usize| self.lines[*i].to_lowercase().contains(&query);
+        let found = if forward {
+            (self.top + 1..self.lines.len()).find(matches)
+        } else {
+            (0..self.top).rev().find(matches)
+        };
+        match found {
+            Some(i) => {
+                self.top = i.min(self.last_top());
+                self.status.clear();
+            }
+            None => self.status = format!("[not found: {}]", query),
+        }
+    }
+
+    fn search(&mut self) -> windows::core::Result<()> {
+        let prompt = format!("\x1b[{};1H\x1b[2K/", self.rows);
+        self.con.write(prompt.as_bytes())?;
+        if let Some(query) = menu::read_line(self.con)? {
+            self.query = Some(query);
+            self.find(true);
+        }
+        Ok(())
+    }
+}
+
+/// Shows the kept output on the alternate screen with paging and a
+/// case-insensitive substring search.
+pub fn run(con: &Console, history: &Mutex<History>) -> windows::core::Result<()> {
+    let lines: Vec<String> = history
+        .lock()
+        .unwrap()
+        .lines()
+        .iter()
+        .map(|line| {
+            let text = ansi::strip(line);
+            String::from_utf8_lossy(&text)
+                .trim_end_matches(['\r', '\n'])
+                .to_string()
+        })
+        .collect();
+    let (cols, rows) = con.size().unwrap_or((80, 24));
+
+    let mut pager = Pager {
+        con,
+        lines,
+        top: 0,
+        rows: rows as usize,
+        cols: cols as usize,
+        query: None,
+        status: String::new(),
+    };
+    pager.top = pager.last_top();
+
+    con.write(ALT_SCREEN_ON)?;
+    let ret = (|| -> windows::core::Result<()> {
+        loop {
+            pager.render()?;
+            let page = pager.page() as isize;
+            match menu::read_input(con)?.as_slice() {
+                b"q" | b"Q" | b"\x1b" => break Ok(()),
+                b"j" | b"\r" | b"\x1b[B" => pager.scroll(1),
+                b"k" | b"\x1b[A" => pager.scroll(-1),
+                b" " | b"\x1b[6~" => pager.scroll(page),
+                b"b" | b"\x1b[5~" => pager.scroll(-page),
+                b"g" | b"\x1b[H" => pager.top = 0,
+                b"G" | b"\x1b[F" => pager.top = pager.last_top(),
+                b"/" => pager.search()?,
+                b"n" => pager.find(true),
+                b"N" => pager.find(false),
+                _ => {}
+            }
+        }
+    })();
+    con.write(ALT_SCREEN_OFF)?;
+    ret
+}
@@ -0,0 +1,79 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::ValueEnum;
+
+/// Reads larger than this are treated as a paste rather than typed keys;
+/// the longest key sequences (e.g. `ESC [ 1 ; 5 A`) stay below it.
+pub const PASTE_MIN_BYTES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PasteTransform {
+    /// remove carriage returns
+    StripCr,
+    /// remove line feeds
+    StripLf,
+    /// fold runs of blank lines into a single one
+    CollapseBlank,
+    /// replace tabs with spaces up to the next tab stop
+    ExpandTabs,
+}
+
+pub fn is_paste(buf: &[u8]) -> bool {
+    buf.len() > PASTE_MIN_BYTES
+}
+
+fn strip(buf: &[u8], byte: u8) -> Vec<u8> {
+    buf.iter().copied().filter(|b| *b != byte).collect()
+}
+
+fn collapse_blank(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut prev_blank = false;
+    for line in buf.split_inclusive(|b| *b == b'\n') {
+        let blank = line.iter().all(|b| b.is_ascii_whitespace());
+        if blank && line.ends_with(b"\n") && prev_blank {
+            continue;
+        }
+        prev_blank = blank;
+        out.extend_from_slice(line);
+    }
+    out
+}
+
+fn expand_tabs(buf: &[u8], tab_width: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut col = 0;
+    for b in buf {
+        match *b {
+            b'\t' => {
+                let n = tab_width - col % tab_width;
+                out.resize(out.len() + n, b' ');
+                col += n;
+            }
+            b'\r' | b'\n' => {
+                out.push(*b);
+                col = 0;
+            }
+            _ => {
+                out.push(*b);
+                col += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Applies `transforms` in order to a pasted buffer.
+pub fn transform(buf: &[u8], transforms: &[PasteTransform], tab_width: usize) -> Vec<u8> {
+    let mut out = buf.to_vec();
+    for t in transforms {
+        out = match t {
+            PasteTransform::StripCr => strip(&out, b'\r'),
+            PasteTransform::StripLf => strip(&out, b'\n'),
+            PasteTransform::CollapseBlank => collapse_blank(&out),
+            PasteTransform::ExpandTabs => expand_tabs(&out, tab_width.max(1)),
+        };
+    }
+    out
+}
@@ -0,0 +1,116 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::debug;
+
+use crate::named_pipe::NamedPipe;
+
+const PROBE_PREFIX: &str = "WINPIPE-PING:";
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub sent: u32,
+    pub received: u32,
+    pub rtts: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn summary(&self) -> String {
+        let loss = if self.sent == 0 {
+            0.0
+        } else {
+            100.0 * (self.sent - self.received) as f64 / self.sent as f64
+        };
+        let mut s = format!(
+            "{} probes sent, {} received, {:.1}% loss",
+            self.sent, self.received, loss
+        );
+        if let (Some(min), Some(max)) = (self.rtts.iter().min(), self.rtts.iter().max()) {
+            let avg = self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32;
+            s.push_str(&format!(
+                "\nrtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+                min.as_secs_f64() * 1000.0,
+                avg.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0
+            ));
+        }
+        s
+    }
+}
+
+/// Extracts the sequence numbers of complete probes in `window`, dropping
+/// everything up to the last one.
+fn take_probes(window: &mut String) -> Vec<u32> {
+    let mut seqs = Vec::new();
+    let mut consumed = 0;
+    while let Some(start) = window[consumed..].find(PROBE_PREFIX) {
+        let rest = &window[consumed + start + PROBE_PREFIX.len()..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        if let Ok(seq) = rest[..end].parse::<u32>() {
+            seqs.push(seq);
+        }
+        consumed += start + PROBE_PREFIX.len() + end + 1;
+    }
+    window.drain(..consumed);
+    seqs
+}
+
+/// Sends `count` probes `interval` apart to an echoing peer and measures
+/// the round trip of each, waiting at most `timeout` for every reply.
+pub fn run(
+    pipe: &NamedPipe,
+    count: u32,
+    interval: Duration,
+    timeout: Duration,
+) -> Result<Stats, std::io::Error> {
+    let mut stats = Stats::default();
+    let mut pending: HashMap<u32, Instant> = HashMap::new();
+    let mut window = String::new();
+
+    for seq in 0..count {
+        let probe = format!("{}{};\r\n", PROBE_PREFIX, seq);
+        pending.insert(seq, Instant::now());
+        pipe.write_all(probe.as_bytes())?;
+        stats.sent += 1;
+
+        let deadline = Instant::now() + timeout;
+        while pending.contains_key(&seq) && Instant::now() < deadline {
+            let mut buffer: Vec<u8> = Vec::new();
+            if pipe.read(&mut buffer)? == 0 {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            window.push_str(&String::from_utf8_lossy(&buffer));
+            for echoed in take_probes(&mut window) {
+                if let Some(sent) = pending.remove(&echoed) {
+                    let rtt = sent.elapsed();
+                    println!(
+                        "reply seq={} time={:.3} ms",
+                        echoed,
+                        rtt.as_secs_f64() * 1000.0
+                    );
+                    stats.received += 1;
+                    stats.rtts.push(rtt);
+                }
+            }
+        }
+        if pending.remove(&seq).is_some() {
+            debug!("Probe {} timed out", seq);
+            println!("timeout seq={}", seq);
+        }
+
+        if seq + 1 < count {
+            thread::sleep(interval);
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,290 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use regex::bytes::Regex;
+
+use crate::ansi;
+use crate::answerback;
+use crate::answerback::Query;
+use crate::crlf;
+use crate::crlf::LineModes;
+use crate::filter::ControlFilter;
+use crate::paste;
+
+/// Which way bytes are flowing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Console input on its way to the pipe.
+    ToPipe,
+    /// Pipe output on its way to the console and captures.
+    FromPipe,
+}
+
+/// A stage of a byte pipeline. A filter returns the bytes handed on to the
+/// next stage; filters that only observe the stream return `buf` as is.
+/// Filters may keep state, e.g. to hold back a sequence split across reads.
+pub trait Filter: Send {
+    fn apply(&mut self, direction: Direction, buf: &[u8]) -> Vec<u8>;
+}
+
+impl Filter for ControlFilter {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        ControlFilter::apply(self, buf)
+    }
+}
+
+/// Applies `--paste-transform` to pastes, see `paste::transform`.
+pub struct PasteTransform {
+    pub transforms: Vec<paste::PasteTransform>,
+    pub tab_width: usize,
+}
+
+impl Filter for PasteTransform {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        if self.transforms.is_empty() || !paste::is_paste(buf) {
+            return buf.to_vec();
+        }
+        paste::transform(buf, &self.transforms, self.tab_width)
+    }
+}
+
+/// CR LF translation of one direction, as `LineModes` currently has it.
+pub struct Crlf {
+    modes: Arc<LineModes>,
+    last_cr: bool,
+}
+
+impl Crlf {
+    pub fn new(modes: Arc<LineModes>) -> Self {
+        Self {
+            modes,
+            last_cr: false,
+        }
+    }
+}
+
+impl Filter for Crlf {
+    fn apply(&mut self, direction: Direction, buf: &[u8]) -> Vec<u8> {
+        match direction {
+            Direction::ToPipe if self.modes.crlf_in() => {
+                crlf::to_crlf(buf, true, &mut self.last_cr)
+            }
+            Direction::FromPipe if self.modes.crlf_out() => {
+                crlf::to_crlf(buf, false, &mut self.last_cr)
+            }
+            _ => buf.to_vec(),
+        }
+    }
+}
+
+/// Takes terminal queries out of the output, see `answerback::Answerback`,
+/// and queues them for the caller to answer once the output before them
+/// is on the console. Guests wait for the answer before writing more.
+pub struct Answerback {
+    inner: answerback::Answerback,
+    queries: Arc<Mutex<Vec<Query>>>,
+}
+
+impl Answerback {
+    pub fn new(queries: Arc<Mutex<Vec<Query>>>) -> Self {
+        Self {
+            inner: answerback::Answerback::new(),
+            queries,
+        }
+    }
+}
+
+impl Filter for Answerback {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len());
+        let mut queries = self.queries.lock().unwrap();
+        for (chunk, query) in self.inner.apply(buf) {
+            out.extend_from_slice(&chunk);
+            queries.extend(query);
+        }
+        out
+    }
+}
+
+/// Removes escape sequences, see `ansi::Stripper`.
+#[derive(Default)]
+pub struct StripAnsi {
+    stripper: ansi::Stripper,
+}
+
+impl Filter for StripAnsi {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        self.stripper.strip(buf)
+    }
+}
+
+/// Replaces the stream with an `xxd` style dump of it. A partial row is
+/// held back until its 16 bytes are in, so rows and offsets line up however
+/// the stream is split.
+#[derive(Default)]
+pub struct Hexdump {
+    offset: usize,
+    row: Vec<u8>,
+}
+
+impl Filter for Hexdump {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        self.row.extend_from_slice(buf);
+        let full = self.row.len() - self.row.len() % 16;
+        let rows: Vec<u8> = self.row.drain(..full).collect();
+        let mut out = String::new();
+        for line in rows.chunks(16) {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = line
+                .iter()
+                .map(|b| match b {
+                    0x20..=0x7e => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            out.push_str(&format!(
+                "{:08x}: {:<47}  {}\r\n",
+                self.offset,
+                hex.join(" "),
+                text
+            ));
+            self.offset += line.len();
+        }
+        out.into_bytes()
+    }
+}
+
+/// Passes only the lines matching a pattern. A partial line is held back
+/// until its end arrives.
+pub struct Grep {
+    pattern: Regex,
+    line: Vec<u8>,
+}
+
+impl Filter for Grep {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in buf.split_inclusive(|b| *b == b'\n') {
+            self.line.extend_from_slice(chunk);
+            if !self.line.ends_with(b"\n") {
+                break;
+            }
+            if self.pattern.is_match(&ansi::strip(&self.line)) {
+                out.extend_from_slice(&self.line);
+            }
+            self.line.clear();
+        }
+        out
+    }
+}
+
+/// Masks every match of a pattern, e.g. passwords or tokens. Matching is
+/// done per read, so a secret split across two reads isn't caught.
+pub struct Redact {
+    pattern: Regex,
+}
+
+impl Filter for Redact {
+    fn apply(&mut self, _direction: Direction, buf: &[u8]) -> Vec<u8> {
+        self.pattern.replace_all(buf, &b"***"[..]).into_owned()
+    }
+}
+
+/// A built-in filter as given on the command line: `strip-ansi`,
+/// `hexdump`, `grep:REGEX` or `redact:REGEX`.
+#[derive(Clone, Debug)]
+pub enum FilterSpec {
+    StripAnsi,
+    Hexdump,
+    Grep(Regex),
+    Redact(Regex),
+}
+
+impl FromStr for FilterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let regex = |arg: Option<&str>| -> Result<Regex, String> {
+            let arg =
+                arg.ok_or_else(|| format!("{} needs a pattern, e.g. {}:REGEX", name, name))?;
+            Regex::new(arg).map_err(|e| e.to_string())
+        };
+        match name {
+            "strip-ansi" => Ok(FilterSpec::StripAnsi),
+            "hexdump" => Ok(FilterSpec::Hexdump),
+            "grep" => Ok(FilterSpec::Grep(regex(arg)?)),
+            "redact" => Ok(FilterSpec::Redact(regex(arg)?)),
+            _ => Err(format!(
+                "unknown filter {:?} (strip-ansi, hexdump, grep:REGEX, redact:REGEX)",
+                name
+            )),
+        }
+    }
+}
+
+impl FilterSpec {
+    pub fn build(&self) -> Box<dyn Filter> {
+        match self {
+            FilterSpec::StripAnsi => Box::new(StripAnsi::default()),
+            FilterSpec::Hexdump => Box::new(Hexdump::default()),
+            FilterSpec::Grep(pattern) => Box::new(Grep {
+                pattern: pattern.clone(),
+                line: Vec::new(),
+            }),
+            FilterSpec::Redact(pattern) => Box::new(Redact {
+                pattern: pattern.clone(),
+            }),
+        }
+    }
+}
+
+/// The filters bytes pass through in one direction, in order.
+pub struct Pipeline {
+    direction: Direction,
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl Pipeline {
+    pub fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn from_specs(direction: Direction, specs: &[FilterSpec]) -> Self {
+        let mut pipeline = Self::new(direction);
+        pipeline.push_specs(specs);
+        pipeline
+    }
+
+    pub fn push(&mut self, filter: Box<dyn Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn push_specs(&mut self, specs: &[FilterSpec]) {
+        for spec in specs {
+            self.push(spec.build());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn run(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut data = buf.to_vec();
+        for filter in self.filters.iter_mut() {
+            data = filter.apply(self.direction, &data);
+        }
+        data
+    }
+}
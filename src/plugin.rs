@@ -0,0 +1,133 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::c_char;
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::info;
+
+use windows::core::s;
+use windows::core::HSTRING;
+use windows::core::PCSTR;
+use windows::Win32::Foundation::ERROR_INVALID_PARAMETER;
+use windows::Win32::Foundation::E_FAIL;
+use windows::Win32::Foundation::HMODULE;
+use windows::Win32::System::LibraryLoader::GetProcAddress;
+use windows::Win32::System::LibraryLoader::LoadLibraryW;
+
+use crate::pipeline::Direction;
+use crate::pipeline::Filter;
+use crate::pipeline::Pipeline;
+
+/// Hands filtered bytes back to winpipe; may be called any number of times
+/// per process call, including not at all.
+pub type Emit = unsafe extern "C" fn(sink: *mut c_void, data: *const u8, len: usize);
+
+/// `void *winpipe_filter_init(const char *arg)`, NULL on failure.
+type Init = unsafe extern "C" fn(arg: *const c_char) -> *mut c_void;
+
+/// What GetProcAddress hands out.
+type Export = unsafe extern "system" fn() -> isize;
+
+/// `void winpipe_filter_process_in/out(void *ctx, const uint8_t *data,
+/// size_t len, emit, void *sink)`.
+type Process = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    data: *const u8,
+    len: usize,
+    emit: Emit,
+    sink: *mut c_void,
+);
+
+struct Plugin {
+    ctx: *mut c_void,
+    process_in: Process,
+    process_out: Process,
+}
+
+// The context is only ever used behind the plugin's mutex, so the DLL
+// never sees two calls at once.
+unsafe impl Send for Plugin {}
+
+static PLUGINS: Mutex<Vec<Arc<Mutex<Plugin>>>> = Mutex::new(Vec::new());
+
+unsafe extern "C" fn emit(sink: *mut c_void, data: *const u8, len: usize) {
+    if data.is_null() || len == 0 {
+        return;
+    }
+    let out = &mut *(sink as *mut Vec<u8>);
+    out.extend_from_slice(std::slice::from_raw_parts(data, len));
+}
+
+fn symbol(module: HMODULE, name: PCSTR) -> windows::core::Result<Export> {
+    unsafe { GetProcAddress(module, name) }.ok_or_else(windows::core::Error::from_win32)
+}
+
+/// Loads a filter DLL given as `PATH[=ARG]` and initializes it with `ARG`
+/// (empty if not given). Its filters run after the built-in ones.
+pub fn load(spec: &str) -> windows::core::Result<()> {
+    let (path, arg) = spec.split_once('=').unwrap_or((spec, ""));
+    let module = unsafe { LoadLibraryW(&HSTRING::from(path))? };
+    // SAFETY: the plugin ABI defines these signatures for the exports.
+    let (init, process_in, process_out) = unsafe {
+        (
+            std::mem::transmute::<Export, Init>(symbol(module, s!("winpipe_filter_init"))?),
+            std::mem::transmute::<Export, Process>(symbol(
+                module,
+                s!("winpipe_filter_process_in"),
+            )?),
+            std::mem::transmute::<Export, Process>(symbol(
+                module,
+                s!("winpipe_filter_process_out"),
+            )?),
+        )
+    };
+    let arg = CString::new(arg).map_err(|_| ERROR_INVALID_PARAMETER.to_hresult())?;
+    let ctx = unsafe { init(arg.as_ptr()) };
+    if ctx.is_null() {
+        return Err(windows::core::Error::new(
+            E_FAIL,
+            "winpipe_filter_init failed",
+        ));
+    }
+    info!("Loaded filter plugin {}", path);
+    PLUGINS.lock().unwrap().push(Arc::new(Mutex::new(Plugin {
+        ctx,
+        process_in,
+        process_out,
+    })));
+    Ok(())
+}
+
+struct PluginFilter(Arc<Mutex<Plugin>>);
+
+impl Filter for PluginFilter {
+    fn apply(&mut self, direction: Direction, buf: &[u8]) -> Vec<u8> {
+        let plugin = self.0.lock().unwrap();
+        let process = match direction {
+            Direction::ToPipe => plugin.process_in,
+            Direction::FromPipe => plugin.process_out,
+        };
+        let mut out: Vec<u8> = Vec::new();
+        unsafe {
+            process(
+                plugin.ctx,
+                buf.as_ptr(),
+                buf.len(),
+                emit,
+                &mut out as *mut Vec<u8> as *mut c_void,
+            )
+        };
+        out
+    }
+}
+
+/// Appends every loaded plugin to `pipeline`.
+pub fn attach(pipeline: &mut Pipeline) {
+    for plugin in PLUGINS.lock().unwrap().iter() {
+        pipeline.push(Box::new(PluginFilter(Arc::clone(plugin))));
+    }
+}
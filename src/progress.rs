@@ -0,0 +1,72 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// Minimum time between two progress updates.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Formats progress of a transfer as bytes, rate and ETA, rate limited so
+/// it can go to the window title on every chunk.
+pub struct Progress {
+    label: String,
+    total: usize,
+    start: Instant,
+    last: Option<Instant>,
+}
+
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+impl Progress {
+    pub fn new(label: &str, total: usize) -> Self {
+        Self {
+            label: label.to_string(),
+            total,
+            start: Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Returns the text for `done` bytes, or `None` if the last update was
+    /// too recent. The final update is always returned.
+    pub fn update(&mut self, done: usize) -> Option<String> {
+        let now = Instant::now();
+        let finished = done >= self.total;
+        if !finished && self.last.is_some_and(|t| now - t < UPDATE_INTERVAL) {
+            return None;
+        }
+        self.last = Some(now);
+
+        let elapsed = (now - self.start).as_secs_f64().max(0.001);
+        let rate = done as f64 / elapsed;
+        let percent = if self.total == 0 {
+            100
+        } else {
+            done * 100 / self.total
+        };
+        let eta = if finished || rate <= 0.0 {
+            String::new()
+        } else {
+            format!(", ETA {:.0}s", (self.total - done) as f64 / rate)
+        };
+        Some(format!(
+            "{} {}% ({} / {}, {}/s{})",
+            self.label,
+            percent,
+            human(done as f64),
+            human(self.total as f64),
+            human(rate),
+            eta
+        ))
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// When a capture into a host file is complete.
+#[derive(Debug)]
+pub enum Until {
+    /// After this many bytes.
+    Bytes(usize),
+    /// At this marker, which is consumed but not written.
+    Marker(Vec<u8>),
+}
+
+/// A capture of pipe output into a host file, started from the escape
+/// menu. While it runs the captured bytes don't reach the console.
+pub struct Receive {
+    file: File,
+    path: PathBuf,
+    until: Until,
+    received: usize,
+    /// Output held back while looking for the marker.
+    pending: Vec<u8>,
+}
+
+/// The capture shared between the escape menu and the output thread.
+pub type Slot = Mutex<Option<Receive>>;
+
+impl Receive {
+    pub fn new(path: PathBuf, until: Until) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            file: File::create(&path)?,
+            path,
+            until,
+            received: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Takes what belongs to the capture from the front of `buf`. Returns
+    /// how many bytes were taken and whether the capture is complete; the
+    /// rest of `buf` is regular output.
+    pub fn feed(&mut self, buf: &[u8]) -> Result<(usize, bool), std::io::Error> {
+        match &self.until {
+            Until::Bytes(total) => {
+                let n = (total - self.received).min(buf.len());
+                self.file.write_all(&buf[..n])?;
+                self.received += n;
+                Ok((n, self.received == *total))
+            }
+            Until::Marker(marker) => {
+                let before = self.pending.len();
+                self.pending.extend_from_slice(buf);
+                let Some(pos) = self
+                    .pending
+                    .windows(marker.len())
+                    .position(|w| w == marker.as_slice())
+                else {
+                    // Everything but a possible marker prefix can be written.
+                    let keep = (marker.len() - 1).min(self.pending.len());
+                    let done = self.pending.len() - keep;
+                    self.file.write_all(&self.pending[..done])?;
+                    self.received += done;
+                    self.pending.drain(..done);
+                    return Ok((buf.len(), false));
+                };
+                self.file.write_all(&self.pending[..pos])?;
+                self.received += pos;
+                let taken = pos + marker.len() - before;
+                self.pending.clear();
+                Ok((taken, true))
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::info;
+
+use windows::Win32::System::SystemInformation::GetLocalTime;
+
+/// Size written to the cast header when there is no console to ask.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// Local time as `2024-05-01 12:34:56.789`.
+pub fn wall_clock() -> String {
+    let t = unsafe { GetLocalTime() };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        t.wYear, t.wMonth, t.wDay, t.wHour, t.wMinute, t.wSecond, t.wMilliseconds
+    )
+}
+
+/// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A session recording in asciinema v2 format: a JSON header line, then
+/// one `[seconds, "o", text]` line per chunk of output, so `asciinema
+/// play` replays it with the original pacing.
+pub struct Cast {
+    file: BufWriter<File>,
+    start: Instant,
+    /// The start of a UTF-8 sequence split across reads.
+    partial: Vec<u8>,
+}
+
+impl Cast {
+    /// Creates `path` for a terminal of `size` (columns, rows).
+    pub fn create(path: &Path, size: Option<(u16, u16)>) -> io::Result<Self> {
+        let (cols, rows) = size.unwrap_or(DEFAULT_SIZE);
+        let mut file = BufWriter::new(File::create(path)?);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        writeln!(
+            file,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"env\": {{\"TERM\": \"xterm-256color\"}}}}",
+            cols, rows, now
+        )?;
+        file.flush()?;
+        info!("Recording to {:?}", path);
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            partial: Vec::new(),
+        })
+    }
+
+    /// Appends `buf` as an output event stamped with the time since the
+    /// recording started.
+    pub fn output(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.partial.extend_from_slice(buf);
+        let complete = match std::str::from_utf8(&self.partial) {
+            Ok(_) => self.partial.len(),
+            // Only an incomplete sequence at the end waits for the next read.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.partial.len(),
+        };
+        if complete == 0 {
+            return Ok(());
+        }
+        let bytes: Vec<u8> = self.partial.drain(..complete).collect();
+        let text = String::from_utf8_lossy(&bytes);
+        writeln!(
+            self.file,
+            "[{:.6}, \"o\", {}]",
+            self.start.elapsed().as_secs_f64(),
+            json_string(&text)
+        )?;
+        self.file.flush()
+    }
+}
+
+/// Prefixes every line of a byte stream with the local time it arrived.
+#[derive(Default)]
+pub struct Timestamps {
+    /// The last chunk ended in the middle of a line.
+    in_line: bool,
+}
+
+impl Timestamps {
+    pub fn apply(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len());
+        for chunk in buf.split_inclusive(|b| *b == b'\n') {
+            if !self.in_line {
+                out.extend_from_slice(format!("[{}] ", wall_clock()).as_bytes());
+            }
+            out.extend_from_slice(chunk);
+            self.in_line = !chunk.ends_with(b"\n");
+        }
+        out
+    }
+}
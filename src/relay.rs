@@ -0,0 +1,123 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Read;
+use std::io::Write;
+use std::thread;
+
+use clap::ValueEnum;
+use log::error;
+use log::warn;
+
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+
+use crate::named_pipe::NamedPipe;
+
+/// How pipe messages are delimited on stdin/stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Frame {
+    /// each message is preceded by its length as a 32-bit little-endian number
+    Len32,
+}
+
+/// Largest `len32` frame accepted, so a corrupt length can't allocate
+/// gigabytes.
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Reads one `len32` frame; `None` at a clean end of stdin.
+fn read_frame(stdin: &mut impl Read) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut len = [0u8; 4];
+    let mut got = 0;
+    while got < len.len() {
+        match stdin.read(&mut len[got..]) {
+            Ok(0) if got == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("stdin ended inside a frame length ({} of 4 bytes)", got),
+                ))
+            }
+            Ok(n) => got += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes is over the {} byte limit",
+                len, MAX_FRAME
+            ),
+        ));
+    }
+    let mut message = vec![0u8; len];
+    stdin.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+fn stdin_to_pipe(pipe: NamedPipe, frame: Option<Frame>) -> Result<(), std::io::Error> {
+    let mut stdin = std::io::stdin().lock();
+    if frame == Some(Frame::Len32) {
+        while let Some(message) = read_frame(&mut stdin)? {
+            pipe.write_message(&message)?;
+        }
+        return Ok(());
+    }
+    let mut buffer: Vec<u8> = vec![0u8; 64 * 1024];
+    loop {
+        let n = stdin.read(&mut buffer)?;
+        if n == 0 {
+            break Ok(());
+        }
+        pipe.write_all(&buffer[..n])?;
+    }
+}
+
+fn pipe_to_stdout(pipe: NamedPipe, frame: Option<Frame>) -> Result<(), std::io::Error> {
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        let mut buffer: Vec<u8> = Vec::new();
+        match pipe.read_wait(&mut buffer, None) {
+            // An empty message.
+            Ok(0) if frame.is_none() => continue,
+            Ok(_) => {}
+            Err(e)
+                if e.code() == ERROR_PIPE_NOT_CONNECTED.into()
+                    || e.code() == ERROR_BROKEN_PIPE.into() =>
+            {
+                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
+                break Ok(());
+            }
+            Err(e) => {
+                error!("Failed to read from pipe: {:?}", e);
+                break Err(e.into());
+            }
+        }
+
+        if frame == Some(Frame::Len32) {
+            stdout.write_all(&(buffer.len() as u32).to_le_bytes())?;
+        }
+        stdout.write_all(&buffer)?;
+        stdout.flush()?;
+    }
+}
+
+/// Pumps raw stdin/stdout to the pipe without touching any console API,
+/// for being exec'd by socat from WSL. Returns when either side closes.
+/// With `frame`, every pipe message is framed on stdout and every frame on
+/// stdin is written as one message, so message boundaries survive.
+pub fn run(pipe: NamedPipe, frame: Option<Frame>) -> Result<(), std::io::Error> {
+    let pipe_r = pipe.clone();
+    thread::spawn(move || {
+        if let Err(e) = stdin_to_pipe(pipe_r, frame) {
+            error!("Error in stdin_to_pipe: {:?}", e);
+        }
+        // stdin EOF ends the relay even if the pipe is still open.
+        std::process::exit(0);
+    });
+
+    pipe_to_stdout(pipe, frame)
+}
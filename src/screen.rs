@@ -0,0 +1,135 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+
+use log::warn;
+
+use crate::console::Console;
+
+/// Most bytes queued for the console before the pipe reader waits.
+const QUEUE_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+struct Queue {
+    data: VecDeque<u8>,
+    /// The writer holds bytes taken off `data` that aren't written yet.
+    busy: bool,
+    closed: bool,
+    error: Option<windows::core::Error>,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+/// Pipe output on its way to the console, written by a dedicated thread.
+/// While the console host is slow to take writes (e.g. a selection in
+/// progress freezes it), the pipe reader keeps draining the pipe into the
+/// queue; only a full queue makes it wait. Dropping it waits for
+/// everything queued to be written.
+pub struct Screen {
+    con: Arc<Console>,
+    shared: Arc<Shared>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+fn write_loop(shared: &Shared, con: &Console) {
+    loop {
+        let chunk: Vec<u8> = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.data.is_empty() && !queue.closed {
+                queue = shared.changed.wait(queue).unwrap();
+            }
+            if queue.data.is_empty() {
+                return;
+            }
+            queue.busy = true;
+            queue.data.drain(..).collect()
+        };
+        let ret = con.write(&chunk);
+        let mut queue = shared.queue.lock().unwrap();
+        queue.busy = false;
+        if let Err(e) = ret {
+            warn!("Failed to write to the console: {:?}", e);
+            queue.error = Some(e);
+            queue.closed = true;
+            queue.data.clear();
+        }
+        shared.changed.notify_all();
+        if queue.error.is_some() {
+            return;
+        }
+    }
+}
+
+impl Screen {
+    pub fn new(con: Arc<Console>) -> Self {
+        let shared = Arc::new(Shared::default());
+        let writer = {
+            let shared = Arc::clone(&shared);
+            let con = Arc::clone(&con);
+            thread::spawn(move || write_loop(&shared, &con))
+        };
+        Self {
+            con,
+            shared,
+            writer: Some(writer),
+        }
+    }
+
+    /// The console written to, for reading it and for prompts.
+    pub fn console(&self) -> &Arc<Console> {
+        &self.con
+    }
+
+    /// Queues `buf` for the console, waiting only while the queue is full.
+    /// Fails from the first failed console write on.
+    pub fn write(&self, buf: &[u8]) -> windows::core::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.error.is_none()
+            && !queue.data.is_empty()
+            && queue.data.len() + buf.len() > QUEUE_SIZE
+        {
+            queue = self.shared.changed.wait(queue).unwrap();
+        }
+        if let Some(e) = &queue.error {
+            return Err(e.clone());
+        }
+        queue.data.extend(buf);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Waits until everything queued is on the console, e.g. before asking
+    /// it for the cursor position.
+    pub fn flush(&self) -> windows::core::Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.error.is_none() && (queue.busy || !queue.data.is_empty()) {
+            queue = self.shared.changed.wait(queue).unwrap();
+        }
+        match &queue.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
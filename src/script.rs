@@ -0,0 +1,299 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use log::warn;
+use regex::Regex;
+
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+
+use crate::summary;
+use crate::transport::Transport;
+
+pub const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Process exit code when an expect step times out.
+pub const EXIT_EXPECT_TIMEOUT: i32 = 2;
+/// Process exit code when the pipe goes away while a script is running.
+pub const EXIT_DISCONNECTED: i32 = 3;
+/// Process exit code for any other script failure.
+pub const EXIT_SCRIPT_ERROR: i32 = 4;
+
+/// Upper bound of unmatched output kept around for expect steps.
+const MATCH_WINDOW: usize = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// How long the guest has to stay quiet before `drain` stops.
+const DRAIN_IDLE: Duration = Duration::from_millis(500);
+/// Upper bound on `drain` for a guest that never stops printing.
+const DRAIN_LIMIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Step {
+    Send(Vec<u8>),
+    Expect {
+        pattern: Regex,
+        timeout: Duration,
+    },
+    /// Waits, still mirroring output and collecting it for the next expect.
+    Sleep(Duration),
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Timeout(String),
+    Disconnected,
+    Io(std::io::Error),
+}
+
+impl ScriptError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScriptError::Timeout(_) => EXIT_EXPECT_TIMEOUT,
+            ScriptError::Disconnected => EXIT_DISCONNECTED,
+            ScriptError::Io(_) => EXIT_SCRIPT_ERROR,
+        }
+    }
+}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(e: std::io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+impl From<windows::core::Error> for ScriptError {
+    fn from(e: windows::core::Error) -> Self {
+        if e.code() == ERROR_PIPE_NOT_CONNECTED.into() {
+            ScriptError::Disconnected
+        } else {
+            ScriptError::Io(e.into())
+        }
+    }
+}
+
+/// Expands `\r`, `\n`, `\t`, `\0`, `\\` and `\xNN` escapes.
+pub fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'0') => out.push(0),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(v) if hex.len() == 2 => out.push(v),
+                    _ => return Err(format!("invalid \\x escape in {:?}", s)),
+                }
+            }
+            Some(c) => return Err(format!("unknown escape \\{} in {:?}", c as char, s)),
+            None => return Err(format!("trailing backslash in {:?}", s)),
+        }
+    }
+    Ok(out)
+}
+
+/// Bytes given on the command line with escapes expanded by [`unescape`].
+#[derive(Clone, Debug)]
+pub struct EscapedBytes(pub Vec<u8>);
+
+impl FromStr for EscapedBytes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        unescape(s).map(EscapedBytes)
+    }
+}
+
+pub fn parse_send(s: &str) -> Result<Step, String> {
+    Ok(Step::Send(unescape(s)?))
+}
+
+/// Parses bytes written as hex, e.g. `1b 5b 41`, `1b5b41` or `0x1b,0x5b`.
+/// Each separated word is an even run of digits on its own, so `1 2` is
+/// an error rather than 0x12.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in s
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+    {
+        let digits = word
+            .strip_prefix("0x")
+            .or_else(|| word.strip_prefix("0X"))
+            .unwrap_or(word);
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(format!(
+                "expected pairs of hex digits, got {:?} in {:?}",
+                word, s
+            ));
+        }
+        for i in (0..digits.len()).step_by(2) {
+            let byte = digits
+                .get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex byte in {:?}", s))?;
+            bytes.push(byte);
+        }
+    }
+    if bytes.is_empty() {
+        return Err(format!("expected pairs of hex digits in {:?}", s));
+    }
+    Ok(bytes)
+}
+
+pub fn parse_send_hex(s: &str) -> Result<Step, String> {
+    Ok(Step::Send(parse_hex(s)?))
+}
+
+/// Splits `pattern[:timeout_secs]`; the suffix is only taken as a timeout
+/// when it is a number, so patterns may contain colons.
+fn split_timeout(s: &str) -> (&str, Duration) {
+    match s.rsplit_once(':') {
+        Some((p, t)) => match t.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => (p, Duration::from_secs_f64(secs)),
+            _ => (s, DEFAULT_EXPECT_TIMEOUT),
+        },
+        None => (s, DEFAULT_EXPECT_TIMEOUT),
+    }
+}
+
+/// Parses `regex[:timeout_secs]`.
+pub fn parse_expect(s: &str) -> Result<Step, String> {
+    let (pattern, timeout) = split_timeout(s);
+    let pattern = Regex::new(pattern).map_err(|e| e.to_string())?;
+    Ok(Step::Expect { pattern, timeout })
+}
+
+/// Parses `text[:timeout_secs]`, matching the text literally.
+pub fn parse_expect_text(s: &str) -> Result<Step, String> {
+    let (text, timeout) = split_timeout(s);
+    let text = String::from_utf8(unescape(text)?).map_err(|e| e.to_string())?;
+    let pattern = Regex::new(&regex::escape(&text)).map_err(|e| e.to_string())?;
+    Ok(Step::Expect { pattern, timeout })
+}
+
+pub fn parse_sleep(s: &str) -> Result<Step, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 => Ok(Step::Sleep(Duration::from_secs_f64(secs))),
+        _ => Err(format!("expected seconds, got {:?}", s)),
+    }
+}
+
+/// Parses a script file: one `COMMAND ARGUMENT` step per line, where the
+/// command is `send`, `send-hex`, `expect`, `expect-text` or `sleep` and
+/// the argument is written as for the matching option. Blank lines and
+/// lines starting with `#` are skipped.
+pub fn parse_file(text: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let step = match command {
+            "send" => parse_send(arg),
+            "send-hex" => parse_send_hex(arg),
+            "expect" => parse_expect(arg),
+            "expect-text" => parse_expect_text(arg),
+            "sleep" => parse_sleep(arg.trim()),
+            _ => Err(format!("unknown command {:?}", command)),
+        };
+        steps.push(step.map_err(|e| format!("line {}: {}", i + 1, e))?);
+    }
+    Ok(steps)
+}
+
+/// Reads whatever the pipe has into `window`, mirroring it to `output`;
+/// sleeps a little when there is nothing.
+fn pump<F>(pipe: &dyn Transport, output: &mut F, window: &mut String) -> Result<(), ScriptError>
+where
+    F: FnMut(&[u8]) -> Result<(), std::io::Error>,
+{
+    let mut buffer: Vec<u8> = Vec::new();
+    if pipe.read(&mut buffer)? == 0 {
+        thread::sleep(POLL_INTERVAL);
+        return Ok(());
+    }
+    output(&buffer)?;
+    window.push_str(&String::from_utf8_lossy(&buffer));
+    if window.len() > MATCH_WINDOW {
+        let mut cut = window.len() - MATCH_WINDOW;
+        while !window.is_char_boundary(cut) {
+            cut += 1;
+        }
+        window.drain(..cut);
+    }
+    Ok(())
+}
+
+/// Hands what the guest prints after the last step to `output`, until it
+/// has been quiet for a moment or the drain has run for too long.
+pub fn drain<F>(pipe: &dyn Transport, mut output: F) -> Result<(), ScriptError>
+where
+    F: FnMut(&[u8]) -> Result<(), std::io::Error>,
+{
+    let deadline = Instant::now() + DRAIN_LIMIT;
+    let mut last = Instant::now();
+    while Instant::now() < deadline && last.elapsed() < DRAIN_IDLE {
+        let mut buffer: Vec<u8> = Vec::new();
+        if pipe.read(&mut buffer)? == 0 {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        output(&buffer)?;
+        last = Instant::now();
+    }
+    Ok(())
+}
+
+/// Runs `steps` in order against the pipe. Everything read from the pipe
+/// is handed to `output` so the session is still mirrored.
+pub fn run<F>(steps: &[Step], pipe: &dyn Transport, mut output: F) -> Result<(), ScriptError>
+where
+    F: FnMut(&[u8]) -> Result<(), std::io::Error>,
+{
+    let mut window = String::new();
+    for step in steps {
+        match step {
+            Step::Send(bytes) => pipe.write_all(bytes)?,
+            Step::Expect { pattern, timeout } => {
+                let deadline = Instant::now() + *timeout;
+                loop {
+                    if let Some(m) = pattern.find(&window) {
+                        info!("Matched {:?}", pattern.as_str());
+                        summary::matched(pattern.as_str());
+                        window.drain(..m.end());
+                        break;
+                    }
+                    if Instant::now() >= deadline {
+                        warn!("Timed out expecting {:?}", pattern.as_str());
+                        return Err(ScriptError::Timeout(pattern.as_str().to_string()));
+                    }
+                    pump(pipe, &mut output, &mut window)?;
+                }
+            }
+            Step::Sleep(duration) => {
+                let deadline = Instant::now() + *duration;
+                while Instant::now() < deadline {
+                    pump(pipe, &mut output, &mut window)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
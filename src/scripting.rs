@@ -0,0 +1,145 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use log::info;
+use log::warn;
+use rhai::CallFnOptions;
+use rhai::Dynamic;
+use rhai::Engine;
+use rhai::Map;
+use rhai::Scope;
+use rhai::AST;
+
+use crate::event::Event;
+use crate::pipeline::Direction;
+use crate::pipeline::Filter;
+use crate::pipeline::Pipeline;
+
+/// A rhai script loaded with --rhai. It may define any of:
+/// - `on_connect()`, run whenever the pipe (re)connects;
+/// - `on_output(text)`, run for every chunk of pipe output;
+/// - `on_input(text)`, run for every chunk of input before it is sent.
+///
+/// The text handlers replace the chunk when they return a string and leave
+/// it alone otherwise. `send(text)` queues bytes for the pipe and `log(text)`
+/// writes to the log. `this` is a map kept across calls for state.
+struct Host {
+    engine: Engine,
+    ast: AST,
+    state: Dynamic,
+}
+
+/// Most operations one call into the script may run, so a runaway loop
+/// fails the call instead of hanging the session.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+static HOST: OnceLock<Arc<Mutex<Host>>> = OnceLock::new();
+static SENDS: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+/// Set while `SENDS` holds bytes, to wake the input thread.
+static SENT: OnceLock<Event> = OnceLock::new();
+
+impl Host {
+    fn defines(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+
+    /// Calls `name` if the script defines it; returns its result if that
+    /// is a string.
+    fn call(&mut self, name: &str, args: Vec<Dynamic>) -> Option<String> {
+        if !self.defines(name, args.len()) {
+            return None;
+        }
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let mut scope = Scope::new();
+        let ret = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut scope, &self.ast, name, args);
+        match ret {
+            Ok(ret) => ret.into_string().ok(),
+            Err(e) => {
+                warn!("Script {} failed: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+/// Compiles and runs the top level of the script at `path`.
+pub fn load(path: &Path) -> Result<(), String> {
+    let sent = Event::new().map_err(|e| e.to_string())?;
+    let _ = SENT.set(sent);
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.register_fn("send", |text: &str| {
+        SENDS.lock().unwrap().extend_from_slice(text.as_bytes());
+        if let Some(sent) = SENT.get() {
+            sent.set();
+        }
+    });
+    engine.register_fn("log", |text: &str| info!("script: {}", text));
+
+    let ast = engine
+        .compile_file(path.to_path_buf())
+        .map_err(|e| e.to_string())?;
+    engine.run_ast(&ast).map_err(|e| e.to_string())?;
+    info!("Loaded script {:?}", path);
+    let _ = HOST.set(Arc::new(Mutex::new(Host {
+        engine,
+        ast,
+        state: Dynamic::from_map(Map::new()),
+    })));
+    Ok(())
+}
+
+/// Takes the bytes the script has queued with `send`.
+pub fn take_sends() -> Vec<u8> {
+    let mut sends = SENDS.lock().unwrap();
+    if let Some(sent) = SENT.get() {
+        sent.reset();
+    }
+    std::mem::take(&mut sends)
+}
+
+/// Set when the script queues bytes, until they are taken; `None` without
+/// a script.
+pub fn sent_event() -> Option<&'static Event> {
+    SENT.get()
+}
+
+pub fn on_connect() {
+    if let Some(host) = HOST.get() {
+        host.lock().unwrap().call("on_connect", Vec::new());
+    }
+}
+
+struct ScriptFilter(Arc<Mutex<Host>>);
+
+impl Filter for ScriptFilter {
+    fn apply(&mut self, direction: Direction, buf: &[u8]) -> Vec<u8> {
+        let name = match direction {
+            Direction::ToPipe => "on_input",
+            Direction::FromPipe => "on_output",
+        };
+        let text = String::from_utf8_lossy(buf).into_owned();
+        match self.0.lock().unwrap().call(name, vec![Dynamic::from(text)]) {
+            Some(replaced) => replaced.into_bytes(),
+            None => buf.to_vec(),
+        }
+    }
+}
+
+/// Appends the script's handlers, if a script is loaded, to `pipeline`.
+pub fn attach(pipeline: &mut Pipeline) {
+    if let Some(host) = HOST.get() {
+        pipeline.push(Box::new(ScriptFilter(Arc::clone(host))));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+
+use crate::transport::Transport;
+
+/// Size of the writes between two progress reports without a line delay.
+const PROGRESS_CHUNK: usize = 16 * 1024;
+
+/// How fast a file is sent, for targets such as bootloader consoles that
+/// drop input arriving faster than they handle it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pacing {
+    /// Pause after every byte.
+    pub char_delay: Option<Duration>,
+    /// Pause after every line.
+    pub line_delay: Option<Duration>,
+}
+
+impl Pacing {
+    fn is_paced(&self) -> bool {
+        self.char_delay.is_some() || self.line_delay.is_some()
+    }
+}
+
+/// Streams the content of `path` into the pipe, paced by `pacing`. Paced
+/// sends go line by line, so `progress` is told the bytes sent so far and
+/// the total at least once per line, and after every byte with a char
+/// delay; it returns false to stop the send, which then fails with
+/// `Interrupted`. Every write is checked for the byte count the pipe took.
+/// Returns the bytes sent.
+pub fn send_file<F>(
+    pipe: &dyn Transport,
+    path: &Path,
+    pacing: Pacing,
+    mut progress: F,
+) -> Result<usize, std::io::Error>
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let content = std::fs::read(path)?;
+    info!("Sending {} bytes from {:?}", content.len(), path);
+
+    let chunks: Box<dyn Iterator<Item = &[u8]>> = if pacing.is_paced() {
+        Box::new(content.split_inclusive(|b| *b == b'\n'))
+    } else {
+        Box::new(content.chunks(PROGRESS_CHUNK))
+    };
+    let aborted = |sent: usize| {
+        info!("Stopped sending {:?} after {} bytes", path, sent);
+        std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            format!("stopped after {} bytes", sent),
+        )
+    };
+    let mut sent = 0;
+    for chunk in chunks {
+        match pacing.char_delay {
+            Some(delay) => {
+                for byte in chunk {
+                    pipe.write_all(std::slice::from_ref(byte))?;
+                    sent += 1;
+                    if !progress(sent, content.len()) {
+                        return Err(aborted(sent));
+                    }
+                    thread::sleep(delay);
+                }
+            }
+            None => {
+                pipe.write_all(chunk)?;
+                sent += chunk.len();
+            }
+        }
+        if !progress(sent, content.len()) {
+            return Err(aborted(sent));
+        }
+        if let Some(delay) = pacing.line_delay {
+            thread::sleep(delay);
+        }
+    }
+
+    info!("Sent {} bytes from {:?}", sent, path);
+    Ok(sent)
+}
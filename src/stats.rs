@@ -0,0 +1,145 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+/// Power of two buckets: bucket `i` counts values in `[2^(i-1), 2^i)`,
+/// bucket 0 counts zeros.
+const BUCKETS: usize = 40;
+const BAR_WIDTH: u64 = 40;
+
+/// How `--stats-at-exit` prints the statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// histograms drawn as text
+    #[default]
+    Text,
+    /// a single JSON object
+    Json,
+}
+
+#[derive(Clone, Copy)]
+struct Histogram {
+    counts: [u64; BUCKETS],
+    total: u64,
+    sum: u64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            counts: [0; BUCKETS],
+            total: 0,
+            sum: 0,
+        }
+    }
+
+    fn add(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.total += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Lower bound of `bucket`.
+    fn low(bucket: usize) -> u64 {
+        match bucket {
+            0 => 0,
+            b => 1 << (b - 1),
+        }
+    }
+
+    fn text(&self, title: &str, unit: &str) -> String {
+        let mut s = format!("{} ({}), {} samples", title, unit, self.total);
+        if self.total > 0 {
+            s.push_str(&format!(", mean {}", self.sum / self.total));
+        }
+        s.push('\n');
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1);
+        for (b, count) in self.counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let bar = "#".repeat((count * BAR_WIDTH).div_ceil(max) as usize);
+            s.push_str(&format!(
+                "  >= {:>10} {:>10} {}\n",
+                Self::low(b),
+                count,
+                bar
+            ));
+        }
+        s
+    }
+
+    fn json(&self) -> String {
+        let buckets: Vec<String> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(b, c)| format!("{{\"ge\":{},\"count\":{}}}", Self::low(b), c))
+            .collect();
+        format!(
+            "{{\"samples\":{},\"sum\":{},\"buckets\":[{}]}}",
+            self.total,
+            self.sum,
+            buckets.join(",")
+        )
+    }
+}
+
+/// Sizes (bytes) and latencies (microseconds) of pipe reads and writes.
+struct Stats {
+    read_size: Histogram,
+    read_latency: Histogram,
+    write_size: Histogram,
+    write_latency: Histogram,
+}
+
+static STATS: Mutex<Stats> = Mutex::new(Stats {
+    read_size: Histogram::new(),
+    read_latency: Histogram::new(),
+    write_size: Histogram::new(),
+    write_latency: Histogram::new(),
+});
+
+/// Records a pipe read that returned data.
+pub fn record_read(bytes: usize, latency: Duration) {
+    let mut stats = STATS.lock().unwrap();
+    stats.read_size.add(bytes as u64);
+    stats.read_latency.add(latency.as_micros() as u64);
+}
+
+/// Records a single WriteFile to the pipe.
+pub fn record_write(bytes: usize, latency: Duration) {
+    let mut stats = STATS.lock().unwrap();
+    stats.write_size.add(bytes as u64);
+    stats.write_latency.add(latency.as_micros() as u64);
+}
+
+/// Total bytes (read, written) so far.
+pub fn totals() -> (u64, u64) {
+    let stats = STATS.lock().unwrap();
+    (stats.read_size.sum, stats.write_size.sum)
+}
+
+/// The statistics gathered so far, as text with `\n` line ends or JSON.
+pub fn dump(format: Format) -> String {
+    let stats = STATS.lock().unwrap();
+    match format {
+        Format::Text => [
+            stats.read_size.text("read size", "bytes"),
+            stats.read_latency.text("read latency", "us"),
+            stats.write_size.text("write size", "bytes"),
+            stats.write_latency.text("write latency", "us"),
+        ]
+        .concat(),
+        Format::Json => format!(
+            "{{\"read_size\":{},\"read_latency_us\":{},\"write_size\":{},\"write_latency_us\":{}}}\n",
+            stats.read_size.json(),
+            stats.read_latency.json(),
+            stats.write_size.json(),
+            stats.write_latency.json()
+        ),
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::error;
+
+use crate::stats;
+
+struct Connection {
+    connected_ms: u128,
+    disconnected_ms: Option<u128>,
+    reason: Option<String>,
+}
+
+struct Summary {
+    file: PathBuf,
+    pipe: String,
+    started_ms: u128,
+    connections: Vec<Connection>,
+    matched: Vec<String>,
+}
+
+static SUMMARY: OnceLock<Mutex<Summary>> = OnceLock::new();
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Starts collecting the summary of the session on `pipe`, written to
+/// `file` by `finish`. Until this is called the other functions do nothing.
+pub fn open(file: &Path, pipe: &str) {
+    let _ = SUMMARY.set(Mutex::new(Summary {
+        file: file.to_path_buf(),
+        pipe: pipe.to_string(),
+        started_ms: now_ms(),
+        connections: Vec::new(),
+        matched: Vec::new(),
+    }));
+}
+
+pub fn connected() {
+    if let Some(summary) = SUMMARY.get() {
+        summary.lock().unwrap().connections.push(Connection {
+            connected_ms: now_ms(),
+            disconnected_ms: None,
+            reason: None,
+        });
+    }
+}
+
+pub fn disconnected(reason: &str) {
+    if let Some(summary) = SUMMARY.get() {
+        if let Some(c) = summary.lock().unwrap().connections.last_mut() {
+            c.disconnected_ms = Some(now_ms());
+            c.reason = Some(reason.to_string());
+        }
+    }
+}
+
+/// Records an --expect pattern that matched.
+pub fn matched(pattern: &str) {
+    if let Some(summary) = SUMMARY.get() {
+        summary.lock().unwrap().matched.push(pattern.to_string());
+    }
+}
+
+/// Writes the summary file with the final exit `reason` and `exit_code`.
+pub fn finish(reason: &str, exit_code: i32) {
+    let Some(summary) = SUMMARY.get() else {
+        return;
+    };
+    let summary = summary.lock().unwrap();
+    let (bytes_from_pipe, bytes_to_pipe) = stats::totals();
+    let connections: Vec<String> = summary
+        .connections
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"connected_ms\":{},\"disconnected_ms\":{},\"reason\":{}}}",
+                c.connected_ms,
+                c.disconnected_ms
+                    .map_or("null".to_string(), |t| t.to_string()),
+                c.reason.as_deref().map_or("null".to_string(), json_string)
+            )
+        })
+        .collect();
+    let matched: Vec<String> = summary.matched.iter().map(|m| json_string(m)).collect();
+    let json = format!(
+        "{{\"pipe\":{},\"started_ms\":{},\"ended_ms\":{},\"connections\":[{}],\"reconnects\":{},\"bytes_from_pipe\":{},\"bytes_to_pipe\":{},\"matched\":[{}],\"exit_reason\":{},\"exit_code\":{}}}\n",
+        json_string(&summary.pipe),
+        summary.started_ms,
+        now_ms(),
+        connections.join(","),
+        summary.connections.len().saturating_sub(1),
+        bytes_from_pipe,
+        bytes_to_pipe,
+        matched.join(","),
+        json_string(reason),
+        exit_code
+    );
+    if let Err(e) = std::fs::write(&summary.file, json) {
+        error!("Failed to write summary {:?}: {:?}", summary.file, e);
+    }
+}
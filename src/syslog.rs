@@ -0,0 +1,174 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use log::warn;
+use log::Level;
+use log::Record;
+use log4rs::append::Append;
+
+/// Facility `user`, the severity is added per record.
+const FACILITY_USER: u8 = 1;
+
+/// Where syslog messages go: `udp://host:port` or `tcp://host:port`.
+#[derive(Clone, Debug)]
+pub enum SyslogTarget {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (proto, addr) = s.split_once("://").unwrap_or(("udp", s));
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("{}: {}", addr, e))?
+            .next()
+            .ok_or_else(|| format!("{}: no address", addr))?;
+        match proto {
+            "udp" => Ok(SyslogTarget::Udp(addr)),
+            "tcp" => Ok(SyslogTarget::Tcp(addr)),
+            _ => Err(format!("unknown syslog protocol {:?}", proto)),
+        }
+    }
+}
+
+/// Most messages queued for the writer thread; past this they are dropped
+/// rather than holding up the thread that logs.
+const QUEUE_SIZE: usize = 1024;
+
+/// Wait before trying a collector that can't be reached again; what is
+/// logged meanwhile is dropped.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Sink {
+    Udp(UdpSocket, SocketAddr),
+    Tcp(TcpStream),
+}
+
+impl Sink {
+    fn open(target: &SyslogTarget) -> Result<Self, std::io::Error> {
+        Ok(match target {
+            SyslogTarget::Udp(addr) => {
+                let bind = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                Sink::Udp(UdpSocket::bind(bind)?, *addr)
+            }
+            SyslogTarget::Tcp(addr) => Sink::Tcp(TcpStream::connect(addr)?),
+        })
+    }
+
+    fn send(&mut self, msg: &str) -> Result<(), std::io::Error> {
+        match self {
+            Sink::Udp(socket, addr) => {
+                socket.send_to(msg.as_bytes(), *addr)?;
+            }
+            // Octet counting framing (RFC 6587).
+            Sink::Tcp(stream) => {
+                let framed = format!("{} {}", msg.len(), msg);
+                stream.write_all(framed.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends every record as an RFC 5424 message to a syslog collector. The
+/// messages are sent from a thread of their own that connects, and
+/// reconnects after a failure, so a collector that is down or slow
+/// neither stops winpipe nor stalls it.
+#[derive(Debug)]
+pub struct SyslogAppender {
+    queue: SyncSender<String>,
+    hostname: String,
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Failures are logged once per outage: the warning itself goes to syslog
+/// too, and is dropped while it is down.
+fn write_loop(target: &SyslogTarget, queue: Receiver<String>) {
+    let mut sink: Option<Sink> = None;
+    // Set while the collector is down, to when it is tried again.
+    let mut retry_at: Option<Instant> = None;
+    for msg in queue {
+        if sink.is_none() {
+            if retry_at.is_some_and(|at| Instant::now() < at) {
+                continue;
+            }
+            match Sink::open(target) {
+                Ok(opened) => {
+                    if retry_at.take().is_some() {
+                        info!("Reconnected to syslog {:?}", target);
+                    }
+                    sink = Some(opened);
+                }
+                Err(e) => {
+                    if retry_at.is_none() {
+                        warn!("Failed to connect to syslog {:?}: {:?}", target, e);
+                    }
+                    retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                    continue;
+                }
+            }
+        }
+        if let Some(Err(e)) = sink.as_mut().map(|sink| sink.send(&msg)) {
+            warn!("Lost syslog {:?}: {:?}", target, e);
+            sink = None;
+            retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        }
+    }
+}
+
+impl SyslogAppender {
+    pub fn new(target: &SyslogTarget) -> Self {
+        let (queue, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let target = target.clone();
+        thread::spawn(move || write_loop(&target, rx));
+        Self {
+            queue,
+            hostname: std::env::var("COMPUTERNAME").unwrap_or_else(|_| "-".to_string()),
+        }
+    }
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let msg = format!(
+            "<{}>1 - {} winpipe {} - - {}",
+            FACILITY_USER * 8 + severity(record.level()),
+            self.hostname,
+            std::process::id(),
+            record.args()
+        );
+        let _ = self.queue.try_send(msg);
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,219 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use log::error;
+use log::info;
+use log::warn;
+
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+
+use crate::audit;
+use crate::errors;
+use crate::iocp::Reactor;
+use crate::named_pipe::NamedPipe;
+use crate::named_pipe::PipeConfig;
+use crate::owner;
+
+/// Reads the pipe until it disconnects, handing every chunk to `sink`.
+fn pump_pipe<F>(pipe: &NamedPipe, mut sink: F) -> Result<(), std::io::Error>
+where
+    F: FnMut(&[u8]) -> Result<(), std::io::Error>,
+{
+    loop {
+        let mut buffer: Vec<u8> = Vec::new();
+        match pipe.read_wait(&mut buffer, None) {
+            // An empty message.
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(e)
+                if e.code() == ERROR_PIPE_NOT_CONNECTED.into()
+                    || e.code() == ERROR_BROKEN_PIPE.into() =>
+            {
+                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
+                break Ok(());
+            }
+            Err(e) => {
+                error!("Failed to read from pipe: {:?}", e);
+                break Err(e.into());
+            }
+        }
+
+        sink(buffer.as_slice())?;
+    }
+}
+
+fn stream_to_pipe(mut stream: TcpStream, pipe: NamedPipe) -> Result<(), std::io::Error> {
+    let mut buffer: Vec<u8> = vec![0u8; 4096];
+    loop {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break Ok(());
+        }
+        pipe.write_all(&buffer[..n])?;
+    }
+}
+
+/// Opens the pipe, checking who serves it when `expect_owner` is set.
+fn open_pipe(
+    path: &str,
+    config: &PipeConfig,
+    expect_owner: Option<&str>,
+) -> Result<NamedPipe, std::io::Error> {
+    let pipe = NamedPipe::try_open(path, config)?;
+    if let Some(expected) = expect_owner {
+        owner::verify(&pipe, expected)?;
+    }
+    Ok(pipe)
+}
+
+/// Bridges one client to its own pipe instance.
+fn serve_client(
+    stream: TcpStream,
+    path: &str,
+    config: &PipeConfig,
+    expect_owner: Option<&str>,
+    reactor: Option<&Reactor>,
+) -> Result<(), std::io::Error> {
+    let pipe = open_pipe(path, config, expect_owner)?;
+
+    // With the completion port backend the pipe side is read by the worker
+    // pool and this thread only carries client input.
+    if let Some(reactor) = reactor {
+        let mut writer = stream.try_clone()?;
+        let closer = stream.try_clone()?;
+        reactor.register(
+            pipe.clone(),
+            move |buf| writer.write_all(buf).is_ok(),
+            move |e| {
+                if let Some(e) = e {
+                    warn!("Pipe read ended: {:?}", e);
+                }
+                let _ = closer.shutdown(Shutdown::Both);
+            },
+        )?;
+        return stream_to_pipe(stream, pipe);
+    }
+
+    let reader = stream.try_clone()?;
+    let pipe_w = pipe.clone();
+    let th_stream_to_pipe = thread::spawn(move || {
+        if let Err(e) = stream_to_pipe(reader, pipe_w) {
+            warn!("Error in stream_to_pipe: {:?}", e);
+        }
+    });
+
+    let mut writer = stream.try_clone()?;
+    let ret = pump_pipe(&pipe, |buf| writer.write_all(buf));
+    let _ = stream.shutdown(Shutdown::Both);
+    th_stream_to_pipe.join().unwrap();
+    ret
+}
+
+/// Accepts TCP clients on `addr` and connects them to the pipe at `path`.
+/// Each client gets its own pipe instance unless `shared` is set, in which
+/// case one connection is opened up front, its output is broadcast to all
+/// clients and input from any client is written to it. Pipes not served
+/// by `expect_owner` are refused. A `reactor` moves
+/// the per-client pipe reads onto its completion port worker pool.
+pub fn listen(
+    addr: SocketAddr,
+    path: &str,
+    config: PipeConfig,
+    shared: bool,
+    expect_owner: Option<String>,
+    reactor: Option<Arc<Reactor>>,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(addr)?;
+    info!("TCP listening on {}", listener.local_addr()?);
+
+    if !shared {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            let path = path.to_string();
+            let expect_owner = expect_owner.clone();
+            let reactor = reactor.clone();
+            info!("TCP client connected: {}", peer);
+            audit::record("connect", &path, &format!("tcp:{}", peer), "accepted");
+            thread::spawn(move || {
+                let ret = serve_client(
+                    stream,
+                    &path,
+                    &config,
+                    expect_owner.as_deref(),
+                    reactor.as_deref(),
+                );
+                let reason = match &ret {
+                    Ok(_) => "client closed".to_string(),
+                    Err(e) => format!("error: {:?}", e),
+                };
+                if let Err(e) = ret {
+                    errors::report_io(&format!("Error serving {}", peer), &e, &path);
+                }
+                info!("TCP client disconnected: {}", peer);
+                audit::record("disconnect", &path, &format!("tcp:{}", peer), &reason);
+            });
+        }
+        return Ok(());
+    }
+
+    let pipe = open_pipe(path, &config, expect_owner.as_deref())?;
+    let clients: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let pipe_r = pipe.clone();
+    let path_r = path.to_string();
+    let clients_r = Arc::clone(&clients);
+    thread::spawn(move || {
+        let ret = pump_pipe(&pipe_r, |buf| {
+            let mut clients = clients_r.lock().unwrap();
+            clients.retain_mut(|c| c.write_all(buf).is_ok());
+            Ok(())
+        });
+        let reason = match &ret {
+            Ok(_) => "pipe disconnected".to_string(),
+            Err(e) => format!("error: {:?}", e),
+        };
+        if let Err(e) = ret {
+            error!("Error in pipe_to_clients: {:?}", e);
+        }
+        audit::record("disconnect", &path_r, "tcp:*", &reason);
+        for c in clients_r.lock().unwrap().drain(..) {
+            let _ = c.shutdown(Shutdown::Both);
+        }
+        std::process::exit(0);
+    });
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        info!("TCP client connected: {}", peer);
+        audit::record("connect", path, &format!("tcp:{}", peer), "accepted");
+        clients.lock().unwrap().push(stream.try_clone()?);
+
+        let pipe_w = pipe.clone();
+        let path = path.to_string();
+        thread::spawn(move || {
+            let reason = match stream_to_pipe(stream, pipe_w) {
+                Ok(_) => "client closed".to_string(),
+                Err(e) => {
+                    warn!("Error in stream_to_pipe: {:?}", e);
+                    format!("error: {:?}", e)
+                }
+            };
+            info!("TCP client disconnected: {}", peer);
+            audit::record("disconnect", &path, &format!("tcp:{}", peer), &reason);
+        });
+    }
+    Ok(())
+}
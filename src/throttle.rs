@@ -0,0 +1,186 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::Level;
+use log::Log;
+use log::Metadata;
+use log::Record;
+
+/// Period the per-message limit applies to.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Upper bound of distinct messages tracked at once.
+const MAX_TRACKED: usize = 1024;
+
+/// Messages that only differ in numbers (counters, handles, addresses)
+/// are treated as the same message.
+fn similar_key(record: &Record) -> (Level, String) {
+    let text: String = record
+        .args()
+        .to_string()
+        .chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .collect();
+    (record.level(), format!("{}: {}", record.target(), text))
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+    suppressed: u32,
+    target: String,
+    sample: String,
+}
+
+/// The last message passed on, for folding exact repeats.
+struct Last {
+    level: Level,
+    target: String,
+    text: String,
+    repeats: u32,
+}
+
+/// Passes records on to `inner`, but at most `limit` similar ones per
+/// second; the rest are counted and reported once their window is over.
+/// With `dedupe`, runs of identical messages are folded into a single
+/// "last message repeated N times" line.
+pub struct Throttle {
+    inner: Box<dyn Log>,
+    limit: Option<u32>,
+    dedupe: bool,
+    windows: Mutex<HashMap<(Level, String), Window>>,
+    last: Mutex<Option<Last>>,
+}
+
+impl Throttle {
+    pub fn new(inner: Box<dyn Log>, limit: Option<u32>, dedupe: bool) -> Self {
+        Self {
+            inner,
+            limit,
+            dedupe,
+            windows: Mutex::new(HashMap::new()),
+            last: Mutex::new(None),
+        }
+    }
+
+    fn report_repeats(&self, last: &Last) {
+        self.inner.log(
+            &Record::builder()
+                .level(last.level)
+                .target(&last.target)
+                .args(format_args!("last message repeated {} times", last.repeats))
+                .build(),
+        );
+    }
+
+    /// Whether `record` is new rather than a repeat of the last message.
+    fn is_new(&self, record: &Record) -> bool {
+        let text = record.args().to_string();
+        let mut last = self.last.lock().unwrap();
+        if let Some(l) = last.as_mut() {
+            if l.level == record.level() && l.target == record.target() && l.text == text {
+                l.repeats += 1;
+                return false;
+            }
+            if l.repeats > 0 {
+                self.report_repeats(l);
+            }
+        }
+        *last = Some(Last {
+            level: record.level(),
+            target: record.target().to_string(),
+            text,
+            repeats: 0,
+        });
+        true
+    }
+
+    fn report(&self, level: Level, w: &Window) {
+        self.inner.log(
+            &Record::builder()
+                .level(level)
+                .target(&w.target)
+                .args(format_args!(
+                    "suppressed {} similar messages: {}",
+                    w.suppressed, w.sample
+                ))
+                .build(),
+        );
+    }
+
+    /// Whether `record` should be logged under the rate limit.
+    fn admit(&self, record: &Record, limit: u32) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        // Report and forget windows that are over.
+        let expired: Vec<(Level, String)> = windows
+            .iter()
+            .filter(|(_, w)| now - w.start >= RATE_WINDOW)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            if let Some(w) = windows.remove(&key) {
+                if w.suppressed > 0 {
+                    self.report(key.0, &w);
+                }
+            }
+        }
+
+        let key = similar_key(record);
+        if !windows.contains_key(&key) && windows.len() >= MAX_TRACKED {
+            return true;
+        }
+        let w = windows.entry(key).or_insert_with(|| Window {
+            start: now,
+            count: 0,
+            suppressed: 0,
+            target: record.target().to_string(),
+            sample: record.args().to_string(),
+        });
+        w.count += 1;
+        if w.count > limit {
+            w.suppressed += 1;
+            return false;
+        }
+        true
+    }
+}
+
+impl Log for Throttle {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.dedupe && !self.is_new(record) {
+            return;
+        }
+        if let Some(limit) = self.limit {
+            if !self.admit(record, limit) {
+                return;
+            }
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        if let Ok(mut last) = self.last.lock() {
+            if let Some(l) = last.take().filter(|l| l.repeats > 0) {
+                self.report_repeats(&l);
+            }
+        }
+        if let Ok(mut windows) = self.windows.lock() {
+            for (key, w) in windows.drain() {
+                if w.suppressed > 0 {
+                    self.report(key.0, &w);
+                }
+            }
+        }
+        self.inner.flush();
+    }
+}
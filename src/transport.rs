@@ -0,0 +1,347 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::c_void;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::mem::size_of;
+use std::net::TcpStream;
+use std::os::windows::io::AsRawSocket;
+use std::os::windows::io::FromRawSocket;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::info;
+
+use windows::core::GUID;
+use windows::core::HRESULT;
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_GEN_FAILURE;
+use windows::Win32::Foundation::ERROR_NETNAME_DELETED;
+use windows::Win32::Foundation::ERROR_OPERATION_ABORTED;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Networking::WinSock::closesocket;
+use windows::Win32::Networking::WinSock::connect;
+use windows::Win32::Networking::WinSock::WSAGetLastError;
+use windows::Win32::Networking::WinSock::WSASocketW;
+use windows::Win32::Networking::WinSock::WSAStartup;
+use windows::Win32::Networking::WinSock::SOCKADDR;
+use windows::Win32::Networking::WinSock::SOCKET_ERROR;
+use windows::Win32::Networking::WinSock::SOCK_STREAM;
+use windows::Win32::Networking::WinSock::WSADATA;
+use windows::Win32::Networking::WinSock::WSA_FLAG_NO_HANDLE_INHERIT;
+use windows::Win32::Networking::WinSock::WSA_FLAG_OVERLAPPED;
+use windows::Win32::System::IO::CancelIoEx;
+
+use crate::event::Event;
+use crate::named_pipe;
+use crate::named_pipe::NamedPipe;
+
+const READ_SIZE: usize = 4096;
+
+const AF_HYPERV: u16 = 34;
+const HV_PROTOCOL_RAW: i32 = 1;
+/// Connects to the partition that hosts this one.
+const HV_GUID_PARENT: GUID = GUID::from_u128(0xa42e7cda_d03f_480c_9cc2_a4de20abb878);
+/// Connects to a service of this same partition.
+const HV_GUID_LOOPBACK: GUID = GUID::from_u128(0xe0e16197_dd56_4a10_9195_5ee7a155a838);
+
+/// The byte stream a session is bridged to. Reads and writes may come
+/// from different threads at once.
+pub trait Transport: Send + Sync {
+    /// Reads what has already arrived into `buffer`, 0 when nothing has.
+    fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32>;
+
+    /// Waits for data and reads it; setting `wake` gives up and returns 0.
+    /// The peer going away fails with ERROR_BROKEN_PIPE or
+    /// ERROR_PIPE_NOT_CONNECTED.
+    fn read_wait(&self, buffer: &mut Vec<u8>, wake: Option<&Event>) -> windows::core::Result<u32>;
+
+    fn write_all(&self, buffer: &[u8]) -> windows::core::Result<()>;
+
+    /// Cancels the reads and writes pending on any thread; they fail with
+    /// ERROR_OPERATION_ABORTED.
+    fn cancel_io(&self);
+
+    /// The pipe underneath, for what only pipes support (owner checks).
+    fn as_named_pipe(&self) -> Option<&NamedPipe> {
+        None
+    }
+}
+
+impl Transport for NamedPipe {
+    fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        NamedPipe::read(self, buffer)
+    }
+
+    fn read_wait(&self, buffer: &mut Vec<u8>, wake: Option<&Event>) -> windows::core::Result<u32> {
+        NamedPipe::read_wait(self, buffer, wake)
+    }
+
+    fn write_all(&self, buffer: &[u8]) -> windows::core::Result<()> {
+        NamedPipe::write_all(self, buffer)
+    }
+
+    fn cancel_io(&self) {
+        unsafe {
+            let _ = CancelIoEx(self.as_handle(), None);
+        }
+    }
+
+    fn as_named_pipe(&self) -> Option<&NamedPipe> {
+        Some(self)
+    }
+}
+
+/// Where `--path` points: a named pipe (plain or `pipe://PATH`),
+/// `tcp://HOST:PORT` or `hvsock://VMID:SERVICE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Pipe(String),
+    Tcp(String),
+    HvSocket { vm_id: GUID, service_id: GUID },
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            None => Ok(Target::Pipe(s.to_string())),
+            Some(("pipe", path)) if !path.is_empty() => Ok(Target::Pipe(path.to_string())),
+            Some(("tcp", addr)) if addr.contains(':') => Ok(Target::Tcp(addr.to_string())),
+            Some(("hvsock", addr)) => match addr.split_once(':') {
+                Some((vm_id, service_id)) => Ok(Target::HvSocket {
+                    vm_id: parse_vm_id(vm_id)?,
+                    service_id: parse_service_id(service_id)?,
+                }),
+                None => Err(format!("expected hvsock://VMID:SERVICE, got {:?}", s)),
+            },
+            _ => Err(format!(
+                "expected a pipe path, pipe://PATH, tcp://HOST:PORT or hvsock://VMID:SERVICE, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses `00000000-0000-0000-0000-000000000000`, braces optional.
+fn parse_guid(s: &str) -> Result<GUID, String> {
+    let s = s.trim_start_matches('{').trim_end_matches('}');
+    let dashes = s.len() == 36 && [8, 13, 18, 23].iter().all(|i| s.as_bytes()[*i] == b'-');
+    let hex: String = s.chars().filter(|c| *c != '-').collect();
+    match u128::from_str_radix(&hex, 16) {
+        Ok(value) if dashes => Ok(GUID::from_u128(value)),
+        _ => Err(format!("invalid GUID {:?}", s)),
+    }
+}
+
+/// A VM id, or `parent` / `loopback`.
+fn parse_vm_id(s: &str) -> Result<GUID, String> {
+    match s {
+        "parent" => Ok(HV_GUID_PARENT),
+        "loopback" => Ok(HV_GUID_LOOPBACK),
+        _ => parse_guid(s),
+    }
+}
+
+/// A service GUID, or the port a Linux guest listens on with AF_VSOCK.
+fn parse_service_id(s: &str) -> Result<GUID, String> {
+    match s.parse::<u32>() {
+        Ok(port) => Ok(GUID::from_values(
+            port,
+            0xfacb,
+            0x11e6,
+            [0xbd, 0x58, 0x64, 0x00, 0x6a, 0x79, 0x86, 0xd3],
+        )),
+        Err(_) => parse_guid(s),
+    }
+}
+
+/// Socket errors are Win32 error codes, so they fit the pipe's error type.
+/// A connection that went away reads like a closed pipe.
+fn io_error(e: std::io::Error) -> windows::core::Error {
+    match e.kind() {
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+            ERROR_BROKEN_PIPE.to_hresult().into()
+        }
+        _ => {
+            let code = e.raw_os_error().unwrap_or(ERROR_GEN_FAILURE.0 as i32);
+            HRESULT::from_win32(code as u32).into()
+        }
+    }
+}
+
+/// A connected stream socket. It is read with overlapped ReadFile like
+/// the pipe, so a read waits on an event instead of polling.
+struct Socket {
+    stream: TcpStream,
+}
+
+impl Socket {
+    fn handle(&self) -> HANDLE {
+        HANDLE(self.stream.as_raw_socket() as *mut c_void)
+    }
+
+    fn read_wait(&self, buffer: &mut Vec<u8>, wake: Option<&Event>) -> windows::core::Result<u32> {
+        buffer.clear();
+        buffer.resize(READ_SIZE, 0);
+        let (n, ret) = named_pipe::read_overlapped(self.handle(), buffer, wake);
+        buffer.truncate(n as usize);
+        match ret {
+            // A read that completes empty is the peer closing.
+            Ok(_) if n == 0 => Err(ERROR_BROKEN_PIPE.to_hresult().into()),
+            Ok(_) => Ok(n),
+            Err(e) if e.code() == ERROR_OPERATION_ABORTED.into() => Ok(0),
+            Err(e) if e.code() == ERROR_NETNAME_DELETED.into() => {
+                Err(ERROR_BROKEN_PIPE.to_hresult().into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A read that is woken right away returns only what has arrived.
+    fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        let now = Event::new()?;
+        now.set();
+        self.read_wait(buffer, Some(&now))
+    }
+
+    fn write_all(&self, buffer: &[u8]) -> windows::core::Result<()> {
+        (&self.stream).write_all(buffer).map_err(io_error)
+    }
+
+    fn cancel_io(&self) {
+        unsafe {
+            let _ = CancelIoEx(self.handle(), None);
+        }
+    }
+}
+
+/// A TCP connection, e.g. a VM serial port exposed as a TCP server.
+pub struct TcpTransport {
+    socket: Socket,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str) -> windows::core::Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(io_error)?;
+        // Keystrokes go out as they are typed.
+        stream.set_nodelay(true).map_err(io_error)?;
+        info!("Connected to tcp://{}", addr);
+        Ok(Self {
+            socket: Socket { stream },
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        self.socket.read(buffer)
+    }
+
+    fn read_wait(&self, buffer: &mut Vec<u8>, wake: Option<&Event>) -> windows::core::Result<u32> {
+        self.socket.read_wait(buffer, wake)
+    }
+
+    fn write_all(&self, buffer: &[u8]) -> windows::core::Result<()> {
+        self.socket.write_all(buffer)
+    }
+
+    fn cancel_io(&self) {
+        self.socket.cancel_io();
+    }
+}
+
+#[repr(C)]
+struct SockaddrHv {
+    family: u16,
+    reserved: u16,
+    vm_id: GUID,
+    service_id: GUID,
+}
+
+/// A Hyper-V socket (AF_HYPERV) to a service in a VM, such as a Linux
+/// guest's console on an AF_VSOCK port.
+pub struct HvSocketTransport {
+    socket: Socket,
+}
+
+impl HvSocketTransport {
+    pub fn connect(vm_id: GUID, service_id: GUID) -> windows::core::Result<Self> {
+        let mut data = WSADATA::default();
+        let ret = unsafe { WSAStartup(0x202, &mut data) };
+        if ret != 0 {
+            return Err(HRESULT::from_win32(ret as u32).into());
+        }
+        let socket = unsafe {
+            WSASocketW(
+                AF_HYPERV as i32,
+                SOCK_STREAM.0,
+                HV_PROTOCOL_RAW,
+                None,
+                0,
+                WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT,
+            )?
+        };
+        let addr = SockaddrHv {
+            family: AF_HYPERV,
+            reserved: 0,
+            vm_id,
+            service_id,
+        };
+        let ret = unsafe {
+            connect(
+                socket,
+                &addr as *const SockaddrHv as *const SOCKADDR,
+                size_of::<SockaddrHv>() as i32,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            let e = unsafe { WSAGetLastError() };
+            unsafe {
+                let _ = closesocket(socket);
+            }
+            return Err(HRESULT::from_win32(e.0 as u32).into());
+        }
+        info!("Connected to hvsock://{:?}:{:?}", vm_id, service_id);
+        // The socket is a plain stream socket from here on; std owns and
+        // closes it.
+        let stream = unsafe { TcpStream::from_raw_socket(socket.0 as u64) };
+        Ok(Self {
+            socket: Socket { stream },
+        })
+    }
+}
+
+impl Transport for HvSocketTransport {
+    fn read(&self, buffer: &mut Vec<u8>) -> windows::core::Result<u32> {
+        self.socket.read(buffer)
+    }
+
+    fn read_wait(&self, buffer: &mut Vec<u8>, wake: Option<&Event>) -> windows::core::Result<u32> {
+        self.socket.read_wait(buffer, wake)
+    }
+
+    fn write_all(&self, buffer: &[u8]) -> windows::core::Result<()> {
+        self.socket.write_all(buffer)
+    }
+
+    fn cancel_io(&self) {
+        self.socket.cancel_io();
+    }
+}
+
+/// Connects to `target`; `config` applies to pipes.
+pub fn connect(
+    target: &Target,
+    config: &named_pipe::PipeConfig,
+) -> windows::core::Result<Arc<dyn Transport>> {
+    match target {
+        Target::Pipe(path) => Ok(Arc::new(NamedPipe::try_open(path, config)?)),
+        Target::Tcp(addr) => Ok(Arc::new(TcpTransport::connect(addr)?)),
+        Target::HvSocket { vm_id, service_id } => {
+            Ok(Arc::new(HvSocketTransport::connect(*vm_id, *service_id)?))
+        }
+    }
+}
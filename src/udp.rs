@@ -0,0 +1,113 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+use log::info;
+use log::warn;
+
+use windows::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows::Win32::Foundation::ERROR_PIPE_NOT_CONNECTED;
+
+use crate::named_pipe::NamedPipe;
+
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn pipe_to_udp(
+    pipe: NamedPipe,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    max_packet: usize,
+) -> Result<(), std::io::Error> {
+    loop {
+        let mut buffer: Vec<u8> = Vec::new();
+        match pipe.read_wait(&mut buffer, None) {
+            // An empty message.
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(e)
+                if e.code() == ERROR_PIPE_NOT_CONNECTED.into()
+                    || e.code() == ERROR_BROKEN_PIPE.into() =>
+            {
+                warn!("Pipe disconnected: {:?}, hresult={}", e.message(), e.code());
+                break Ok(());
+            }
+            Err(e) => {
+                error!("Failed to read from pipe: {:?}", e);
+                break Err(e.into());
+            }
+        }
+
+        for packet in buffer.chunks(max_packet) {
+            socket.send_to(packet, peer)?;
+        }
+    }
+}
+
+fn udp_to_pipe(
+    pipe: NamedPipe,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    max_packet: usize,
+    stop: &AtomicBool,
+) -> Result<(), std::io::Error> {
+    let mut buffer: Vec<u8> = vec![0u8; max_packet];
+    while !stop.load(Ordering::Relaxed) {
+        let (n, from) = match socket.recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to receive datagram: {:?}", e);
+                return Err(e);
+            }
+        };
+        if from.ip() != peer.ip() {
+            warn!("Dropped {} bytes from unexpected peer {}", n, from);
+            continue;
+        }
+
+        pipe.write_all(&buffer[..n])?;
+    }
+    Ok(())
+}
+
+/// Relays pipe reads to `peer` as datagrams of at most `max_packet` bytes
+/// and injects datagrams received from `peer` into the pipe.
+pub fn bridge(
+    pipe: NamedPipe,
+    bind: SocketAddr,
+    peer: SocketAddr,
+    max_packet: usize,
+) -> Result<(), std::io::Error> {
+    let socket = Arc::new(UdpSocket::bind(bind)?);
+    socket.set_read_timeout(Some(RECV_POLL_INTERVAL))?;
+    info!("UDP bridge: {} <-> {}", socket.local_addr()?, peer);
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let pipe_r = pipe.clone();
+    let socket_r = Arc::clone(&socket);
+    let stop_r = Arc::clone(&stop);
+    let th_udp_to_pipe = thread::spawn(move || {
+        if let Err(e) = udp_to_pipe(pipe_r, &socket_r, peer, max_packet, &stop_r) {
+            error!("Error in udp_to_pipe: {:?}", e);
+        }
+    });
+
+    let ret = pipe_to_udp(pipe, &socket, peer, max_packet);
+    stop.store(true, Ordering::Relaxed);
+    th_udp_to_pipe.join().unwrap();
+    ret
+}
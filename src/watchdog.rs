@@ -0,0 +1,108 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::OnceLock;
+
+use log::info;
+use log::warn;
+
+use windows::core::HSTRING;
+use windows::Win32::Foundation::CloseHandle;
+use windows::Win32::Foundation::FALSE;
+use windows::Win32::Foundation::TRUE;
+use windows::Win32::System::Threading::CreateEventW;
+use windows::Win32::System::Threading::OpenEventW;
+use windows::Win32::System::Threading::OpenProcess;
+use windows::Win32::System::Threading::SetEvent;
+use windows::Win32::System::Threading::WaitForSingleObject;
+use windows::Win32::System::Threading::INFINITE;
+use windows::Win32::System::Threading::PROCESS_SYNCHRONIZE;
+use windows::Win32::System::Threading::SYNCHRONIZATION_SYNCHRONIZE;
+use windows::Win32::System::Threading::WAIT_OBJECT_0;
+
+use crate::console::SavedState;
+use crate::named_pipe::HandleDesc;
+
+/// Set by `disarm` once winpipe has put the console back itself.
+static RESTORED: OnceLock<HandleDesc> = OnceLock::new();
+
+/// Name of the event `pid` sets once it has restored the console.
+fn restored_name(pid: u32) -> HSTRING {
+    HSTRING::from(format!("Local\\winpipe-restored-{}", pid))
+}
+
+/// Starts a copy of winpipe on the same console that puts `state` back
+/// once this process is gone, unless `disarm` was called first. Another
+/// program may own the console by the time a clean exit is noticed, so
+/// only a winpipe that was killed is cleaned up after.
+pub fn spawn(state: &SavedState) {
+    match unsafe { CreateEventW(None, TRUE, FALSE, &restored_name(std::process::id())) } {
+        Ok(event) => {
+            let _ = RESTORED.set(HandleDesc::from_handle(event));
+        }
+        Err(e) => {
+            warn!("Console restore watchdog not started: {:?}", e);
+            return;
+        }
+    }
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            warn!("Console restore watchdog not started: {:?}", e);
+            return;
+        }
+    };
+    let ret = Command::new(exe)
+        .arg("watchdog")
+        .arg("--pid")
+        .arg(std::process::id().to_string())
+        .arg("--in-cp")
+        .arg(state.in_cp.to_string())
+        .arg("--out-cp")
+        .arg(state.out_cp.to_string())
+        .arg("--in-mode")
+        .arg(state.in_mode.to_string())
+        .arg("--out-mode")
+        .arg(state.out_mode.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match ret {
+        Ok(child) => info!("Console restore watchdog pid {}", child.id()),
+        Err(e) => warn!("Console restore watchdog not started: {:?}", e),
+    }
+}
+
+/// Tells the watchdog the console has been restored, on the way out.
+pub fn disarm() {
+    if let Some(event) = RESTORED.get() {
+        unsafe {
+            let _ = SetEvent(event.as_handle());
+        }
+    }
+}
+
+/// Body of the watchdog: waits for `pid` to exit, then restores `state`
+/// unless `pid` disarmed it.
+pub fn run(pid: u32, state: &SavedState) -> windows::core::Result<()> {
+    let restored = unsafe {
+        let process = OpenProcess(PROCESS_SYNCHRONIZE, FALSE, pid)?;
+        // Opened before `pid` can exit and take the event with it.
+        let event = OpenEventW(SYNCHRONIZATION_SYNCHRONIZE, FALSE, &restored_name(pid)).ok();
+        WaitForSingleObject(process, INFINITE);
+        let _ = CloseHandle(process);
+        event.is_some_and(|event| {
+            let set = WaitForSingleObject(event, 0) == WAIT_OBJECT_0;
+            let _ = CloseHandle(event);
+            set
+        })
+    };
+    if restored {
+        info!("winpipe pid {} restored the console itself", pid);
+        return Ok(());
+    }
+    state.restore()
+}
@@ -0,0 +1,161 @@
+// Copyright 2024 Qi, Yadong.
+// SPDX-License-Identifier: Apache-2.0
+
+const ESC: u8 = 0x1b;
+
+const SHIFT_PRESSED: u32 = 0x10;
+const LEFT_ALT_PRESSED: u32 = 0x02;
+const RIGHT_ALT_PRESSED: u32 = 0x01;
+const LEFT_CTRL_PRESSED: u32 = 0x08;
+const RIGHT_CTRL_PRESSED: u32 = 0x04;
+
+/// Longest win32-input-mode sequence held back while waiting for its end.
+const MAX_PENDING: usize = 64;
+
+/// The VT encoding of a key without a character.
+enum Key {
+    /// `ESC [ final`, or `ESC [ 1 ; mod final` with modifiers.
+    Csi(u8),
+    /// `ESC O final`, or `ESC [ 1 ; mod final` with modifiers.
+    Ss3(u8),
+    /// `ESC [ n ~`, or `ESC [ n ; mod ~` with modifiers.
+    Tilde(u8),
+}
+
+fn key(vk: u32) -> Option<Key> {
+    Some(match vk {
+        0x21 => Key::Tilde(5),
+        0x22 => Key::Tilde(6),
+        0x23 => Key::Csi(b'F'),
+        0x24 => Key::Csi(b'H'),
+        0x25 => Key::Csi(b'D'),
+        0x26 => Key::Csi(b'A'),
+        0x27 => Key::Csi(b'C'),
+        0x28 => Key::Csi(b'B'),
+        0x2d => Key::Tilde(2),
+        0x2e => Key::Tilde(3),
+        0x70..=0x73 => Key::Ss3(b'P' + (vk - 0x70) as u8),
+        0x74 => Key::Tilde(15),
+        0x75..=0x77 => Key::Tilde(17 + (vk - 0x75) as u8),
+        0x78..=0x79 => Key::Tilde(20 + (vk - 0x78) as u8),
+        0x7a..=0x7b => Key::Tilde(23 + (vk - 0x7a) as u8),
+        _ => return None,
+    })
+}
+
+/// xterm modifier parameter, 1 when no modifier is held.
+fn modifier(state: u32) -> u32 {
+    let mut m = 1;
+    if state & SHIFT_PRESSED != 0 {
+        m += 1;
+    }
+    if state & (LEFT_ALT_PRESSED | RIGHT_ALT_PRESSED) != 0 {
+        m += 2;
+    }
+    if state & (LEFT_CTRL_PRESSED | RIGHT_CTRL_PRESSED) != 0 {
+        m += 4;
+    }
+    m
+}
+
+/// Turns Windows Terminal's win32-input-mode key events
+/// (`ESC [ Vk ; Sc ; Uc ; Kd ; Cs ; Rc _`) back into the plain VT input a
+/// guest expects. Everything else is passed through unchanged.
+#[derive(Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+    /// High surrogate waiting for the event carrying its low half.
+    high: Option<u16>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(buf);
+
+        let mut out = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != ESC || input.get(i + 1) != Some(&b'[') {
+                out.push(input[i]);
+                i += 1;
+                continue;
+            }
+            let params = &input[i + 2..];
+            let end = params
+                .iter()
+                .position(|b| !b.is_ascii_digit() && *b != b';');
+            match end {
+                // Possibly a sequence cut short by the read, keep it.
+                None if input.len() - i < MAX_PENDING => {
+                    self.pending = input[i..].to_vec();
+                    break;
+                }
+                Some(end) if params[end] == b'_' => {
+                    self.event(&params[..end], &mut out);
+                    i += end + 3;
+                }
+                _ => {
+                    out.push(input[i]);
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+
+    fn event(&mut self, params: &[u8], out: &mut Vec<u8>) {
+        let mut values = params.split(|b| *b == b';').map(|p| {
+            std::str::from_utf8(p)
+                .ok()
+                .and_then(|p| p.parse::<u32>().ok())
+        });
+        let mut next = |default: u32| values.next().flatten().unwrap_or(default);
+        let (vk, _sc, uc, down, state, repeat) =
+            (next(0), next(0), next(0), next(0), next(0), next(1));
+        if down == 0 {
+            return;
+        }
+
+        let mut text = Vec::new();
+        if uc != 0 {
+            let unit = uc as u16;
+            let ch = match (self.high.take(), unit) {
+                (_, 0xd800..=0xdbff) => {
+                    self.high = Some(unit);
+                    return;
+                }
+                (Some(high), 0xdc00..=0xdfff) => char::decode_utf16([high, unit]).next(),
+                (_, unit) => char::decode_utf16([unit]).next(),
+            };
+            let Some(Ok(ch)) = ch else {
+                return;
+            };
+            // AltGr shows up as Ctrl+Alt and already produced the character.
+            let alt = state & (LEFT_ALT_PRESSED | RIGHT_ALT_PRESSED) != 0
+                && state & (LEFT_CTRL_PRESSED | RIGHT_CTRL_PRESSED) == 0;
+            if alt {
+                text.push(ESC);
+            }
+            text.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+        } else {
+            let m = modifier(state);
+            let seq = match (key(vk), m) {
+                (None, _) => return,
+                (Some(Key::Csi(f)), 1) => format!("\x1b[{}", f as char),
+                (Some(Key::Ss3(f)), 1) => format!("\x1bO{}", f as char),
+                (Some(Key::Csi(f) | Key::Ss3(f)), m) => format!("\x1b[1;{}{}", m, f as char),
+                (Some(Key::Tilde(n)), 1) => format!("\x1b[{}~", n),
+                (Some(Key::Tilde(n)), m) => format!("\x1b[{};{}~", n, m),
+            };
+            text.extend_from_slice(seq.as_bytes());
+        }
+        for _ in 0..repeat.max(1) {
+            out.extend_from_slice(&text);
+        }
+    }
+}